@@ -0,0 +1,372 @@
+//! Automatic gain control for the HRS3300.
+//!
+//! `Agc` watches the level of the HRS channel and steps the LED current,
+//! the gain and the resolution to keep it inside the ADC range. Sensitivity
+//! goes up through the LED current first and the gain second, so the LED is
+//! as bright as the signal allows. Samples around each change are marked,
+//! filters downstream should skip them instead of seeing the step.
+
+use embedded_hal::blocking::i2c::{
+    Write,
+    WriteRead,
+};
+
+use crate::hrs3300::{
+    Sensor,
+    SensorError,
+    SensorConfig,
+    RawSample,
+    LedCurrent,
+    Gain,
+    BitsResolution,
+};
+
+/// Peak level in permille of full scale above which sensitivity goes down
+const HIGH_PERMILLE: u32 = 800;
+/// Peak level below which sensitivity goes up
+const LOW_PERMILLE: u32 = 200;
+/// Sensitivity goes up only if the predicted peak stays below this level,
+/// so a big step (X8 to X64) doesn't start hunting
+const TARGET_PERMILLE: u32 = 700;
+/// Samples the peak level is taken over before a decision
+const WINDOW_LEN: u32 = 16;
+/// Samples marked after a change, conversions already running
+/// finish with the old settings
+const SETTLE_SAMPLES: u32 = 3;
+
+/// Settings controlled by `Agc`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AgcSetting {
+    pub led_current: LedCurrent,
+    pub gain: Gain,
+    pub resolution: BitsResolution,
+}
+impl From<SensorConfig> for AgcSetting {
+    fn from(config: SensorConfig) -> Self {
+        AgcSetting {
+            led_current: config.led_current,
+            gain: config.gain,
+            resolution: config.resolution,
+        }
+    }
+}
+
+/// Sample with the AGC marks
+#[derive(Clone, Copy, Debug)]
+pub struct AgcSample {
+    pub sample: RawSample,
+    /// Taken around a setting change, the level may be off
+    pub settling: bool,
+    /// New setting applied after this sample
+    pub change: Option<AgcSetting>,
+}
+impl AgcSample {
+    /// The sample can be used by the signal processing
+    pub fn is_usable(&self) -> bool {
+        !self.settling && !self.sample.quality.is_saturated() && !self.sample.quality.config_changed
+    }
+}
+
+pub struct Agc {
+    min_resolution: BitsResolution,
+    max_resolution: BitsResolution,
+    /// Highest level in the current window, permille of full scale
+    peak: u32,
+    count: u32,
+    /// Samples left to mark after a change
+    settle: u32,
+}
+
+impl Agc {
+    /// Resolution is raised up to `max_resolution` only when the LED current
+    /// and the gain are at their maximum, and goes back to `min_resolution`
+    /// with the first step down
+    pub fn new(min_resolution: BitsResolution, max_resolution: BitsResolution) -> Self {
+        Agc {
+            min_resolution,
+            max_resolution,
+            peak: 0,
+            count: 0,
+            settle: 0,
+        }
+    }
+
+    /// Reads a sample and applies a new setting if it's needed.
+    ///
+    /// Unlike `Sensor::read_sample` a saturated sample is not an error,
+    /// it's marked and makes the sensitivity go down right away.
+    pub fn read_sample<I2C, E>(&mut self, sensor: &mut Sensor<I2C>) -> Result<AgcSample, SensorError<E>>
+    where
+        I2C: Write<Error = E> + WriteRead<Error = E>
+    {
+        let sample = sensor.read_raw_sample()?;
+        let current = AgcSetting::from(sensor.get_config());
+
+        let agc_sample = self.consume_sample(sample, current);
+        if let Some(next) = agc_sample.change {
+            apply(sensor, next)?;
+        }
+
+        Ok(agc_sample)
+    }
+
+    /// Same as `read_sample` for a sample taken with `setting`,
+    /// the returned change is left to the caller
+    pub fn consume_sample(&mut self, sample: RawSample, setting: AgcSetting) -> AgcSample {
+        let mask = sample.resolution.get_mask();
+        let saturated = sample.quality.hrs_saturated;
+        let mut agc_sample = AgcSample {
+            sample,
+            settling: false,
+            change: None,
+        };
+
+        if self.settle > 0 {
+            self.settle -= 1;
+            agc_sample.settling = true;
+            return agc_sample;
+        }
+
+        self.peak = self.peak.max((sample.hrs as u64 * 1_000 / mask as u64) as u32);
+        self.count += 1;
+
+        let change = if saturated || self.peak > HIGH_PERMILLE {
+            self.step_down(setting)
+        } else if self.count >= WINDOW_LEN && self.peak < LOW_PERMILLE {
+            self.step_up(setting, self.peak)
+        } else {
+            None
+        };
+
+        if change.is_some() || self.count >= WINDOW_LEN {
+            self.peak = 0;
+            self.count = 0;
+        }
+        if change.is_some() {
+            self.settle = SETTLE_SAMPLES;
+            agc_sample.settling = true;
+            agc_sample.change = change;
+        }
+
+        agc_sample
+    }
+
+    /// Marks the next samples as settling again, for a change which was
+    /// applied some samples after `consume_sample` returned it
+    pub fn hold(&mut self) {
+        self.settle = SETTLE_SAMPLES;
+        self.peak = 0;
+        self.count = 0;
+    }
+
+    /// Forgets the level, for a sensor which was configured again
+    pub fn reset(&mut self) {
+        self.peak = 0;
+        self.count = 0;
+        self.settle = 0;
+    }
+
+    fn step_down(&self, setting: AgcSetting) -> Option<AgcSetting> {
+        let mut next = setting;
+        if let Some(gain) = lower_gain(setting.gain) {
+            next.gain = gain;
+        } else if let Some(led_current) = lower_led_current(setting.led_current) {
+            next.led_current = led_current;
+        } else {
+            // nothing left, the signal stays clipped
+            return None;
+        }
+
+        if setting.resolution as u8 > self.min_resolution as u8 {
+            next.resolution = self.min_resolution;
+        }
+        Some(next)
+    }
+
+    fn step_up(&self, setting: AgcSetting, peak: u32) -> Option<AgcSetting> {
+        let mut next = setting;
+        let (new, old) = if let Some(led_current) = higher_led_current(setting.led_current) {
+            next.led_current = led_current;
+            (led_current.get_ma_x10(), setting.led_current.get_ma_x10())
+        } else if let Some(gain) = higher_gain(setting.gain) {
+            next.gain = gain;
+            (gain.get_factor(), setting.gain.get_factor())
+        } else if (setting.resolution as u8) < self.max_resolution as u8 {
+            // level in permille doesn't change, more bits still help a weak signal
+            next.resolution = BitsResolution::from_bits(setting.resolution as u8 + 1)?;
+            (1, 1)
+        } else {
+            return None;
+        };
+
+        if peak * new / old >= TARGET_PERMILLE {
+            return None;
+        }
+        Some(next)
+    }
+}
+
+/// Writes the parts of `setting` which differ from the sensor configuration
+pub fn apply<I2C, E>(sensor: &mut Sensor<I2C>, setting: AgcSetting) -> Result<(), SensorError<E>>
+where
+    I2C: Write<Error = E> + WriteRead<Error = E>
+{
+    let current = AgcSetting::from(sensor.get_config());
+    if setting.led_current != current.led_current {
+        sensor.set_led_current(setting.led_current)?;
+    }
+    if setting.gain != current.gain {
+        sensor.set_gain(setting.gain)?;
+    }
+    if setting.resolution != current.resolution {
+        sensor.set_resolution(setting.resolution)?;
+    }
+    Ok(())
+}
+
+fn lower_gain(gain: Gain) -> Option<Gain> {
+    Gain::from_bits((gain as u8).checked_sub(1)?)
+}
+
+fn higher_gain(gain: Gain) -> Option<Gain> {
+    Gain::from_bits(gain as u8 + 1)
+}
+
+fn lower_led_current(led_current: LedCurrent) -> Option<LedCurrent> {
+    LedCurrent::from_bits((led_current as u8).checked_sub(1)?)
+}
+
+fn higher_led_current(led_current: LedCurrent) -> Option<LedCurrent> {
+    LedCurrent::from_bits(led_current as u8 + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hrs3300::emulator::Emulator;
+
+    /// Reads `count` samples, returns the last one
+    fn run<'a>(agc: &mut Agc, sensor: &mut Sensor<&'a Emulator>, count: u32) -> AgcSample {
+        let mut last = None;
+        for _ in 0..count {
+            last = Some(agc.read_sample(sensor).unwrap());
+        }
+        last.unwrap()
+    }
+
+    fn started(chip: &Emulator) -> Sensor<&Emulator> {
+        let mut sensor = Sensor::new(chip);
+        sensor.init().unwrap();
+        sensor.set_hrs_active(true).unwrap();
+        sensor
+    }
+
+    fn level(sample: &AgcSample, sensor: &Sensor<&Emulator>) -> u32 {
+        sample.sample.hrs * 1_000 / sensor.get_config().resolution.get_mask()
+    }
+
+    #[test]
+    fn bright_signal_lowers_gain() {
+        let chip = Emulator::new();
+        chip.set_light(20_000, 0);
+        let mut sensor = started(&chip);
+        let mut agc = Agc::new(BitsResolution::_14, BitsResolution::_16);
+
+        let first = agc.read_sample(&mut sensor).unwrap();
+        assert!(first.sample.quality.hrs_saturated);
+        assert_eq!(first.change.unwrap().gain, Gain::X8);
+
+        let last = run(&mut agc, &mut sensor, 100);
+        let config = sensor.get_config();
+        assert_eq!((config.gain, config.led_current), (Gain::X8, LedCurrent::Ma40));
+        assert!(last.is_usable());
+        let level = level(&last, &sensor);
+        assert!((LOW_PERMILLE..=HIGH_PERMILLE).contains(&level), "{}", level);
+    }
+
+    #[test]
+    fn weak_signal_raises_led_current_first() {
+        let chip = Emulator::new();
+        chip.set_light(10_000, 0);
+        let mut sensor = started(&chip);
+        sensor.set_gain(Gain::X1).unwrap();
+        sensor.set_led_current(LedCurrent::Ma12_5).unwrap();
+        let mut agc = Agc::new(BitsResolution::_14, BitsResolution::_16);
+
+        let mut changes = 0;
+        for _ in 0..400 {
+            if let Some(change) = agc.read_sample(&mut sensor).unwrap().change {
+                changes += 1;
+                if change.gain != Gain::X1 {
+                    assert_eq!(change.led_current, LedCurrent::Ma40);
+                }
+            }
+        }
+
+        let config = sensor.get_config();
+        assert_eq!((config.gain, config.led_current), (Gain::X8, LedCurrent::Ma40));
+        assert_eq!(changes, 6);
+    }
+
+    #[test]
+    fn samples_around_change_are_marked() {
+        let chip = Emulator::new();
+        chip.set_light(20_000, 0);
+        let mut sensor = started(&chip);
+        let mut agc = Agc::new(BitsResolution::_14, BitsResolution::_14);
+
+        let marks: [bool; 6] = core::array::from_fn(|_| agc.read_sample(&mut sensor).unwrap().settling);
+        assert_eq!(marks, [true, true, true, true, false, false]);
+    }
+
+    #[test]
+    fn hold_marks_samples_again() {
+        let mut agc = Agc::new(BitsResolution::_14, BitsResolution::_14);
+        let setting = AgcSetting {
+            led_current: LedCurrent::Ma40,
+            gain: Gain::X8,
+            resolution: BitsResolution::_14,
+        };
+        let sample = RawSample::new(8_000, 0);
+
+        agc.hold();
+        let marks: [bool; 4] = core::array::from_fn(|_| agc.consume_sample(sample, setting).settling);
+        assert_eq!(marks, [true, true, true, false]);
+    }
+
+    #[test]
+    fn no_step_up_into_clipping() {
+        let mut agc = Agc::new(BitsResolution::_14, BitsResolution::_14);
+        let setting = AgcSetting {
+            led_current: LedCurrent::Ma40,
+            gain: Gain::X8,
+            resolution: BitsResolution::_14,
+        };
+
+        // 15% of full scale would be 120% at X64
+        let sample = RawSample::new(16_383 * 15 / 100, 0);
+        for _ in 0..10 * WINDOW_LEN {
+            assert!(agc.consume_sample(sample, setting).change.is_none());
+        }
+    }
+
+    #[test]
+    fn resolution_only_at_full_sensitivity() {
+        let chip = Emulator::new();
+        chip.set_light(100, 0);
+        let mut sensor = started(&chip);
+        let mut agc = Agc::new(BitsResolution::_14, BitsResolution::_16);
+
+        run(&mut agc, &mut sensor, 100);
+        let config = sensor.get_config();
+        assert_eq!((config.gain, config.led_current), (Gain::X64, LedCurrent::Ma40));
+        assert_eq!(config.resolution, BitsResolution::_16);
+
+        // strong signal comes back, resolution returns with the first step down
+        chip.set_light(20_000, 0);
+        run(&mut agc, &mut sensor, 100);
+        let config = sensor.get_config();
+        assert_eq!(config.gain, Gain::X8);
+        assert_eq!(config.resolution, BitsResolution::_14);
+    }
+}
@@ -0,0 +1,588 @@
+//! Tasks of the firmware, in the style of RTIC.
+//!
+//! | task     | priority | runs on                                     |
+//! |----------|----------|---------------------------------------------|
+//! | sampling | 3        | TIMER2 (or TIMER3 with DMA), see `sampler`  |
+//! | process  | 2        | SWI0, spawned by the sampling               |
+//! | display  | 1        | SWI1, every `DISPLAY_PERIOD_MS`             |
+//! | battery  | 1        | SWI1, every `BATTERY_PERIOD_MS`             |
+//! | input    | 1        | SWI1, every `INPUT_PERIOD_MS`               |
+//!
+//! Software tasks are pended on the dispatcher interrupt of their priority,
+//! delayed ones wait in the timer queue on the RTC1 compare of the monotonic.
+//! Higher priorities preempt lower ones, a slow display refresh doesn't hold
+//! up the processing and nothing holds up the sampling. Data used by more
+//! than one task is in a `Resource` with the highest priority of its users
+//! as the ceiling, the idle loop only sleeps.
+
+use core::cell::RefCell;
+use core::sync::atomic::{
+    self,
+    AtomicU8,
+};
+use cortex_m::interrupt::{
+    self as cs,
+    Mutex,
+};
+use nrf52832_hal::pac::{
+    self,
+    interrupt,
+    Interrupt,
+};
+use pt_hello::{
+    sample_queue::{SampleQueue, Producer, Consumer},
+    filters::{self, CardiacBand, Filter},
+    render::FrameStats,
+    screens::ChartRendering,
+    ui::Status,
+    sample_timing::TimingChecker,
+    wrist::{WristDetector, WristState},
+    heart_rate::HeartRateEstimator,
+    hrv::{HrvAnalyzer, IntervalKind},
+};
+use crate::{
+    init,
+    sampler,
+    dma_sampler,
+    display::{self, Ui},
+    backlight::Backlight,
+    button::Button,
+    battery::BatteryStatus,
+    monotonic_nrf52::{
+        Instant,
+        Duration,
+        Rtc1,
+    },
+    resource::{
+        Resource,
+        to_hw_priority,
+    },
+    SensorType,
+    SensorErrorType,
+    GLOBAL_ALS,
+    GLOBAL_HRS,
+    GLOBAL_SUM,
+    GLOBAL_BPM,
+    GLOBAL_RMSSD,
+};
+
+/// Timer queue, above everything it can spawn
+const TIMER_QUEUE_PRIORITY: u8 = 4;
+pub const SAMPLING_PRIORITY: u8 = 3;
+const PROCESS_PRIORITY: u8 = 2;
+const DISPLAY_PRIORITY: u8 = 1;
+const BATTERY_PRIORITY: u8 = 1;
+const INPUT_PRIORITY: u8 = 1;
+
+const DISPLAY_PERIOD_MS: u64 = 432;
+const BATTERY_PERIOD_MS: u64 = 10_000;
+/// Button polling, short enough for a press, long enough to skip the bounce
+const INPUT_PERIOD_MS: u64 = 50;
+/// Display refreshes between two status lines in the log
+const STATUS_EVERY: u32 = 10;
+/// Consecutive bus errors tolerated before the sampling stops
+const MAX_BUS_ERRORS: u32 = 10;
+/// Read the sensor through EasyDMA instead of from the timer interrupt,
+/// built with `--features dma-sampling`
+const DMA_SAMPLING: bool = cfg!(feature = "dma-sampling");
+/// How the chart is drawn
+const RENDERING: ChartRendering = ChartRendering::Columns;
+// NN intervals in the HRV window, about a minute at rest
+const HRV_WINDOW: usize = 64;
+/// Delayed spawns waiting at the same time
+const TIMER_QUEUE_LEN: usize = 4;
+/// Filtered samples waiting for the display, more than one refresh worth
+const PLOT_QUEUE_LEN: usize = 64;
+
+type PlotProducer = Producer<'static, i32, PLOT_QUEUE_LEN>;
+type PlotConsumer = Consumer<'static, i32, PLOT_QUEUE_LEN>;
+
+/// Software tasks
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Task {
+    Process,
+    Display,
+    Battery,
+    Input,
+}
+impl Task {
+    fn priority(self) -> u8 {
+        match self {
+            Task::Process => PROCESS_PRIORITY,
+            Task::Display => DISPLAY_PRIORITY,
+            Task::Battery => BATTERY_PRIORITY,
+            Task::Input => INPUT_PRIORITY,
+        }
+    }
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// Latest results, written by the processing, shown by the display
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Measurements {
+    pub bpm: u32,
+    pub rmssd_us: u32,
+    pub wrist: Option<WristState>,
+    /// Bus errors in a row
+    pub bus_errors: u32,
+}
+
+/// Everything the process task works on
+struct Processing {
+    consumer: sampler::SampleConsumer,
+    pipeline: PpgPipeline,
+    bus_errors: u32,
+    dropped: u32,
+    running: bool,
+}
+
+/// Everything the display and the input task work on
+struct UserInterface {
+    ui: Ui,
+    button: Button,
+    plot: PlotConsumer,
+    #[allow(unused)]
+    backlight: Backlight,
+    refreshes: u32,
+    /// Times of the frames since the last status line
+    frames: FrameStats,
+}
+
+// shared, the ceiling is the highest priority of the users
+static MEASUREMENTS: Resource<Measurements> = Resource::new(PROCESS_PRIORITY);
+static BATTERY: Resource<BatteryStatus> = Resource::new(BATTERY_PRIORITY);
+// local
+static PROCESSING: Resource<Processing> = Resource::new(PROCESS_PRIORITY);
+static USER_INTERFACE: Resource<UserInterface> = Resource::new(DISPLAY_PRIORITY);
+
+/// Spawned tasks waiting for their dispatcher
+static PENDING: AtomicU8 = AtomicU8::new(0);
+/// Delayed spawns with their time
+type TimerQueue = [Option<(Instant, Task)>; TIMER_QUEUE_LEN];
+static TIMER_QUEUE: Mutex<RefCell<TimerQueue>> = Mutex::new(RefCell::new([None; TIMER_QUEUE_LEN]));
+
+/// Like RTIC `init`: puts the resources in place, sets the priorities,
+/// starts the sampling and the periodic tasks
+pub fn start(shared: init::Shared, local: init::Local) {
+    let init::Local {
+        mut lcd,
+        mut sensor,
+        sensor_probe,
+        sample_timer,
+        block_timer,
+        ppi,
+        backlight,
+        button,
+        ..
+    } = local;
+
+    let mut core = cortex_m::Peripherals::take().unwrap();
+    unsafe {
+        core.NVIC.set_priority(Interrupt::RTC1, to_hw_priority(TIMER_QUEUE_PRIORITY));
+        core.NVIC.set_priority(Interrupt::TIMER2, to_hw_priority(SAMPLING_PRIORITY));
+        core.NVIC.set_priority(Interrupt::TIMER3, to_hw_priority(SAMPLING_PRIORITY));
+        core.NVIC.set_priority(Interrupt::SPIM0_SPIS0_TWIM0_TWIS0_SPI0_TWI0, to_hw_priority(SAMPLING_PRIORITY));
+        core.NVIC.set_priority(dispatcher(PROCESS_PRIORITY), to_hw_priority(PROCESS_PRIORITY));
+        core.NVIC.set_priority(dispatcher(DISPLAY_PRIORITY), to_hw_priority(DISPLAY_PRIORITY));
+        pac::NVIC::unmask(dispatcher(PROCESS_PRIORITY));
+        pac::NVIC::unmask(dispatcher(DISPLAY_PRIORITY));
+    }
+
+    // samples go from the sampling to the process task through the queue
+    let queue = cortex_m::singleton!(
+        : SampleQueue<sampler::TimedSample, { sampler::QUEUE_LEN }> = SampleQueue::new()
+    ).unwrap();
+    let (producer, consumer) = queue.split();
+    // filtered ones go on from the process task to the display
+    let plot_queue = cortex_m::singleton!(
+        : SampleQueue<i32, PLOT_QUEUE_LEN> = SampleQueue::new()
+    ).unwrap();
+    let (plot_producer, plot_consumer) = plot_queue.split();
+
+    MEASUREMENTS.init(Measurements::default());
+    BATTERY.init(shared.battery);
+
+    display::init(&mut lcd).unwrap();
+    let mut ui = Ui::new(lcd, RENDERING);
+    ui.start().unwrap();
+    USER_INTERFACE.init(UserInterface {
+        ui,
+        button,
+        plot: plot_consumer,
+        backlight,
+        refreshes: 0,
+        frames: FrameStats::new(),
+    });
+
+    match sensor_probe.and_then(|_| start_hrs3300(&mut sensor)) {
+        Ok(()) => {
+            let pipeline = PpgPipeline::new(sensor.get_adc_wait_time_us(), plot_producer);
+            PROCESSING.init(Processing { consumer, pipeline, bus_errors: 0, dropped: 0, running: true });
+
+            info!("HRS3300 sampling starts");
+            if DMA_SAMPLING {
+                dma_sampler::start(sensor, sample_timer, block_timer, ppi, producer);
+            } else {
+                sampler::start(sensor, sample_timer, producer);
+            }
+        }
+        // nothing to process, the display still runs
+        Err(e) => error!("HRS3300 is not available: {:?}", e),
+    }
+
+    spawn(Task::Display);
+    spawn(Task::Battery);
+    spawn(Task::Input);
+}
+
+/// Runs `task` as soon as its priority allows
+pub fn spawn(task: Task) {
+    PENDING.fetch_or(task.bit(), atomic::Ordering::AcqRel);
+    pac::NVIC::pend(dispatcher(task.priority()));
+}
+
+/// Runs `task` at `instant`, gives it back if the timer queue is full
+pub fn spawn_at(task: Task, instant: Instant) -> Result<(), Task> {
+    cs::free(|cs| {
+        let mut queue = TIMER_QUEUE.borrow(cs).borrow_mut();
+        let slot = queue.iter_mut().find(|slot| slot.is_none()).ok_or(task)?;
+        *slot = Some((instant, task));
+        arm_timer_queue(&queue);
+        Ok(())
+    })
+}
+
+/// Runs `task` once `delay` has passed
+pub fn spawn_after(task: Task, delay: Duration) -> Result<(), Task> {
+    spawn_at(task, Instant::now() + delay)
+}
+
+fn dispatcher(priority: u8) -> Interrupt {
+    match priority {
+        PROCESS_PRIORITY => Interrupt::SWI0_EGU0,
+        _ => Interrupt::SWI1_EGU1,
+    }
+}
+
+/// Sets the RTC compare to the earliest waiting spawn
+fn arm_timer_queue(queue: &TimerQueue) {
+    match queue.iter().flatten().map(|(instant, _)| *instant).min() {
+        Some(instant) => Rtc1::set_compare(instant),
+        None => Rtc1::clear_compare(),
+    }
+}
+
+/// Runs the pending tasks of `priority` in the order of `Task`
+fn dispatch(priority: u8) {
+    for task in [Task::Process, Task::Display, Task::Battery, Task::Input] {
+        if task.priority() != priority {
+            continue;
+        }
+        let previous = PENDING.fetch_and(!task.bit(), atomic::Ordering::AcqRel);
+        if previous & task.bit() == 0 {
+            continue;
+        }
+        match task {
+            Task::Process => process(),
+            Task::Display => display(),
+            Task::Battery => battery(),
+            Task::Input => input(),
+        }
+    }
+}
+
+#[interrupt]
+fn RTC1() {
+    Rtc1::on_interrupt();
+
+    let now = Instant::now();
+    cs::free(|cs| {
+        let mut queue = TIMER_QUEUE.borrow(cs).borrow_mut();
+        for slot in queue.iter_mut() {
+            if let Some((instant, task)) = *slot {
+                if instant <= now {
+                    *slot = None;
+                    spawn(task);
+                }
+            }
+        }
+        arm_timer_queue(&queue);
+    });
+}
+
+#[interrupt]
+fn SWI0_EGU0() {
+    dispatch(PROCESS_PRIORITY);
+}
+
+#[interrupt]
+fn SWI1_EGU1() {
+    dispatch(DISPLAY_PRIORITY);
+}
+
+/// Takes the queued samples through the signal processing,
+/// restarts the sensor after bus errors
+fn process() {
+    PROCESSING.lock(|processing| {
+        if !processing.running {
+            return;
+        }
+
+        while let Some(timed) = processing.consumer.dequeue() {
+            processing.bus_errors = 0;
+            processing.pipeline.consume(timed);
+        }
+        let measurements = Measurements {
+            bus_errors: processing.bus_errors,
+            ..processing.pipeline.measurements()
+        };
+        MEASUREMENTS.lock(|shared| *shared = measurements);
+
+        let error = if DMA_SAMPLING { dma_sampler::take_error() } else { sampler::take_error() };
+        if let Some(e) = error {
+            if processing.bus_errors >= MAX_BUS_ERRORS {
+                error!("HRS3300 gave up after bus error {:?}", e);
+                processing.running = false;
+                stop_sampling();
+                return;
+            }
+            processing.bus_errors += 1;
+            warn!("HRS3300 bus error {:?}, restarting sensor ({}/{})", e, processing.bus_errors, MAX_BUS_ERRORS);
+
+            // sensor may have lost its configuration, set it up again
+            let restart = if DMA_SAMPLING {
+                dma_sampler::with_sensor(start_hrs3300)
+            } else {
+                sampler::with_sensor(start_hrs3300)
+            };
+            if let Some(Err(e)) = restart {
+                warn!("HRS3300 restart failed: {:?}", e);
+            }
+        }
+
+        let dropped = dropped_samples();
+        if dropped != processing.dropped {
+            processing.dropped = dropped;
+            warn!("HRS3300 samples dropped: {}", dropped);
+        }
+    });
+}
+
+/// Refreshes the shown screen, logs the state now and then
+fn display() {
+    let measurements = MEASUREMENTS.lock(|measurements| *measurements);
+    let (voltage, charging) = BATTERY.lock(|battery| (battery.voltage(), battery.is_charging()));
+
+    let status = USER_INTERFACE.lock(|interface| {
+        while let Some(value) = interface.plot.dequeue() {
+            interface.ui.push_sample(value);
+        }
+
+        let status = Status {
+            bpm: measurements.bpm,
+            rmssd_us: measurements.rmssd_us,
+            wrist: measurements.wrist,
+            battery_voltage: voltage,
+            charging,
+            uptime_s: (Instant::now().as_micros() / 1_000_000) as u32,
+            frames: interface.frames,
+            dropped: dropped_samples(),
+            bus_errors: measurements.bus_errors,
+        };
+        let start = Instant::now();
+        match interface.ui.update(&status) {
+            // an unchanged screen isn't drawn, it's not a frame
+            Ok(true) => interface.frames.record(start.elapsed().as_micros() as u32),
+            Ok(false) => {}
+            Err(e) => error!("display update failed: {:?}", e),
+        }
+
+        interface.refreshes += 1;
+        if interface.refreshes % STATUS_EVERY != 0 {
+            return None;
+        }
+        let frames = interface.frames;
+        interface.frames.reset();
+        Some((frames, interface.ui.current()))
+    });
+
+    if let Some((frames, screen)) = status {
+        info!(
+            "{:?} BPM: {}, RMSSD: {} us, battery: {}.{} V{}",
+            measurements.wrist, measurements.bpm, measurements.rmssd_us,
+            voltage / 10, voltage % 10, if charging { ", charging" } else { "" },
+        );
+        if let Some(average_us) = frames.average_us() {
+            info!(
+                "frames ({:?}, {:?}): {}, avg {} us, min {} us, max {} us",
+                screen, RENDERING,
+                frames.frames, average_us, frames.min_us, frames.max_us,
+            );
+        }
+    }
+
+    if spawn_after(Task::Display, Duration::from_millis(DISPLAY_PERIOD_MS)).is_err() {
+        warn!("timer queue full, display stops");
+    }
+}
+
+/// Reads the battery state
+fn battery() {
+    BATTERY.lock(|battery| {
+        if battery.update() {
+            info!("battery: {} V/10, charging: {}", battery.voltage(), battery.is_charging());
+        }
+    });
+
+    if spawn_after(Task::Battery, Duration::from_millis(BATTERY_PERIOD_MS)).is_err() {
+        warn!("timer queue full, battery polling stops");
+    }
+}
+
+/// Polls the button, the shown screen decides what a press does
+fn input() {
+    let now_ms = (Instant::now().as_micros() / 1_000) as u32;
+    USER_INTERFACE.lock(|interface| {
+        if let Some(event) = interface.button.poll(now_ms) {
+            match interface.ui.handle(event) {
+                Ok(transition) => debug!("button {:?}: {:?}", event, transition),
+                Err(e) => error!("screen change failed: {:?}", e),
+            }
+        }
+    });
+
+    if spawn_after(Task::Input, Duration::from_millis(INPUT_PERIOD_MS)).is_err() {
+        warn!("timer queue full, button polling stops");
+    }
+}
+
+/// Samples the sampling dropped since the start
+fn dropped_samples() -> u32 {
+    if DMA_SAMPLING { dma_sampler::dropped() } else { sampler::dropped() }
+}
+
+fn stop_sampling() {
+    let sensor = if DMA_SAMPLING {
+        dma_sampler::stop().map(|(sensor, ..)| sensor)
+    } else {
+        sampler::stop().map(|(sensor, _)| sensor)
+    };
+
+    if let Some(mut sensor) = sensor {
+        info!("HRS3300 sensor off");
+        if let Err(e) = sensor.set_osc_active(false).and_then(|_| sensor.set_hrs_active(false)) {
+            warn!("HRS3300 didn't turn off: {:?}", e);
+        }
+    }
+}
+
+fn start_hrs3300(sensor: &mut SensorType) -> Result<(), SensorErrorType> {
+    sensor.init()?;
+
+    sensor.set_hrs_active(true)?;
+
+    sensor.set_osc_active(true)
+}
+
+/// Signal processing of the sampled PPG
+struct PpgPipeline {
+    timing: TimingChecker,
+    wrist: WristDetector,
+    heart_rate: HeartRateEstimator,
+    hrv: HrvAnalyzer<HRV_WINDOW>,
+    /// Band-pass for the chart, `None` if the rate is too low for it
+    plot_filter: Option<CardiacBand>,
+    plot: PlotProducer,
+}
+impl PpgPipeline {
+    fn new(sample_period_us: u32, plot: PlotProducer) -> Self {
+        PpgPipeline {
+            plot_filter: filters::cardiac_band(sample_period_us),
+            plot,
+            timing: TimingChecker::new(sample_period_us),
+            wrist: WristDetector::new(sample_period_us),
+            heart_rate: HeartRateEstimator::new(sample_period_us),
+            hrv: HrvAnalyzer::new(),
+        }
+    }
+
+    fn consume(&mut self, timed: sampler::TimedSample) {
+        let agc_sample = timed.sample;
+        let raw_sample = agc_sample.sample;
+        GLOBAL_HRS.store(raw_sample.hrs, atomic::Ordering::Relaxed);
+        GLOBAL_ALS.store(raw_sample.als,  atomic::Ordering::Relaxed);
+        GLOBAL_SUM.store(raw_sample.get_sum(), atomic::Ordering::Relaxed);
+
+        if let Some(setting) = agc_sample.change {
+            info!("HRS3300 AGC: {:?}", setting);
+        }
+
+        let timing = self.timing.check(timed.stamp);
+        if timing.dropped > 0 {
+            warn!("HRS3300 {} samples missing before #{}", timing.dropped, timed.stamp.seq);
+        } else if timing.late_us > 0 {
+            debug!("HRS3300 sample #{} late by {} us", timed.stamp.seq, timing.late_us);
+        }
+
+        // a gap breaks the detrend, the sample starts it over
+        if timing.dropped > 0 || !agc_sample.is_usable() {
+            // level step or clipped value, keep it away from the filters
+            self.wrist.skip_sample();
+            self.heart_rate.skip_sample();
+            if let Some(filter) = self.plot_filter.as_mut() {
+                filter.reset();
+            }
+            return;
+        }
+
+        if let Some(filter) = self.plot_filter.as_mut() {
+            // display behind, the sample is simply not shown
+            let _ = self.plot.enqueue(filter.process_sample(raw_sample));
+        }
+
+        if let Some(state) = self.wrist.consume_sample(raw_sample) {
+            info!("HRS3300 {:?}: {:?}", state, self.wrist.features());
+            if state == WristState::OffWrist {
+                // nothing to measure, don't report the last rate
+                self.heart_rate.reset();
+                GLOBAL_BPM.store(0, atomic::Ordering::Relaxed);
+            }
+        }
+        if self.wrist.get() == Some(WristState::OffWrist) {
+            return;
+        }
+
+        // intervals come from the capture times, not from counting samples
+        match self.heart_rate.consume_sample_at(raw_sample, timed.stamp.time_us) {
+            Some(rate) if rate.confidence >= 50 => {
+                GLOBAL_BPM.store(rate.smoothed_bpm as u32, atomic::Ordering::Relaxed);
+            }
+            Some(_) => {}
+            None => GLOBAL_BPM.store(0, atomic::Ordering::Relaxed),
+        }
+
+        // same beats as the rate, not a second detector
+        match self.heart_rate.beat().and_then(|beat| self.hrv.consume_beat(beat)) {
+            Some(IntervalKind::Normal) => {
+                if let Some(metrics) = self.hrv.metrics() {
+                    GLOBAL_RMSSD.store(metrics.rmssd_us, atomic::Ordering::Relaxed);
+                    info!("HRV: {}", metrics);
+                }
+            }
+            Some(kind) => info!("HRV: interval rejected as {:?}", kind),
+            None => {}
+        }
+    }
+
+    fn measurements(&self) -> Measurements {
+        Measurements {
+            bpm: GLOBAL_BPM.load(atomic::Ordering::Relaxed),
+            rmssd_us: GLOBAL_RMSSD.load(atomic::Ordering::Relaxed),
+            wrist: self.wrist.get(),
+            ..Measurements::default()
+        }
+    }
+}
@@ -3,6 +3,7 @@ use embedded_hal::digital::v2::InputPin;
 use nrf52832_hal::gpio::{p0, Floating, Input};
 use nrf52832_hal::saadc::{Saadc, SaadcConfig};
 use nrf52832_hal::target::SAADC;
+use pt_hello::battery_level;
 
 pub struct BatteryStatus {
     /// Pin P0.12: High = battery, Low = charging.
@@ -34,7 +35,7 @@ impl BatteryStatus {
         // Get initial voltage
         let mut saadc = Saadc::new(SAADC, SaadcConfig::default());
         let voltage =
-            Self::convert_adc_measurement(saadc.read(&mut pin_voltage).unwrap()).unwrap_or(0);
+            battery_level::convert_adc_measurement(saadc.read(&mut pin_voltage).unwrap()).unwrap_or(0);
 
         Self {
             pin_charge_indication,
@@ -45,17 +46,6 @@ impl BatteryStatus {
         }
     }
 
-    /// Convert a raw ADC measurement into a battery voltage in 0.1 volts.
-    fn convert_adc_measurement(raw_measurement: i16) -> Option<u8> {
-        if raw_measurement < 0 {
-            // What?
-            return None;
-        }
-        let adc_val: u32 = (raw_measurement as u16).into(); // keep as 32bit for multiplication
-        let battery_voltage: u32 = (adc_val * 2000) / 4965; // we multiply the ADC value by 2 * 1000 for mV and divide by (2 ^ 14 / 3.3V reference)
-        Some((battery_voltage / 100) as u8)
-    }
-
     /// Return whether the watch is currently charging.
     ///
     /// This returns the stored value. To fetch current data, call `update()` first.
@@ -95,7 +85,7 @@ impl BatteryStatus {
 
         // Check voltage
         let voltage =
-            Self::convert_adc_measurement(self.saadc.read(&mut self.pin_voltage).unwrap())
+            battery_level::convert_adc_measurement(self.saadc.read(&mut self.pin_voltage).unwrap())
                 .unwrap_or(0);
         if voltage != self.voltage {
             self.voltage = voltage;
@@ -0,0 +1,31 @@
+//! Battery voltage math, kept apart from the SAADC driver in `battery`.
+
+/// Convert a raw ADC measurement into a battery voltage in 0.1 volts.
+pub fn convert_adc_measurement(raw_measurement: i16) -> Option<u8> {
+    if raw_measurement < 0 {
+        // What?
+        return None;
+    }
+    let adc_val: u32 = (raw_measurement as u16).into(); // keep as 32bit for multiplication
+    let battery_voltage: u32 = (adc_val * 2000) / 4965; // we multiply the ADC value by 2 * 1000 for mV and divide by (2 ^ 14 / 3.3V reference)
+    Some((battery_voltage / 100) as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negative_measurement() {
+        assert_eq!(convert_adc_measurement(-1), None);
+    }
+
+    #[test]
+    fn voltage_in_tenths_of_volt() {
+        assert_eq!(convert_adc_measurement(0), Some(0));
+        assert_eq!(convert_adc_measurement(4593), Some(18));
+        // 3.7 V battery is 1.85 V on the pin behind the divider
+        assert_eq!(convert_adc_measurement(9186), Some(37));
+        assert_eq!(convert_adc_measurement(i16::MAX), Some(131));
+    }
+}
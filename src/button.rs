@@ -0,0 +1,35 @@
+use embedded_hal::digital::v2::InputPin;
+use nrf52832_hal::gpio::{p0, Input, Output, PullDown, PushPull};
+use pt_hello::input::{ButtonDecoder, InputEvent};
+
+/// The side button, polled.
+///
+/// The button only drives its pin while the enable pin is high, which it
+/// stays for as long as the button is kept.
+pub struct Button {
+    /// Pin P0.13: High = pressed
+    pin_in: p0::P0_13<Input<PullDown>>,
+
+    /// Pin P0.15: High = button powered
+    #[allow(unused)]
+    pin_enable: p0::P0_15<Output<PushPull>>,
+
+    decoder: ButtonDecoder,
+}
+
+impl Button {
+    /// Initialize the button, `pin_enable` is expected high already.
+    pub fn init(pin_in: p0::P0_13<Input<PullDown>>, pin_enable: p0::P0_15<Output<PushPull>>) -> Self {
+        Self {
+            pin_in,
+            pin_enable,
+            decoder: ButtonDecoder::new(),
+        }
+    }
+
+    /// Read the button at `now_ms`, return the event it makes, if any.
+    pub fn poll(&mut self, now_ms: u32) -> Option<InputEvent> {
+        let pressed = self.pin_in.is_high().unwrap();
+        self.decoder.update(pressed, now_ms)
+    }
+}
@@ -1,242 +1,146 @@
+use core::sync::atomic::{
+    compiler_fence,
+    Ordering,
+};
 use embedded_graphics::{
-    style,
     prelude::*,
-    pixelcolor,
-    primitives,
-    fonts
+    pixelcolor::Rgb565,
+    primitives::Rectangle,
+    style::{PrimitiveStyle, Styled},
 };
 use nrf52832_hal::{
     gpio,
     spim,
     pac,
 };
+use pt_hello::{
+    render::WindowWrite,
+    scroll::VerticalScroll,
+};
 
-const BACKGROUND_COLOR: pixelcolor::Rgb565 = pixelcolor::Rgb565::WHITE;
-const AXES_COLOR:       pixelcolor::Rgb565 = pixelcolor::Rgb565::BLACK;
-const LINE_COLOR:       pixelcolor::Rgb565 = pixelcolor::Rgb565::RED;
-
-#[allow(unused)]
-const MARGIN: u16 = 10;
-pub const LCD_W: u16 = 240;
-pub const LCD_H: u16 = 240;
+pub use pt_hello::plot::{
+    LCD_W,
+    LCD_H,
+};
 
 type SPIType    = spim::Spim<pac::SPIM1>;
 type DCType     = gpio::p0::P0_18<gpio::Output<gpio::PushPull>>;
 type RSTType    = gpio::p0::P0_26<gpio::Output<gpio::PushPull>>;
 type DELAYType  = crate::DisplayDelayProviderType;
-type ErrorType  = core::convert::Infallible;
 
 pub type DisplayType        = st7789::ST7789<SPIType, DCType, RSTType, DELAYType>;
-pub type DisplayErrorType   = st7789::Error<ErrorType, ErrorType, ErrorType>;
-
-pub struct DisplayDriver 
-{
-    pub display_driver: DisplayType,
-    plot_values: [Point; LCD_W as usize]
-}
-
-impl DisplayDriver {
-    pub fn new(display_driver: DisplayType) -> Self {
-        DisplayDriver { 
-            display_driver, 
-            plot_values: [Point::default(); LCD_W as usize]
-        }
-    }
-    
-    pub fn init(&mut self) -> Result<(), DisplayErrorType> {
-        self.display_driver.init().unwrap();       
-        self.display_driver.set_orientation(&st7789::Orientation::Portrait).unwrap();
-        Ok(())
-    }
+pub type DisplayErrorType   = <DisplayType as DrawTarget<Rgb565>>::Error;
 
-    pub fn update(&mut self) -> Result<(), DisplayErrorType> {
-        self.clear_sin()?;
-        self.rotate_sin();
-        self.draw_axes()?;
-        self.draw_sin()?;
-        
-        Ok(())
-    }
+/// Screens of the watch on the ST7789, see `pt_hello::ui`
+pub type Ui = pt_hello::ui::Ui<Lcd>;
 
-    #[allow(unused)]
-    pub fn draw_text(&mut self) -> Result<(), DisplayErrorType> {
-        // Draw something onto the LCD
-        let backdrop_style = style::PrimitiveStyleBuilder::new()
-            .fill_color(pixelcolor::Rgb565::RED)
-            .build();
-
-        primitives::rectangle::Rectangle::new(
-            Point::new(0, 0), 
-            Point::new(LCD_W as i32, LCD_H as i32)
-        )
-            .into_styled(backdrop_style)
-            .draw(&mut self.display_driver).unwrap();
-
-        // Choose text style
-        let text_style = style::TextStyleBuilder::new(fonts::Font12x16)
-            .text_color(pixelcolor::Rgb565::WHITE)
-            .background_color(AXES_COLOR);
-
-        // Draw text
-        fonts::Text::new("HRS data ...", Point::new(10, 10))
-            .into_styled(text_style.build())
-            .draw(&mut self.display_driver).unwrap();
-
-        // Draw text
-        fonts::Text::new("20%", Point::new(10, 10 + 16 + MARGIN as i32))
-            .into_styled(text_style.build())
-            .draw(&mut self.display_driver).unwrap();
-
-        Ok(())
-    }
+/// Data/command pin of the display, P0.18
+const DC_PIN: u32 = 18;
 
-    pub fn draw_backgound(&mut self)  -> Result<(), DisplayErrorType> {
-        // background
-        let backdrop_style = style::PrimitiveStyleBuilder::new()
-        .fill_color(BACKGROUND_COLOR)
-        .build();
+// ST7789 commands the driver doesn't have
+const VSCRDEF: u8 = 0x33;
+const VSCSAD: u8 = 0x37;
 
-        primitives::rectangle::Rectangle::new(
-        Point::new(0, 0), 
-        Point::new(LCD_W as i32, LCD_H as i32)
-        )
-        .into_styled(backdrop_style)
-        .draw(&mut self.display_driver).unwrap();
+/// The ST7789 with the commands the `st7789` driver is missing.
+///
+/// They go straight to the SPIM1 and DC registers, which the driver owns,
+/// so they are only sent through the owner of the driver, between its calls.
+pub struct Lcd(pub DisplayType);
 
-        Ok(())
-    }
+impl Lcd {
+    fn write_command(&mut self, command: u8, params: &[u8]) -> Result<(), DisplayErrorType> {
+        let p0 = unsafe { &*pac::P0::ptr() };
 
-    pub fn draw_axes(&mut self) -> Result<(), DisplayErrorType> {
-        let line_style = style::PrimitiveStyleBuilder::new()
-        .stroke_color(AXES_COLOR)
-        .stroke_width(1)
-        .build();
-        
-        // X axis
-        let ox1 = Point::new(0,             LCD_H as i32 / 2);
-        let ox2 = Point::new(LCD_W as i32,  LCD_H as i32 / 2 + 1);
-        primitives::line::Line::new(ox1, ox2)
-            .into_styled(line_style)
-            .draw(&mut self.display_driver).unwrap();
-
-        // Y axis
-        let oy1 = Point::new(LCD_H as i32 / 2,      0);
-        let oy2 = Point::new(LCD_H as i32 / 2 + 1,  LCD_H as i32);
-        primitives::line::Line::new(oy1, oy2)
-            .into_styled(line_style)
-            .draw(&mut self.display_driver).unwrap();
+        p0.outclr.write(|w| unsafe { w.bits(1 << DC_PIN) });
+        let result = Self::write_bytes(&[command]);
+        p0.outset.write(|w| unsafe { w.bits(1 << DC_PIN) });
+        result?;
 
-        Ok(())
+        Self::write_bytes(params)
     }
 
-    pub fn count_sin(&mut self) {
-        let x_min = -(LCD_W as i32) / 2;
-        let mut x = x_min-1;
-        let mut y: i32;
+    /// Blocking EasyDMA transfer, like the HAL does it
+    fn write_bytes(bytes: &[u8]) -> Result<(), DisplayErrorType> {
+        let spim = unsafe { &*pac::SPIM1::ptr() };
 
-        let div = 10_f32;
-        let mul = 100_f32;
+        // EasyDMA reads from RAM only, the bytes may be in flash
+        let mut buffer = [0_u8; 8];
+        let buffer = &mut buffer[..bytes.len()];
+        buffer.copy_from_slice(bytes);
+        if buffer.is_empty() {
+            return Ok(());
+        }
 
-        for p in self.plot_values.iter_mut() {
-            x += 1;
-            y = sin(x, div, mul);
+        compiler_fence(Ordering::SeqCst);
+        spim.txd.ptr.write(|w| unsafe { w.ptr().bits(buffer.as_ptr() as u32) });
+        spim.txd.maxcnt.write(|w| unsafe { w.maxcnt().bits(buffer.len() as _) });
+        spim.rxd.maxcnt.write(|w| unsafe { w.maxcnt().bits(0) });
+        spim.tasks_start.write(|w| unsafe { w.bits(1) });
+        compiler_fence(Ordering::SeqCst);
 
-            p.x = x;
-            p.y = y;
+        while spim.events_end.read().bits() == 0 {}
+        spim.events_end.reset();
+        compiler_fence(Ordering::SeqCst);
 
-            *p = transform(*p);
+        if spim.txd.amount.read().bits() != buffer.len() as u32 {
+            return Err(st7789::Error::Spi(spim::Error::Transmit));
         }
+        Ok(())
     }
+}
 
-    pub fn draw_sin(&mut self) -> Result<(), DisplayErrorType> {
-        let backdrop_style = style::PrimitiveStyleBuilder::new()
-            .stroke_color(LINE_COLOR)
-            .stroke_width(1)
-            .build();
-
-        let mut p1: &Point = &self.plot_values[0];
-        for p2 in &(self.plot_values[1..]) {
-            if p1.y <= p2.y {
-                primitives::line::Line::new(*p1, *p2)
-                    .into_styled(backdrop_style)
-                    .draw(&mut self.display_driver).unwrap();
-            }
-
-            p1 = p2;
-        }
+impl DrawTarget<Rgb565> for Lcd {
+    type Error = DisplayErrorType;
 
-        Ok(())
+    fn draw_pixel(&mut self, item: Pixel<Rgb565>) -> Result<(), Self::Error> {
+        self.0.draw_pixel(item)
     }
 
-    pub fn clear_sin(&mut self) -> Result<(), DisplayErrorType> {
-        let backdrop_style = style::PrimitiveStyleBuilder::new()
-            .stroke_color(BACKGROUND_COLOR)
-            .stroke_width(1)
-            .build();
-
-        let mut p1: &Point = &self.plot_values[0];
-        for p2 in &(self.plot_values[1..]) {
-            if p1.y <= p2.y {
-                primitives::line::Line::new(*p1, *p2)
-                    .into_styled(backdrop_style)
-                    .draw(&mut self.display_driver).unwrap();
-            }
-
-            p1 = p2;
-        }
+    fn draw_iter<T>(&mut self, item: T) -> Result<(), Self::Error>
+    where
+        T: IntoIterator<Item = Pixel<Rgb565>>,
+    {
+        self.0.draw_iter(item)
+    }
 
-        Ok(())
+    fn size(&self) -> Size {
+        self.0.size()
     }
 
-    pub fn rotate_sin(&mut self) {
-        let y = self.plot_values[0].y;
-        for i in 1..self.plot_values.len() {
-            self.plot_values[i-1].y = self.plot_values[i].y;
-        }
-        (self.plot_values.last_mut().unwrap()).y = y;
+    fn clear(&mut self, color: Rgb565) -> Result<(), Self::Error> {
+        self.0.clear(color)
     }
-}
 
-fn transform(p: Point) -> Point {
-    Point::new(
-        clamp(p.y + LCD_H as i32 / 2, 0, LCD_W),
-        clamp(p.x + LCD_W as i32 / 2, 0, LCD_H)
-    )
+    fn draw_rectangle(&mut self, item: &Styled<Rectangle, PrimitiveStyle<Rgb565>>) -> Result<(), Self::Error> {
+        self.0.draw_rectangle(item)
+    }
 }
 
-fn clamp(x: i32, min: u16, max: u16) -> i32 {
-    let x_u16 = x as u16;
-    if x_u16 > max { max as i32 }
-    else if x_u16 < min { min as i32 }
-    else { x as i32 }
+impl WindowWrite for Lcd {
+    fn write_window<I>(&mut self, top_left: Point, bottom_right: Point, colors: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Rgb565>,
+    {
+        self.0.write_window(top_left, bottom_right, colors)
+    }
 }
 
-// y = mul * sin(x / div)
-fn sin(x: i32, div: f32, mul: f32) -> i32 {
-    let mut y: f32 = 0_f32;
-    let x = x as f32;
-
-    let mut sign: f32 = 1_f32;
-    let mut dx: f32 = x / div;
-
-    let mut den_factor: f32 = 1_f32;
-
-    loop {
-        y += sign * dx; 
-
-        // calculate next addition            
-        dx *= x / div * x / div;
-
-        den_factor += 1_f32;
-        dx /= den_factor;
-        den_factor += 1_f32;
-        dx /= den_factor;
-        //println!("dx: '{}'", dx);
-
-        if dx < 0.1_f32 && dx > -0.1_f32 { break; }
+impl VerticalScroll for Lcd {
+    fn define_scroll_area(&mut self, top_fixed: u16, scroll_lines: u16, bottom_fixed: u16) -> Result<(), Self::Error> {
+        let [t0, t1] = top_fixed.to_be_bytes();
+        let [s0, s1] = scroll_lines.to_be_bytes();
+        let [b0, b1] = bottom_fixed.to_be_bytes();
+        self.write_command(VSCRDEF, &[t0, t1, s0, s1, b0, b1])
+    }
 
-        sign *= -1_f32;
+    fn set_scroll_start(&mut self, line: u16) -> Result<(), Self::Error> {
+        self.write_command(VSCSAD, &line.to_be_bytes())
     }
+}
 
-    (y * mul) as i32
-}
\ No newline at end of file
+/// Resets the controller and sets the orientation, before anything is drawn
+pub fn init(lcd: &mut Lcd) -> Result<(), DisplayErrorType> {
+    lcd.0.init()?;
+    lcd.0.set_orientation(&st7789::Orientation::Portrait)?;
+    Ok(())
+}
@@ -0,0 +1,388 @@
+//! Sampling of the HRS3300 without the CPU.
+//!
+//! TIMER2 fires every `ADCWaitTime` and starts the TWIM0 burst read of the
+//! sample registers through PPI, EasyDMA writes the 7 bytes into the next slot
+//! of a double buffer. A second PPI channel counts the finished transfers on
+//! TIMER3, only when a block of samples is in one half of the buffer the CPU
+//! wakes up: the TIMER3 handler decodes the block, runs the AGC and puts the
+//! samples into the same queue `sampler` uses, while the other half is filled.
+//!
+//! The sensor driver keeps owning TWIM0, the registers are only borrowed while
+//! the transfers run. `with_sensor` stops them, so the blocking driver calls
+//! work in between. The state is a `Resource` with the sampling priority as
+//! its ceiling, the timer queue above it keeps running while the bus is busy.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{
+    AtomicU32,
+    Ordering,
+};
+use embedded_hal::timer::CountDown;
+use nrf52832_hal::{
+    pac::{
+        self,
+        interrupt,
+    },
+    timer::{
+        Timer,
+        Periodic,
+    },
+    twim,
+};
+use pt_hello::{
+    agc::{self, Agc, AgcSample},
+    hrs3300::{
+        BitsResolution,
+        RegAddrs,
+        SensorError,
+        SENSOR_ADDR,
+        SAMPLE_BLOCK_LEN,
+    },
+    sample_timing::BlockStamper,
+};
+use crate::{
+    app,
+    SensorType,
+    SensorErrorType,
+    SampleTimerType,
+    monotonic_nrf52::Instant,
+    resource::Resource,
+    sampler::{
+        TimedSample,
+        SampleProducer,
+    },
+};
+
+/// Samples per block, one wake-up every 200 ms at 12.5 ms
+pub const BLOCK_LEN: usize = 16;
+/// Counter of the finished transfers
+pub type BlockTimerType = pac::TIMER3;
+/// PPI channels used, the rest is left free
+const TRIGGER_CHANNEL: usize = 0;
+const COUNT_CHANNEL: usize = 1;
+/// Longest transfer at 400 kHz is ~250 µs, with some margin
+const TRANSFER_US: u32 = 400;
+const CYCLES_PER_US: u32 = 64;
+
+/// EasyDMA reaches RAM only, so the register address to send lives here too
+struct DmaBuffers {
+    tx: [u8; 1],
+    rx: [[u8; SAMPLE_BLOCK_LEN]; 2 * BLOCK_LEN],
+}
+struct DmaCell(UnsafeCell<DmaBuffers>);
+// written by EasyDMA, the handler reads only the half which is not being filled
+unsafe impl Sync for DmaCell {}
+
+static BUFFERS: DmaCell = DmaCell(UnsafeCell::new(DmaBuffers {
+    tx: [RegAddrs::C1DATAM as u8],
+    rx: [[0; SAMPLE_BLOCK_LEN]; 2 * BLOCK_LEN],
+}));
+
+struct DmaSampler {
+    sensor: SensorType,
+    agc: Agc,
+    timer: Timer<SampleTimerType, Periodic>,
+    counter: BlockTimerType,
+    ppi: pac::PPI,
+    producer: SampleProducer,
+    period_us: u32,
+    /// Sequence numbers and times of the blocks
+    stamps: BlockStamper,
+    /// Half of the buffer being filled
+    half: usize,
+    /// Transfers of the current block which failed
+    failed: u32,
+    /// Last failed transfer, kept until the process task takes it
+    error: Option<SensorErrorType>,
+}
+
+static SAMPLER: Resource<DmaSampler> = Resource::new(app::SAMPLING_PRIORITY);
+/// Samples lost because the queue was full
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+/// Transfers failed on the bus
+static BUS_ERRORS: AtomicU32 = AtomicU32::new(0);
+/// Blocks the CPU was woken up for
+static BLOCKS: AtomicU32 = AtomicU32::new(0);
+
+/// Starts sampling at the configured `ADCWaitTime`, the sensor is expected
+/// to be running already
+pub fn start(
+    sensor: SensorType,
+    timer: SampleTimerType,
+    counter: BlockTimerType,
+    ppi: pac::PPI,
+    producer: SampleProducer,
+) {
+    let period_us = sensor.get_adc_wait_time_us();
+
+    // counts finished transfers, wakes up the CPU once per block
+    counter.mode.write(|w| w.mode().counter());
+    counter.bitmode.write(|w| w.bitmode()._32bit());
+    counter.cc[0].write(|w| unsafe { w.bits(BLOCK_LEN as u32) });
+    counter.shorts.write(|w| w.compare0_clear().enabled());
+    counter.events_compare[0].reset();
+    counter.intenset.write(|w| w.compare0().set());
+    counter.tasks_clear.write(|w| unsafe { w.bits(1) });
+    counter.tasks_start.write(|w| unsafe { w.bits(1) });
+
+    let twim = twim0();
+    ppi.ch[TRIGGER_CHANNEL].eep.write(|w| unsafe { w.bits(timer_event_address(&timer)) });
+    ppi.ch[TRIGGER_CHANNEL].tep.write(|w| unsafe { w.bits(&twim.tasks_starttx as *const _ as u32) });
+    ppi.ch[COUNT_CHANNEL].eep.write(|w| unsafe { w.bits(&twim.events_stopped as *const _ as u32) });
+    ppi.ch[COUNT_CHANNEL].tep.write(|w| unsafe { w.bits(&counter.tasks_count as *const _ as u32) });
+
+    let mut sampler = DmaSampler {
+        sensor,
+        agc: Agc::new(BitsResolution::_14, BitsResolution::_16),
+        timer: Timer::periodic(timer),
+        counter,
+        ppi,
+        producer,
+        period_us,
+        stamps: BlockStamper::new(period_us),
+        half: 0,
+        failed: 0,
+        error: None,
+    };
+    sampler.resume();
+
+    SAMPLER.replace(Some(sampler));
+
+    unsafe {
+        pac::NVIC::unmask(pac::Interrupt::TIMER3);
+        pac::NVIC::unmask(pac::Interrupt::SPIM0_SPIS0_TWIM0_TWIS0_SPI0_TWI0);
+    }
+}
+
+/// Stops sampling, gives back the sensor and the peripherals.
+/// Samples of the unfinished block are lost.
+pub fn stop() -> Option<(SensorType, SampleTimerType, BlockTimerType, pac::PPI)> {
+    pac::NVIC::mask(pac::Interrupt::TIMER3);
+    pac::NVIC::mask(pac::Interrupt::SPIM0_SPIS0_TWIM0_TWIS0_SPI0_TWI0);
+
+    // the interrupts are off, the transfers stop without a lock
+    let mut sampler = SAMPLER.replace(None)?;
+    sampler.pause();
+
+    let counter = sampler.counter;
+    counter.intenclr.write(|w| w.compare0().clear());
+    counter.tasks_stop.write(|w| unsafe { w.bits(1) });
+    counter.shorts.reset();
+    counter.events_compare[0].reset();
+
+    Some((sampler.sensor, sampler.timer.free(), counter, sampler.ppi))
+}
+
+/// Runs `f` on the sensor with the transfers stopped, e.g. to set it up again
+/// after bus errors. The AGC and the block start over afterwards.
+pub fn with_sensor<R>(f: impl FnOnce(&mut SensorType) -> R) -> Option<R> {
+    SAMPLER.try_lock(|sampler| {
+        sampler.pause();
+        let result = f(&mut sampler.sensor);
+        sampler.agc.reset();
+        sampler.resume();
+        result
+    })
+}
+
+/// Error of the last failed transfer since the previous call
+pub fn take_error() -> Option<SensorErrorType> {
+    SAMPLER.try_lock(|sampler| sampler.error.take()).flatten()
+}
+
+pub fn dropped() -> u32 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+#[allow(unused)]
+pub fn bus_errors() -> u32 {
+    BUS_ERRORS.load(Ordering::Relaxed)
+}
+
+/// CPU wake-ups for finished blocks
+#[allow(unused)]
+pub fn blocks() -> u32 {
+    BLOCKS.load(Ordering::Relaxed)
+}
+
+fn twim0() -> &'static pac::twim0::RegisterBlock {
+    // the sensor owns TWIM0, registers are touched only while it doesn't use them
+    unsafe { &*pac::TWIM0::ptr() }
+}
+
+fn timer_event_address(timer: &SampleTimerType) -> u32 {
+    &timer.events_compare[0] as *const _ as u32
+}
+
+/// Waits for the transfer started `elapsed_us` ago to end, at most until the
+/// longest one would be done. Right after `resume` nothing may have started,
+/// then the wait is simply longer.
+fn wait_for_transfer(elapsed_us: u32) {
+    let twim = twim0();
+    // set by every transfer, the one running now sets it again
+    twim.events_stopped.reset();
+    for _ in elapsed_us..TRANSFER_US {
+        if twim.events_error.read().bits() != 0 {
+            // the master doesn't stop by itself after an error
+            twim.events_error.reset();
+            twim.tasks_stop.write(|w| unsafe { w.bits(1) });
+        }
+        if twim.events_stopped.read().bits() != 0 {
+            return;
+        }
+        cortex_m::asm::delay(CYCLES_PER_US);
+    }
+}
+
+fn rx_slot(index: usize) -> u32 {
+    unsafe { (*BUFFERS.0.get()).rx.as_ptr().add(index) as u32 }
+}
+
+impl DmaSampler {
+    /// Sets TWIM0 up for the triggered reads and starts the timer,
+    /// filling starts over with the first half
+    fn resume(&mut self) {
+        let twim = twim0();
+        let tx = unsafe { (*BUFFERS.0.get()).tx.as_ptr() as u32 };
+        twim.address.write(|w| unsafe { w.address().bits(SENSOR_ADDR) });
+        twim.txd.ptr.write(|w| unsafe { w.ptr().bits(tx) });
+        twim.txd.maxcnt.write(|w| unsafe { w.maxcnt().bits(1) });
+        twim.txd.list.write(|w| w.list().disabled());
+        // each transfer moves the pointer to the next slot
+        twim.rxd.ptr.write(|w| unsafe { w.ptr().bits(rx_slot(0)) });
+        twim.rxd.maxcnt.write(|w| unsafe { w.maxcnt().bits(SAMPLE_BLOCK_LEN as u8) });
+        twim.rxd.list.write(|w| w.list().array_list());
+        twim.shorts.write(|w| w.lasttx_startrx().enabled().lastrx_stop().enabled());
+        twim.events_stopped.reset();
+        twim.events_error.reset();
+        twim.intenset.write(|w| w.error().set());
+
+        self.half = 0;
+        self.failed = 0;
+        self.counter.tasks_clear.write(|w| unsafe { w.bits(1) });
+        self.counter.events_compare[0].reset();
+
+        self.ppi.chenset.write(|w| w.ch0().set().ch1().set());
+        self.timer.start(self.period_us);
+    }
+
+    /// Stops the triggers and leaves TWIM0 as the driver expects it
+    fn pause(&mut self) {
+        self.ppi.chenclr.write(|w| w.ch0().clear());
+        // the timer is cleared by every trigger, it counts microseconds
+        let elapsed_us = self.timer.read();
+        // timer.cancel() is infallible
+        let _ = embedded_hal::timer::Cancel::cancel(&mut self.timer);
+
+        // a transfer started just before has to finish, it's still counted
+        if elapsed_us < TRANSFER_US {
+            wait_for_transfer(elapsed_us);
+        }
+        self.ppi.chenclr.write(|w| w.ch1().clear());
+
+        // samples of the unfinished block are lost, their numbers too
+        self.counter.tasks_capture[1].write(|w| unsafe { w.bits(1) });
+        self.stamps.skip(self.counter.cc[1].read().bits());
+
+        let twim = twim0();
+        twim.intenclr.write(|w| w.error().clear());
+        twim.shorts.reset();
+        twim.rxd.list.write(|w| w.list().disabled());
+        twim.events_stopped.reset();
+        twim.events_error.reset();
+        twim.events_lastrx.reset();
+        twim.events_lasttx.reset();
+        // an error of the last transfer is not for `on_error`
+        pac::NVIC::unpend(pac::Interrupt::SPIM0_SPIS0_TWIM0_TWIS0_SPI0_TWI0);
+    }
+
+    fn on_block(&mut self) {
+        self.counter.events_compare[0].reset();
+        BLOCKS.fetch_add(1, Ordering::Relaxed);
+        // runs once this handler is done, with the samples or the error
+        app::spawn(app::Task::Process);
+
+        let done = self.half;
+        self.half = 1 - done;
+        // pointer has just moved past the end of the half, the next
+        // transfer starts no earlier than one period from now
+        twim0().rxd.ptr.write(|w| unsafe { w.ptr().bits(rx_slot(self.half * BLOCK_LEN)) });
+
+        if self.failed > 0 {
+            // a failed transfer leaves the pointer at an unknown slot,
+            // the block is dropped and filling starts over
+            self.stamps.finish_block(BLOCK_LEN);
+            self.pause();
+            self.agc.reset();
+            self.resume();
+            return;
+        }
+
+        let end_us = Instant::now().as_micros();
+        let mut change = None;
+        for i in 0..BLOCK_LEN {
+            // EasyDMA wrote the slot, the compiler doesn't know
+            let block = unsafe {
+                core::ptr::read_volatile((*BUFFERS.0.get()).rx.as_ptr().add(done * BLOCK_LEN + i))
+            };
+            let sample = self.sensor.decode_sample(&block);
+            let agc_sample = if change.is_none() {
+                let agc_sample = self.agc.consume_sample(sample, self.sensor.get_config().into());
+                change = agc_sample.change;
+                agc_sample
+            } else {
+                // the AGC already wants another setting, the rest is skipped
+                AgcSample { sample, settling: true, change: None }
+            };
+
+            // the last transfer has just finished, the others were one period apart
+            let stamp = self.stamps.stamp(i, BLOCK_LEN, end_us);
+            let timed = TimedSample { stamp, sample: agc_sample };
+            if self.producer.enqueue(timed).is_err() {
+                DROPPED.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.stamps.finish_block(BLOCK_LEN);
+
+        if let Some(next) = change {
+            // block was taken with the old setting, the new one starts now
+            self.pause();
+            if let Err(e) = agc::apply(&mut self.sensor, next) {
+                BUS_ERRORS.fetch_add(1, Ordering::Relaxed);
+                self.error = Some(e);
+            }
+            self.agc.hold();
+            self.resume();
+        }
+    }
+
+    fn on_error(&mut self) {
+        let twim = twim0();
+        twim.events_error.reset();
+        // STOPPED follows and counts the transfer as done
+        twim.tasks_stop.write(|w| unsafe { w.bits(1) });
+
+        let errorsrc = twim.errorsrc.read();
+        let error = if errorsrc.overrun().is_received() {
+            twim::Error::Receive
+        } else {
+            twim::Error::Transmit
+        };
+        // bits are cleared by writing them back
+        twim.errorsrc.write(|w| unsafe { w.bits(errorsrc.bits()) });
+
+        BUS_ERRORS.fetch_add(1, Ordering::Relaxed);
+        self.failed += 1;
+        self.error = Some(SensorError::Bus(error));
+    }
+}
+
+#[interrupt]
+fn TIMER3() {
+    SAMPLER.try_lock(DmaSampler::on_block);
+}
+
+#[interrupt]
+fn SPIM0_SPIS0_TWIM0_TWIS0_SPI0_TWI0() {
+    SAMPLER.try_lock(DmaSampler::on_error);
+}
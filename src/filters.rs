@@ -0,0 +1,493 @@
+//! Fixed-point filters for PPG conditioning.
+//!
+//! Filters work on `i32` values, e.g. `RawSample::get_normalised_sum()`, and
+//! are chained into a pipeline with `Filter::then`. Coefficients are designed once in `f32`
+//! and kept in fixed point, so processing a sample is integer only.
+//!
+//! ```ignore
+//! let mut pipeline = DcBlocker::new(0.3, 80.0)
+//!     .then(Biquad::notch(20.0, 80.0, 5.0))
+//!     .then(Fir::<4>::moving_average());
+//! let value = pipeline.process_sample(raw_sample);
+//! ```
+
+use crate::hrs3300::RawSample;
+
+/// Biquad coefficients are Q2.29, enough for poles close to 1 at low cutoffs
+const BIQUAD_FRAC_BITS: u32 = 29;
+/// FIR taps and DC blocker pole are Q15
+const Q15_FRAC_BITS: u32 = 15;
+
+/// Lower edge of the cardiac band, 30 BPM
+pub const CARDIAC_LOW_HZ: f32 = 0.5;
+/// Upper edge of the cardiac band, 240 BPM
+pub const CARDIAC_HIGH_HZ: f32 = 4.0;
+
+const BUTTERWORTH_Q: f32 = core::f32::consts::FRAC_1_SQRT_2;
+
+/// One processing stage
+pub trait Filter {
+    /// Takes the next input value, returns the next output value
+    fn process(&mut self, x: i32) -> i32;
+
+    /// Forgets the history, as if no value was processed yet
+    fn reset(&mut self);
+
+    fn process_sample(&mut self, sample: RawSample) -> i32 {
+        self.process(sample.get_normalised_sum() as i32)
+    }
+
+    /// Feeds the output of this filter into `next`
+    fn then<F: Filter>(self, next: F) -> Chain<Self, F>
+    where
+        Self: Sized
+    {
+        Chain { first: self, second: next }
+    }
+}
+
+/// Two filters in series, built by `Filter::then`
+pub struct Chain<A, B> {
+    first: A,
+    second: B,
+}
+impl<A: Filter, B: Filter> Filter for Chain<A, B> {
+    fn process(&mut self, x: i32) -> i32 {
+        let y = self.first.process(x);
+        self.second.process(y)
+    }
+
+    fn reset(&mut self) {
+        self.first.reset();
+        self.second.reset();
+    }
+}
+
+/// Second order IIR section, direct form I with error feedback
+/// so truncation doesn't add a DC offset at low cutoff frequencies.
+#[derive(Clone, Debug)]
+pub struct Biquad {
+    b0: i32,
+    b1: i32,
+    b2: i32,
+    a1: i32,
+    a2: i32,
+    x1: i32,
+    x2: i32,
+    y1: i32,
+    y2: i32,
+    error: i64,
+}
+
+impl Biquad {
+    /// Section from `b` and `a` coefficients, `a[0]` is normalised to 1
+    pub fn from_coefficients(b: [f32; 3], a: [f32; 3]) -> Self {
+        let q = |c: f32| to_fixed(c / a[0], BIQUAD_FRAC_BITS);
+        Biquad {
+            b0: q(b[0]),
+            b1: q(b[1]),
+            b2: q(b[2]),
+            a1: q(a[1]),
+            a2: q(a[2]),
+            x1: 0,
+            x2: 0,
+            y1: 0,
+            y2: 0,
+            error: 0,
+        }
+    }
+
+    pub fn lowpass(cutoff_hz: f32, sample_rate_hz: f32, q: f32) -> Self {
+        let (_, cos, alpha) = Self::design(cutoff_hz, sample_rate_hz, q);
+        // 1 - cos(w0), without cancellation at low cutoffs
+        let one_minus_cos = 2_f32 * sin_half_squared(cutoff_hz, sample_rate_hz);
+        Self::from_coefficients(
+            [one_minus_cos / 2_f32, one_minus_cos, one_minus_cos / 2_f32],
+            [1_f32 + alpha, -2_f32 * cos, 1_f32 - alpha],
+        )
+    }
+
+    pub fn highpass(cutoff_hz: f32, sample_rate_hz: f32, q: f32) -> Self {
+        let (_, cos, alpha) = Self::design(cutoff_hz, sample_rate_hz, q);
+        let one_plus_cos = 1_f32 + cos;
+        Self::from_coefficients(
+            [one_plus_cos / 2_f32, -one_plus_cos, one_plus_cos / 2_f32],
+            [1_f32 + alpha, -2_f32 * cos, 1_f32 - alpha],
+        )
+    }
+
+    /// Band-pass with 0 dB gain at `center_hz`
+    pub fn bandpass(center_hz: f32, sample_rate_hz: f32, q: f32) -> Self {
+        let (_, cos, alpha) = Self::design(center_hz, sample_rate_hz, q);
+        Self::from_coefficients(
+            [alpha, 0_f32, -alpha],
+            [1_f32 + alpha, -2_f32 * cos, 1_f32 - alpha],
+        )
+    }
+
+    /// Removes `center_hz`, e.g. light flicker aliased into the band
+    pub fn notch(center_hz: f32, sample_rate_hz: f32, q: f32) -> Self {
+        let (_, cos, alpha) = Self::design(center_hz, sample_rate_hz, q);
+        Self::from_coefficients(
+            [1_f32, -2_f32 * cos, 1_f32],
+            [1_f32 + alpha, -2_f32 * cos, 1_f32 - alpha],
+        )
+    }
+
+    /// Butterworth low-pass
+    pub fn butterworth_lowpass(cutoff_hz: f32, sample_rate_hz: f32) -> Self {
+        Self::lowpass(cutoff_hz, sample_rate_hz, BUTTERWORTH_Q)
+    }
+
+    /// Butterworth high-pass
+    pub fn butterworth_highpass(cutoff_hz: f32, sample_rate_hz: f32) -> Self {
+        Self::highpass(cutoff_hz, sample_rate_hz, BUTTERWORTH_Q)
+    }
+
+    // (sin(w0), cos(w0), alpha) of the Audio EQ Cookbook designs
+    fn design(f0_hz: f32, sample_rate_hz: f32, q: f32) -> (f32, f32, f32) {
+        let w0 = 2_f32 * core::f32::consts::PI * f0_hz / sample_rate_hz;
+        let (sin, cos) = sin_cos(w0);
+        (sin, cos, sin / (2_f32 * q))
+    }
+}
+
+impl Filter for Biquad {
+    fn process(&mut self, x: i32) -> i32 {
+        let acc = self.b0 as i64 * x as i64
+            + self.b1 as i64 * self.x1 as i64
+            + self.b2 as i64 * self.x2 as i64
+            - self.a1 as i64 * self.y1 as i64
+            - self.a2 as i64 * self.y2 as i64
+            + self.error;
+
+        let y = acc >> BIQUAD_FRAC_BITS;
+        self.error = acc - (y << BIQUAD_FRAC_BITS);
+        let y = saturate(y);
+
+        self.x2 = self.x1;
+        self.x1 = x;
+        self.y2 = self.y1;
+        self.y1 = y;
+
+        y
+    }
+
+    fn reset(&mut self) {
+        self.x1 = 0;
+        self.x2 = 0;
+        self.y1 = 0;
+        self.y2 = 0;
+        self.error = 0;
+    }
+}
+
+/// FIR filter with `N` Q15 taps
+#[derive(Clone, Debug)]
+pub struct Fir<const N: usize> {
+    taps: [i32; N],
+    history: [i32; N],
+    cursor: usize,
+}
+
+impl<const N: usize> Fir<N> {
+    /// Taps in Q15, 32768 is 1.0
+    pub fn new(taps: [i32; N]) -> Self {
+        Fir {
+            taps,
+            history: [0; N],
+            cursor: 0,
+        }
+    }
+
+    pub fn from_f32(taps: [f32; N]) -> Self {
+        let mut fixed = [0_i32; N];
+        for (fixed, &tap) in fixed.iter_mut().zip(taps.iter()) {
+            *fixed = to_fixed(tap, Q15_FRAC_BITS);
+        }
+        Self::new(fixed)
+    }
+
+    /// Average of the last `N` values
+    pub fn moving_average() -> Self {
+        Self::new([((1 << Q15_FRAC_BITS) + N as i32 / 2) / N as i32; N])
+    }
+}
+
+impl<const N: usize> Filter for Fir<N> {
+    fn process(&mut self, x: i32) -> i32 {
+        if N == 0 {
+            return 0;
+        }
+
+        self.history[self.cursor] = x;
+
+        // taps[0] is applied to the newest value
+        let mut acc = 0_i64;
+        let mut index = self.cursor;
+        for &tap in self.taps.iter() {
+            acc += tap as i64 * self.history[index] as i64;
+            index = if index == 0 { N - 1 } else { index - 1 };
+        }
+        self.cursor = (self.cursor + 1) % N;
+
+        saturate(round_shift(acc, Q15_FRAC_BITS))
+    }
+
+    fn reset(&mut self) {
+        self.history = [0; N];
+        self.cursor = 0;
+    }
+}
+
+/// DC blocker, `y[n] = x[n] - x[n-1] + p * y[n-1]`
+#[derive(Clone, Debug)]
+pub struct DcBlocker {
+    pole: i64,
+    x1: i32,
+    y1: i32,
+    error: i64,
+}
+
+impl DcBlocker {
+    /// `pole` in Q15, closer to 32768 is a lower cutoff
+    pub fn with_pole(pole: i32) -> Self {
+        DcBlocker {
+            pole: pole as i64,
+            x1: 0,
+            y1: 0,
+            error: 0,
+        }
+    }
+
+    /// Blocker with the -3 dB point around `cutoff_hz`
+    pub fn new(cutoff_hz: f32, sample_rate_hz: f32) -> Self {
+        let pole = 1_f32 - 2_f32 * core::f32::consts::PI * cutoff_hz / sample_rate_hz;
+        Self::with_pole(to_fixed(pole.max(0_f32), Q15_FRAC_BITS))
+    }
+}
+
+impl Filter for DcBlocker {
+    fn process(&mut self, x: i32) -> i32 {
+        let acc = ((x as i64 - self.x1 as i64) << Q15_FRAC_BITS)
+            + self.pole * self.y1 as i64
+            + self.error;
+
+        let y = acc >> Q15_FRAC_BITS;
+        self.error = acc - (y << Q15_FRAC_BITS);
+
+        self.x1 = x;
+        self.y1 = saturate(y);
+        self.y1
+    }
+
+    fn reset(&mut self) {
+        self.x1 = 0;
+        self.y1 = 0;
+        self.error = 0;
+    }
+}
+
+/// 0.5..4 Hz band-pass, Butterworth high-pass and low-pass sections
+pub type CardiacBand = Chain<Biquad, Biquad>;
+
+/// Cardiac band filter for samples taken every `sample_period_us`,
+/// `None` if the rate is too low to keep the band under Nyquist
+pub fn cardiac_band(sample_period_us: u32) -> Option<CardiacBand> {
+    if sample_period_us == 0 {
+        return None;
+    }
+    let sample_rate_hz = 1_000_000_f32 / sample_period_us as f32;
+    if CARDIAC_HIGH_HZ >= sample_rate_hz / 2_f32 {
+        return None;
+    }
+
+    let highpass = Biquad::butterworth_highpass(CARDIAC_LOW_HZ, sample_rate_hz);
+    let lowpass = Biquad::butterworth_lowpass(CARDIAC_HIGH_HZ, sample_rate_hz);
+    Some(highpass.then(lowpass))
+}
+
+fn to_fixed(value: f32, frac_bits: u32) -> i32 {
+    let scaled = value * (1_u32 << frac_bits) as f32;
+    // round half away from zero, there is no f32::round in core
+    if scaled >= 0_f32 { (scaled + 0.5_f32) as i32 } else { (scaled - 0.5_f32) as i32 }
+}
+
+fn round_shift(value: i64, bits: u32) -> i64 {
+    (value + (1 << (bits - 1))) >> bits
+}
+
+fn saturate(value: i64) -> i32 {
+    value.max(i32::MIN as i64).min(i32::MAX as i64) as i32
+}
+
+/// sin(w0 / 2)^2 for `w0 = 2 pi f / fs`
+fn sin_half_squared(f_hz: f32, sample_rate_hz: f32) -> f32 {
+    let (sin, _) = sin_cos(core::f32::consts::PI * f_hz / sample_rate_hz);
+    sin * sin
+}
+
+/// Sine and cosine by Taylor series, `x` is reduced to -pi..pi first
+fn sin_cos(x: f32) -> (f32, f32) {
+    use core::f32::consts::PI;
+
+    let mut x = x % (2_f32 * PI);
+    if x > PI {
+        x -= 2_f32 * PI;
+    } else if x < -PI {
+        x += 2_f32 * PI;
+    }
+
+    let x2 = x * x;
+    let mut sin = 0_f32;
+    let mut cos = 0_f32;
+    let mut sin_term = x;
+    let mut cos_term = 1_f32;
+    let mut n = 0_f32;
+    // |x| <= pi, terms are under f32 precision after 12 steps
+    for _ in 0..12 {
+        sin += sin_term;
+        cos += cos_term;
+        sin_term *= -x2 / ((n + 2_f32) * (n + 3_f32));
+        cos_term *= -x2 / ((n + 1_f32) * (n + 2_f32));
+        n += 2_f32;
+    }
+
+    (sin, cos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE_HZ: f32 = 80_f32;
+    const PERIOD_US: u32 = 12_500;
+
+    /// Peak output amplitude for a sine of `amplitude` at `freq_hz`, after settling
+    fn amplitude<F: Filter>(filter: &mut F, freq_hz: f32, amplitude: f32, offset: f32) -> i32 {
+        filter.reset();
+        let samples = (RATE_HZ * 20_f32) as usize;
+        let mut peak = 0;
+        for i in 0..samples {
+            let (sin, _) = sin_cos(2_f32 * core::f32::consts::PI * freq_hz * i as f32 / RATE_HZ);
+            let y = filter.process((offset + amplitude * sin) as i32);
+            if i > samples / 2 {
+                peak = peak.max(y.abs());
+            }
+        }
+        peak
+    }
+
+    #[test]
+    fn sin_cos_values() {
+        use core::f32::consts::PI;
+        let expected = [
+            (0_f32,         0_f32,          1_f32),
+            (PI / 2_f32,    1_f32,          0_f32),
+            (PI,            0_f32,          -1_f32),
+            (-PI / 6_f32,   -0.5_f32,       0.866_025_4_f32),
+            (7_f32,         0.656_986_6_f32, 0.753_902_3_f32),
+        ];
+        for &(x, sin, cos) in expected.iter() {
+            let (s, c) = sin_cos(x);
+            assert!((s - sin).abs() < 1e-5 && (c - cos).abs() < 1e-5, "{}: {} {}", x, s, c);
+        }
+    }
+
+    #[test]
+    fn cardiac_band_response() {
+        let mut band = cardiac_band(PERIOD_US).unwrap();
+
+        // pulse at 72 BPM on a large DC level
+        let pass = amplitude(&mut band, 1.2, 1_000_f32, 100_000_f32);
+        assert!(pass > 900 && pass < 1_100, "{}", pass);
+
+        // breathing and motion drift
+        assert!(amplitude(&mut band, 0.05, 1_000_f32, 100_000_f32) < 100);
+        // flicker aliased to 20 Hz
+        assert!(amplitude(&mut band, 20_f32, 1_000_f32, 100_000_f32) < 60);
+    }
+
+    #[test]
+    fn cardiac_band_needs_enough_rate() {
+        assert!(cardiac_band(12_500).is_some());
+        assert!(cardiac_band(100_000).is_some());
+        assert!(cardiac_band(200_000).is_none());
+        assert!(cardiac_band(0).is_none());
+    }
+
+    #[test]
+    fn biquad_settles_without_offset() {
+        let mut highpass = Biquad::butterworth_highpass(0.5, RATE_HZ);
+        let mut y = 0;
+        for _ in 0..2_000 {
+            y = highpass.process(200_000);
+        }
+        assert_eq!(y, 0);
+
+        let mut lowpass = Biquad::butterworth_lowpass(4_f32, RATE_HZ);
+        for _ in 0..2_000 {
+            y = lowpass.process(200_000);
+        }
+        assert_eq!(y, 200_000);
+    }
+
+    #[test]
+    fn bandpass_peak() {
+        let mut bandpass = Biquad::bandpass(1.2, RATE_HZ, 1_f32);
+        let peak = amplitude(&mut bandpass, 1.2, 1_000_f32, 0_f32);
+        assert!(peak > 950 && peak < 1_050, "{}", peak);
+        assert!(amplitude(&mut bandpass, 10_f32, 1_000_f32, 0_f32) < 150);
+    }
+
+    #[test]
+    fn notch_removes_center() {
+        let mut notch = Biquad::notch(20_f32, RATE_HZ, 5_f32);
+        assert!(amplitude(&mut notch, 20_f32, 1_000_f32, 0_f32) < 20);
+        assert!(amplitude(&mut notch, 1.2, 1_000_f32, 0_f32) > 980);
+    }
+
+    #[test]
+    fn dc_blocker() {
+        let mut blocker = DcBlocker::new(0.3, RATE_HZ);
+        assert!(amplitude(&mut blocker, 0_f32, 0_f32, 50_000_f32) == 0);
+        assert!(amplitude(&mut blocker, 2_f32, 1_000_f32, 50_000_f32) > 950);
+    }
+
+    #[test]
+    fn fir_moving_average() {
+        let mut average = Fir::<4>::moving_average();
+        let out: [i32; 6] = [
+            average.process(400),
+            average.process(400),
+            average.process(400),
+            average.process(400),
+            average.process(0),
+            average.process(0),
+        ];
+        assert_eq!(out, [100, 200, 300, 400, 300, 200]);
+    }
+
+    #[test]
+    fn fir_tap_order() {
+        let mut delay = Fir::from_f32([0_f32, 1_f32, 0_f32]);
+        assert_eq!(delay.process(7), 0);
+        assert_eq!(delay.process(8), 7);
+        assert_eq!(delay.process(9), 8);
+    }
+
+    #[test]
+    fn chain_and_sample_input() {
+        let mut pipeline = DcBlocker::new(0.3, RATE_HZ)
+            .then(Fir::<2>::moving_average());
+
+        let mut y = 0;
+        for _ in 0..2_000 {
+            y = pipeline.process_sample(RawSample::new(30_000, 10_000));
+        }
+        assert_eq!(y, 0);
+
+        pipeline.reset();
+        assert_eq!(pipeline.process(1_000), 500);
+    }
+}
@@ -0,0 +1,257 @@
+//! In-memory display, for drawing the screens without the watch.
+//!
+//! `FrameBuffer` is an `embedded_graphics` draw target like the ST7789, the
+//! pixels stay in memory and come out as a binary PPM image, which any image
+//! viewer opens. The unit tests compare the screens against golden images
+//! in `snapshots/`, refresh them with `make snapshots` after a deliberate
+//! change of the looks.
+
+use core::convert::Infallible;
+use embedded_graphics::{
+    prelude::*,
+    pixelcolor::{Rgb565, Rgb888},
+};
+
+use crate::plot::{LCD_W, LCD_H};
+
+/// Frame buffer the size of the PineTime display
+pub type LcdFrameBuffer = FrameBuffer<{ LCD_W as usize }, { LCD_H as usize }>;
+
+/// `W` x `H` pixels, black at the start.
+///
+/// Counts the writes like the display would get them, every `draw_pixel`
+/// is a window of its own. Drawing goes to the rows as they are in the RAM,
+/// reading gives them as they are shown, with the scrolling.
+pub struct FrameBuffer<const W: usize, const H: usize> {
+    rows: [[Rgb565; W]; H],
+    windows: usize,
+    pixels: usize,
+    /// Top, height and start row of the scrolling area
+    scroll: Option<(usize, usize, usize)>,
+}
+
+impl<const W: usize, const H: usize> FrameBuffer<W, H> {
+    pub fn new() -> Self {
+        FrameBuffer { rows: [[Rgb565::BLACK; W]; H], windows: 0, pixels: 0, scroll: None }
+    }
+
+    /// Color at `point`, `None` outside of the buffer
+    pub fn get_pixel(&self, point: Point) -> Option<Rgb565> {
+        if point.x < 0 || point.y < 0 {
+            return None;
+        }
+        self.rows.get(self.shown_row(point.y as usize))?.get(point.x as usize).copied()
+    }
+
+    /// Pixels of a row, left to right
+    pub fn row(&self, y: usize) -> &[Rgb565] {
+        &self.rows[self.shown_row(y)]
+    }
+
+    /// Address windows set since the last `reset_counts`
+    pub fn windows(&self) -> usize {
+        self.windows
+    }
+
+    /// Pixels written since the last `reset_counts`
+    pub fn pixels(&self) -> usize {
+        self.pixels
+    }
+
+    pub fn reset_counts(&mut self) {
+        self.windows = 0;
+        self.pixels = 0;
+    }
+
+    pub(crate) fn count_window(&mut self) {
+        self.windows += 1;
+    }
+
+    /// Writes a pixel, outside of the buffer it's dropped like the display does
+    pub(crate) fn set_pixel(&mut self, point: Point, color: Rgb565) {
+        self.pixels += 1;
+        if point.x >= 0 && point.y >= 0 && (point.x as usize) < W && (point.y as usize) < H {
+            self.rows[point.y as usize][point.x as usize] = color;
+        }
+    }
+
+    pub(crate) fn set_scroll(&mut self, top: usize, height: usize, start: usize) {
+        self.scroll = Some((top, height, start)).filter(|_| height > 0);
+    }
+
+    pub(crate) fn set_scroll_start_line(&mut self, start: usize) {
+        if let Some((_, _, line)) = self.scroll.as_mut() {
+            *line = start;
+        }
+    }
+
+    /// Row of the RAM shown at screen row `y`
+    fn shown_row(&self, y: usize) -> usize {
+        match self.scroll {
+            Some((top, height, start)) if (top..top + height).contains(&y) => {
+                top + (y - top + start.saturating_sub(top)) % height
+            }
+            _ => y,
+        }
+    }
+
+    /// The picture as a binary PPM (P6) file, 8 bits per channel
+    pub fn ppm_bytes(&self) -> impl Iterator<Item = u8> + '_ {
+        PpmHeader::new(W, H).chain(
+            (0..H)
+                .flat_map(move |y| self.row(y).iter())
+                .flat_map(|color| {
+                    let color = Rgb888::from(*color);
+                    [color.r(), color.g(), color.b()]
+                })
+        )
+    }
+}
+
+impl<const W: usize, const H: usize> Default for FrameBuffer<W, H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const W: usize, const H: usize> DrawTarget<Rgb565> for FrameBuffer<W, H> {
+    type Error = Infallible;
+
+    fn draw_pixel(&mut self, pixel: Pixel<Rgb565>) -> Result<(), Self::Error> {
+        let Pixel(point, color) = pixel;
+        self.count_window();
+        self.set_pixel(point, color);
+        Ok(())
+    }
+
+    fn size(&self) -> Size {
+        Size::new(W as u32, H as u32)
+    }
+}
+
+/// "P6\n<width> <height>\n255\n", without formatting into a buffer
+struct PpmHeader {
+    bytes: [u8; 32],
+    len: usize,
+    next: usize,
+}
+
+impl PpmHeader {
+    fn new(width: usize, height: usize) -> Self {
+        let mut header = PpmHeader { bytes: [0; 32], len: 0, next: 0 };
+        header.push(b"P6\n");
+        header.push_number(width);
+        header.push(b" ");
+        header.push_number(height);
+        header.push(b"\n255\n");
+        header
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        self.bytes[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+    }
+
+    fn push_number(&mut self, mut number: usize) {
+        let mut digits = [0_u8; 20];
+        let mut count = 0;
+        loop {
+            digits[count] = b'0' + (number % 10) as u8;
+            count += 1;
+            number /= 10;
+            if number == 0 {
+                break;
+            }
+        }
+        digits[..count].reverse();
+        self.push(&digits[..count]);
+    }
+}
+
+impl Iterator for PpmHeader {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        if self.next == self.len {
+            return None;
+        }
+        self.next += 1;
+        Some(self.bytes[self.next - 1])
+    }
+}
+
+/// Golden image comparison for the unit tests
+#[cfg(test)]
+pub(crate) mod snapshot {
+    extern crate std;
+
+    use std::{env, fs, format, path::PathBuf, vec::Vec};
+    use super::FrameBuffer;
+
+    fn path(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("snapshots").join(name)
+    }
+
+    /// Compares the buffer with `snapshots/<name>.ppm`. With `UPDATE_SNAPSHOTS`
+    /// set the file is written instead, a mismatch leaves `<name>.new.ppm`
+    /// next to it to look at.
+    pub fn assert_snapshot<const W: usize, const H: usize>(name: &str, buffer: &FrameBuffer<W, H>) {
+        let actual: Vec<u8> = buffer.ppm_bytes().collect();
+        let golden = path(&format!("{}.ppm", name));
+
+        if env::var_os("UPDATE_SNAPSHOTS").is_some() {
+            fs::create_dir_all(golden.parent().unwrap()).unwrap();
+            fs::write(&golden, &actual).unwrap();
+            return;
+        }
+
+        let expected = fs::read(&golden).unwrap_or_else(|_| {
+            panic!("no snapshot {}, run `make snapshots` to create it", golden.display())
+        });
+        if expected != actual {
+            let new = path(&format!("{}.new.ppm", name));
+            fs::write(&new, &actual).unwrap();
+            let differing = expected.chunks(3).zip(actual.chunks(3)).filter(|(e, a)| e != a).count();
+            panic!(
+                "{} differs from the snapshot in {} pixels, see {}",
+                name, differing, new.display()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_graphics::{
+        primitives::Rectangle,
+        style::PrimitiveStyle,
+    };
+
+    #[test]
+    fn draws_and_clips() {
+        let mut buffer = FrameBuffer::<8, 4>::new();
+        Rectangle::new(Point::new(6, 2), Point::new(10, 10))
+            .into_styled(PrimitiveStyle::with_fill(Rgb565::RED))
+            .draw(&mut buffer)
+            .unwrap();
+
+        assert_eq!(buffer.get_pixel(Point::new(7, 3)), Some(Rgb565::RED));
+        assert_eq!(buffer.get_pixel(Point::new(5, 3)), Some(Rgb565::BLACK));
+        assert_eq!(buffer.get_pixel(Point::new(8, 3)), None);
+        assert_eq!(buffer.row(2).iter().filter(|c| **c == Rgb565::RED).count(), 2);
+    }
+
+    #[test]
+    fn ppm_output() {
+        let mut buffer = FrameBuffer::<2, 1>::new();
+        buffer.draw_pixel(Pixel(Point::new(1, 0), Rgb565::WHITE)).unwrap();
+
+        let mut bytes = buffer.ppm_bytes();
+        assert!(bytes.by_ref().take(11).eq(b"P6\n2 1\n255\n".iter().copied()));
+        assert!(bytes.eq([0, 0, 0, 255, 255, 255].iter().copied()));
+
+        let lcd = LcdFrameBuffer::new();
+        assert_eq!(lcd.ppm_bytes().count(), 15 + 240 * 240 * 3);
+    }
+}
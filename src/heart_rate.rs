@@ -0,0 +1,434 @@
+//! Beat detection and heart rate (BPM) estimation from the PPG stream.
+//!
+//! `BeatDetector` finds pulse peaks in a detrended PPG signal, and
+//! `HeartRateEstimator` turns the intervals between them into beats per minute.
+//! All math is done in integers, time is kept in microseconds.
+
+use crate::hrs3300::RawSample;
+use crate::ppg_processor::PpgFilter;
+
+const US_PER_MINUTE: u32 = 60_000_000;
+
+/// Shortest accepted beat interval, 220 BPM
+pub const MIN_INTERVAL_US: u32 = US_PER_MINUTE / 220;
+/// Longest accepted beat interval, 30 BPM
+pub const MAX_INTERVAL_US: u32 = US_PER_MINUTE / 30;
+
+/// Time for the amplitude envelope to decay to zero without new peaks
+const ENVELOPE_DECAY_US: u64 = 2_000_000;
+
+/// Number of intervals used for the smoothed value
+const INTERVALS_LEN: usize = 8;
+/// Interval within this percentage of the median counts as consistent
+const CONSISTENCY_PERCENT: u32 = 20;
+
+/// Detected pulse peak
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Beat {
+    /// Time of the peak since the detector started
+    pub time_us: u64,
+    /// Time since the previous beat, `None` for the first beat
+    /// or after a gap longer than `MAX_INTERVAL_US`
+    pub interval_us: Option<u32>,
+}
+
+/// Peak detector with an adaptive threshold.
+///
+/// A peak is the maximum of a run of values above half of the recent
+/// amplitude, its position is refined between samples with a parabola
+/// through the maximum and its neighbours.
+pub struct BeatDetector {
+    sample_period_us: u32,
+    /// Expected capture time of the next sample
+    next_time_us: u64,
+    last_value: i64,
+    envelope: i64,
+
+    in_peak: bool,
+    peak_time_us: u64,
+    peak_value: i64,
+    peak_prev: i64,
+    peak_next: Option<i64>,
+
+    last_beat_us: Option<u64>,
+}
+
+impl BeatDetector {
+    pub fn new(sample_period_us: u32) -> Self {
+        BeatDetector {
+            sample_period_us,
+            next_time_us: 0,
+            last_value: 0,
+            envelope: 0,
+            in_peak: false,
+            peak_time_us: 0,
+            peak_value: 0,
+            peak_prev: 0,
+            peak_next: None,
+            last_beat_us: None,
+        }
+    }
+
+    pub fn get_sample_period_us(&self) -> u32 {
+        self.sample_period_us
+    }
+
+    /// Expected capture time of the next sample, one period after the last one
+    pub fn get_next_time_us(&self) -> u64 {
+        self.next_time_us
+    }
+
+    /// Takes the next detrended value, returns a beat once its peak is over
+    pub fn consume_value(&mut self, value: i64) -> Option<Beat> {
+        self.consume_value_at(value, self.next_time_us)
+    }
+
+    /// Same as `consume_value` for a value with its capture time, beats are
+    /// placed at the real time even if the samples aren't evenly spaced
+    pub fn consume_value_at(&mut self, value: i64, time_us: u64) -> Option<Beat> {
+        self.next_time_us = time_us + self.sample_period_us as u64;
+
+        self.update_envelope(value);
+        let threshold = self.envelope / 2;
+
+        let mut beat = None;
+        if value > 0 && value > threshold {
+            if !self.in_peak || value > self.peak_value {
+                self.in_peak = true;
+                self.peak_time_us = time_us;
+                self.peak_value = value;
+                self.peak_prev = self.last_value;
+                self.peak_next = None;
+            } else if self.peak_next.is_none() {
+                self.peak_next = Some(value);
+            }
+        } else if self.in_peak {
+            self.in_peak = false;
+            let peak_next = self.peak_next.unwrap_or(value);
+            beat = self.register_peak(peak_next);
+        }
+
+        self.last_value = value;
+        beat
+    }
+
+    /// Accounts for a sample period without a value, a peak in progress is dropped
+    pub fn skip_sample(&mut self) {
+        self.skip_sample_at(self.next_time_us);
+    }
+
+    /// Same as `skip_sample` for a sample captured at `time_us`
+    pub fn skip_sample_at(&mut self, time_us: u64) {
+        self.next_time_us = time_us + self.sample_period_us as u64;
+        self.in_peak = false;
+    }
+
+    fn update_envelope(&mut self, value: i64) {
+        let decay = self.envelope * self.sample_period_us as i64 / ENVELOPE_DECAY_US as i64;
+        self.envelope = (self.envelope - decay.max(1)).max(value.abs()).max(0);
+    }
+
+    fn register_peak(&mut self, peak_next: i64) -> Option<Beat> {
+        let period = self.sample_period_us as i64;
+
+        // vertex of the parabola through the 3 points, within half a sample
+        let denominator = 2 * (self.peak_prev - 2 * self.peak_value + peak_next);
+        let offset_us = if denominator < 0 {
+            ((self.peak_prev - peak_next) * period / denominator).max(-period / 2).min(period / 2)
+        } else {
+            0
+        };
+        let time_us = (self.peak_time_us as i64 + offset_us).max(0) as u64;
+
+        let interval_us = match self.last_beat_us {
+            Some(last) if time_us < last + MIN_INTERVAL_US as u64 => {
+                // too early, take it as a notch of the previous pulse
+                return None;
+            }
+            Some(last) if time_us - last <= MAX_INTERVAL_US as u64 => Some((time_us - last) as u32),
+            _ => None
+        };
+        self.last_beat_us = Some(time_us);
+
+        Some(Beat { time_us, interval_us })
+    }
+}
+
+/// Heart rate estimate
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HeartRate {
+    /// Rate from the last beat interval
+    pub instant_bpm: u16,
+    /// Rate from the median of the recent intervals
+    pub smoothed_bpm: u16,
+    /// 0..=100, share of recent intervals agreeing with the median,
+    /// lowered while beats are missing
+    pub confidence: u8,
+}
+
+/// Heart rate from `RawSample`s taken every `sample_period_us`
+pub struct HeartRateEstimator {
+    filter: PpgFilter,
+    detector: BeatDetector,
+    intervals: [u32; INTERVALS_LEN],
+    intervals_count: usize,
+    cursor: usize,
+    since_beat_us: u32,
+    /// Beat found by the last sample
+    beat: Option<Beat>,
+    current: Option<HeartRate>,
+}
+
+impl HeartRateEstimator {
+    pub fn new(sample_period_us: u32) -> Self {
+        HeartRateEstimator {
+            filter: PpgFilter::new(),
+            detector: BeatDetector::new(sample_period_us),
+            intervals: [0; INTERVALS_LEN],
+            intervals_count: 0,
+            cursor: 0,
+            since_beat_us: 0,
+            beat: None,
+            current: None,
+        }
+    }
+
+    /// Takes the next sample, returns the current estimate
+    pub fn consume_sample(&mut self, sample: RawSample) -> Option<HeartRate> {
+        self.consume_sample_at(sample, self.detector.get_next_time_us())
+    }
+
+    /// Accounts for a sample which can't be used, like one taken around
+    /// a sensor setting change, the detrend starts over after it
+    pub fn skip_sample(&mut self) {
+        self.filter.reset();
+        self.detector.skip_sample();
+        self.beat = None;
+    }
+
+    /// Same as `consume_sample` for a sample with its capture time,
+    /// intervals are measured between the real times
+    pub fn consume_sample_at(&mut self, sample: RawSample, time_us: u64) -> Option<HeartRate> {
+        match self.filter.consume_value(sample) {
+            Some(value) => self.consume_value_at(value, time_us),
+            None => {
+                // detrend is warming up, only the time goes on
+                self.detector.skip_sample_at(time_us);
+                self.beat = None;
+                self.current
+            }
+        }
+    }
+
+    /// Same as `consume_sample` for an already detrended value
+    pub fn consume_value(&mut self, value: i64) -> Option<HeartRate> {
+        self.consume_value_at(value, self.detector.get_next_time_us())
+    }
+
+    /// Same as `consume_value` with the capture time of the value
+    pub fn consume_value_at(&mut self, value: i64, time_us: u64) -> Option<HeartRate> {
+        // one period plus whatever the sample came later than expected
+        let elapsed_us = (time_us + self.detector.get_sample_period_us() as u64)
+            .saturating_sub(self.detector.get_next_time_us());
+        self.since_beat_us = self.since_beat_us.saturating_add(elapsed_us.min(u32::MAX as u64) as u32);
+
+        self.beat = self.detector.consume_value_at(value, time_us);
+        if let Some(beat) = self.beat {
+            self.since_beat_us = 0;
+            if let Some(interval_us) = beat.interval_us {
+                self.push_interval(interval_us);
+            }
+        }
+
+        if self.since_beat_us > 2 * MAX_INTERVAL_US {
+            // pulse is lost
+            self.reset();
+        }
+
+        self.current = self.estimate();
+        self.current
+    }
+
+    /// Last estimate
+    pub fn get(&self) -> Option<HeartRate> {
+        self.current
+    }
+
+    /// Beat found by the last consumed sample, if any
+    pub fn beat(&self) -> Option<Beat> {
+        self.beat
+    }
+
+    pub fn reset(&mut self) {
+        self.intervals_count = 0;
+        self.cursor = 0;
+        self.current = None;
+    }
+
+    fn push_interval(&mut self, interval_us: u32) {
+        self.intervals[self.cursor] = interval_us;
+        self.cursor = (self.cursor + 1) % INTERVALS_LEN;
+        self.intervals_count = (self.intervals_count + 1).min(INTERVALS_LEN);
+    }
+
+    fn last_interval(&self) -> u32 {
+        self.intervals[(self.cursor + INTERVALS_LEN - 1) % INTERVALS_LEN]
+    }
+
+    fn estimate(&self) -> Option<HeartRate> {
+        if self.intervals_count == 0 {
+            return None;
+        }
+
+        let mut sorted = [0_u32; INTERVALS_LEN];
+        let sorted = &mut sorted[..self.intervals_count];
+        sorted.copy_from_slice(&self.intervals[..self.intervals_count]);
+        sorted.sort_unstable();
+        let median = sorted[sorted.len() / 2];
+
+        let tolerance = median * CONSISTENCY_PERCENT / 100;
+        let consistent = sorted.iter()
+            .filter(|&&interval| interval + tolerance >= median && interval <= median + tolerance)
+            .count() as u32;
+        let mut confidence = consistent * 100 / INTERVALS_LEN as u32;
+        // a missed beat is fine, more means the pulse is fading
+        if self.since_beat_us > median * 2 {
+            confidence = confidence * median * 2 / self.since_beat_us;
+        }
+
+        Some(HeartRate {
+            instant_bpm: to_bpm(self.last_interval()),
+            smoothed_bpm: to_bpm(median),
+            confidence: confidence as u8,
+        })
+    }
+}
+
+/// Beats per minute for a beat interval, rounded
+pub fn to_bpm(interval_us: u32) -> u16 {
+    if interval_us == 0 {
+        return 0;
+    }
+    ((US_PER_MINUTE + interval_us / 2) / interval_us) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+
+    const PERIOD_US: u32 = 12_500;
+
+    /// PPG-like signal: DC level, pulse at `bpm` with a dicrotic notch, noise-free
+    fn ppg(index: u32, bpm: f32) -> RawSample {
+        let t = (index * PERIOD_US) as f32 / 1_000_000_f32;
+        let phase = 2_f32 * core::f32::consts::PI * bpm / 60_f32 * t;
+        let pulse = 1_000_f32 * phase.sin() + 250_f32 * (2_f32 * phase).sin();
+        RawSample::new((20_000_f32 + pulse) as u32, 16)
+    }
+
+    #[test]
+    fn bpm_conversion() {
+        assert_eq!(to_bpm(1_000_000), 60);
+        assert_eq!(to_bpm(800_000), 75);
+        assert_eq!(to_bpm(0), 0);
+    }
+
+    #[test]
+    fn detector_finds_peaks_between_samples() {
+        let mut detector = BeatDetector::new(PERIOD_US);
+
+        // 1 Hz pulse, sampled at 80 Hz
+        let mut beats = std::vec::Vec::new();
+        for i in 0..800 {
+            let t = (i * PERIOD_US) as f32 / 1_000_000_f32;
+            let value = 1_000_f32 * (2_f32 * core::f32::consts::PI * t).sin();
+            if let Some(beat) = detector.consume_value(value as i64) {
+                beats.push(beat);
+            }
+        }
+
+        assert_eq!(beats.len(), 10);
+        assert_eq!(beats[0].interval_us, None);
+        for beat in &beats[1..] {
+            let interval = beat.interval_us.unwrap() as i64;
+            assert!((interval - 1_000_000).abs() < 2_000, "interval {}", interval);
+        }
+        // peaks are at 0.25 s, 1.25 s, ...
+        assert!((beats[1].time_us as i64 - 1_250_000).abs() < 2_000);
+    }
+
+    #[test]
+    fn estimates_steady_rate() {
+        let mut estimator = HeartRateEstimator::new(PERIOD_US);
+
+        let mut last = None;
+        for i in 0..(15_000_000 / PERIOD_US) {
+            last = estimator.consume_sample(ppg(i, 72_f32));
+        }
+
+        let rate = last.unwrap();
+        assert!((rate.smoothed_bpm as i32 - 72).abs() <= 1, "{:?}", rate);
+        assert!((rate.instant_bpm as i32 - 72).abs() <= 2, "{:?}", rate);
+        assert_eq!(rate.confidence, 100);
+    }
+
+    #[test]
+    fn follows_rate_change() {
+        let mut estimator = HeartRateEstimator::new(PERIOD_US);
+
+        let mut last = None;
+        for i in 0..(15_000_000 / PERIOD_US) {
+            let bpm = if i < 800 { 60_f32 } else { 100_f32 };
+            last = estimator.consume_sample(ppg(i, bpm));
+        }
+
+        let rate = last.unwrap();
+        assert!((rate.smoothed_bpm as i32 - 100).abs() <= 2, "{:?}", rate);
+    }
+
+    #[test]
+    fn uses_capture_times() {
+        // sensor runs slower than configured and a few samples are lost,
+        // only the capture times tell
+        let mut estimator = HeartRateEstimator::new(10_000);
+
+        let mut last = None;
+        for i in (0..(15_000_000 / PERIOD_US)).filter(|i| i % 100 > 3) {
+            last = estimator.consume_sample_at(ppg(i, 72_f32), (i * PERIOD_US) as u64);
+        }
+
+        let rate = last.unwrap();
+        assert!((rate.smoothed_bpm as i32 - 72).abs() <= 1, "{:?}", rate);
+    }
+
+    #[test]
+    fn no_rate_without_pulse() {
+        let mut estimator = HeartRateEstimator::new(PERIOD_US);
+
+        for _ in 0..(10_000_000 / PERIOD_US) {
+            assert_eq!(estimator.consume_sample(RawSample::new(20_000, 16)), None);
+        }
+    }
+
+    #[test]
+    fn pulse_loss_drops_estimate() {
+        let mut estimator = HeartRateEstimator::new(PERIOD_US);
+
+        let samples = 10_000_000 / PERIOD_US;
+        for i in 0..samples {
+            estimator.consume_sample(ppg(i, 72_f32));
+        }
+        assert!(estimator.get().is_some());
+
+        let mut confidence = 100;
+        for _ in 0..samples {
+            if let Some(rate) = estimator.consume_sample(RawSample::new(20_000, 16)) {
+                assert!(rate.confidence <= confidence);
+                confidence = rate.confidence;
+            }
+        }
+        assert_eq!(estimator.get(), None);
+    }
+}
@@ -1,143 +1,241 @@
-use nrf52832_hal::{
-    twim,
-    pac,
+use embedded_hal::blocking::i2c::{
+    Write,
+    WriteRead,
 };
-use embedded_hal::blocking::i2c::WriteRead;
 
 pub const SENSOR_ADDR: u8 = 0x44;
-#[allow(unused)]
 pub const DEVICE_ID: u8 = 0x21;
-const SAMPLE_BLOCK_LEN: usize = 7;
+/// Bytes of one sample burst read from `RegAddrs::C1DATAM`
+pub const SAMPLE_BLOCK_LEN: usize = 7;
+/// Approximate light for one ALS count at 18 bits, full scale at 16 bits
+/// is about 65k lux. HGAIN is the gain of the HRS channel, not of the ALS.
+const MILLILUX_PER_COUNT: u64 = 250;
+/// Fraction bits of normalised values, enough to keep every count at gain x64
+pub const NORMALISED_FRACTION_BITS: u32 = 6;
 
-pub type SensorError = twim::Error;
-type SensorTwim = twim::Twim<pac::TWIM0>;
+mod registers;
+#[cfg(test)]
+pub mod emulator;
+pub use registers::{
+    Register,
+    Registers,
+    Enable,
+    PDriver,
+    Res,
+    HGain,
+};
 
-pub struct Sensor {
-    i2c: SensorTwim,
-    adc_wait_time_us: u32,
-    resolution_mask: u32
+/// Errors reported by the HRS3300 driver
+#[allow(unused)]
+#[derive(Debug)]
+pub enum SensorError<E> {
+    /// I2C transaction failed, the underlying bus error is kept
+    Bus(E),
+    /// Device at `SENSOR_ADDR` answered with an unexpected ID
+    WrongDeviceId(u8),
+    /// Register holds a value which doesn't match any known setting
+    InvalidConfig { reg: RegAddrs, value: u8 },
+    /// One of the channels reached the full scale of the configured resolution
+    Saturated(RawSample),
 }
 
-impl Sensor {
-    pub fn new(i2c: SensorTwim) -> Self {
+/// HRS3300 driver, generic over any blocking I2C bus.
+///
+/// The error of the bus, `I2C::Error` of its `Write` and `WriteRead`, is
+/// wrapped into `SensorError::Bus` by every method that talks to the sensor.
+pub struct Sensor<I2C> {
+    i2c: I2C,
+    /// Last values written to or read from the configuration registers
+    regs: Registers,
+    /// A configuration register was written since the last sample
+    config_changed: bool,
+}
+
+impl<I2C, E> Sensor<I2C>
+where
+    I2C: Write<Error = E> + WriteRead<Error = E>
+{
+    pub fn new(i2c: I2C) -> Self {
         Sensor {
             i2c,
-            adc_wait_time_us: 1250,
-            resolution_mask: (1 << 15) - 1
+            regs: Registers::default(),
+            config_changed: true,
         }
     }
 
-    pub fn init(&mut self) -> Result<(), SensorError> {
-        // recommended values
+    /// Destroys the driver and gives back the I2C bus
+    #[allow(unused)]
+    pub fn release(self) -> I2C {
+        self.i2c
+    }
 
-        // ENABLE = 0x68 => 
-        //   wait time = 12.5ms, .110_....
-        //   led current = 40mA, ...._1... bit 1
-        self.set_adc_wait_time(ADCWaitTime::Ms12_5)?;
+    /// Checks that the device at `SENSOR_ADDR` is an HRS3300 and reads back
+    /// its configuration registers.
+    ///
+    /// A missing sensor is reported as `SensorError::Bus`, a different chip
+    /// as `SensorError::WrongDeviceId`. Registers are only expected to hold
+    /// the power-on values before `init`, see `ProbeReport::is_default`.
+    pub fn probe(&mut self) -> Result<ProbeReport, SensorError<E>> {
+        let id = self.verify_id()?;
 
-        // PDRIVER = 0x68 => 
-        //   led current = 40mA, .1.._.... bit 0
-        //   osc = active,       ..1._....
-        self.set_led_current(LedCurrent::Ma40)?;
+        Ok(ProbeReport {
+            id,
+            enable: self.reg_read(RegAddrs::ENABLE)?,
+            pdriver: self.reg_read(RegAddrs::PDRIVER)?,
+            res: self.reg_read(RegAddrs::RES)?,
+            hgain: self.reg_read(RegAddrs::HGAIN)?,
+        })
+    }
 
-        // RES = 0x66 => 
-        //   reolution = 14 bits, ...._0110
-        self.set_resolution(BitsResolution::_14)?;
+    pub fn init(&mut self) -> Result<(), SensorError<E>> {
+        self.verify_id()?;
 
-        // HGAIN = 0x10 => 
-        //   gain = x64,          ...1_00..
-        self.set_gain(Gain::X64)?;
+        // the chip keeps its registers over MCU reset, start from what it has
+        let current = self.read_config()?;
 
-        Ok(())
-    }
-    
-    #[allow(unused)]
-    pub fn get_id(&mut self) -> Result<u8, SensorError> {
-        self.reg_read(RegAddrs::ENABLE)
+        // recommended values, HRS and oscillator states are left as they are
+        self.configure(SensorConfig {
+            hrs_active: current.hrs_active,
+            osc_active: current.osc_active,
+            ..SensorConfig::default()
+        })
     }
 
-    pub fn set_hrs_active(&mut self, active: bool) -> Result<(), SensorError> {
-        let mut reg_data = self.reg_read(RegAddrs::ENABLE)?; 
+    pub fn get_id(&mut self) -> Result<u8, SensorError<E>> {
+        self.reg_read(RegAddrs::ID)
+    }
 
-        let value: u8 = active as u8;
-        // bit 7 on/off 
-        Self::write_bits(value, &mut reg_data, 7, 1);
+    fn verify_id(&mut self) -> Result<u8, SensorError<E>> {
+        let id = self.get_id()?;
+        if id != DEVICE_ID {
+            return Err(SensorError::WrongDeviceId(id));
+        }
 
-        self.reg_write(RegAddrs::ENABLE, reg_data)
+        Ok(id)
     }
 
-    pub fn set_adc_wait_time(&mut self, wt: ADCWaitTime) -> Result<(), SensorError> {
-        let mut reg_data = self.reg_read(RegAddrs::ENABLE)?;  
+    /// Writes the whole configuration, one write per register
+    pub fn configure(&mut self, config: SensorConfig) -> Result<(), SensorError<E>> {
+        let (pdrive_hi, pdrive_lo) = config.led_current.split();
 
-        self.adc_wait_time_us = wt.get_us();
+        let mut regs = self.regs;
+        regs.enable.hrs_active = config.hrs_active;
+        regs.enable.wait_time = config.wait_time;
+        regs.enable.pdrive_hi = pdrive_hi;
+        regs.pdriver.pdrive_lo = pdrive_lo;
+        regs.pdriver.osc_active = config.osc_active;
+        regs.res.resolution = config.resolution;
+        regs.hgain.gain = config.gain;
 
-        let value = wt as u8;
-        // write to bits 4:6 of ENABLE
-        Self::write_bits(value, &mut reg_data, 4, 3);
+        self.write_reg(regs.enable)?;
+        self.write_reg(regs.pdriver)?;
+        self.write_reg(regs.res)?;
+        self.write_reg(regs.hgain)
+    }
 
-        self.reg_write(RegAddrs::ENABLE, reg_data)
+    /// Configuration as the driver believes it is, no bus access
+    pub fn get_config(&self) -> SensorConfig {
+        SensorConfig::from_registers(&self.regs)
     }
 
-    pub fn get_adc_wait_time_us(&self) -> u32 {
-        self.adc_wait_time_us
+    /// Reads the configuration back from the chip and refreshes the shadow copy
+    pub fn read_config(&mut self) -> Result<SensorConfig, SensorError<E>> {
+        self.read_reg::<Enable>()?;
+        self.read_reg::<PDriver>()?;
+        self.read_reg::<Res>()?;
+        self.read_reg::<HGain>()?;
+
+        Ok(self.get_config())
     }
 
-    pub fn set_led_current(&mut self, lc: LedCurrent) -> Result<(), SensorError> {
-        let value = lc as u8;
+    /// Reads the configuration back and compares it with the shadow copy
+    #[allow(unused)]
+    pub fn verify_config(&mut self) -> Result<SensorConfig, SensorError<E>> {
+        let written = self.regs;
+        self.read_config()?;
 
-        let mut enable_data = self.reg_read( RegAddrs::ENABLE)?; 
-        // extract bit 1 from lc and move it to 3
-        let to_enable: u8 = value & 0b10;
-        // write to bit 3 of ENABLE
-        Self::write_bits(to_enable, &mut enable_data, 3, 1);
-        self.reg_write(RegAddrs::ENABLE, enable_data)?;
+        let compared = [
+            (RegAddrs::ENABLE,  written.enable.bits(),  self.regs.enable.bits()),
+            (RegAddrs::PDRIVER, written.pdriver.bits(), self.regs.pdriver.bits()),
+            (RegAddrs::RES,     written.res.bits(),     self.regs.res.bits()),
+            (RegAddrs::HGAIN,   written.hgain.bits(),   self.regs.hgain.bits()),
+        ];
+        for &(reg, expected, value) in compared.iter() {
+            if expected != value {
+                return Err(SensorError::InvalidConfig { reg, value });
+            }
+        }
 
-        let mut pdriver_data = self.reg_read( RegAddrs::PDRIVER)?; 
-        // extract bit 0 from lc and move it to 6
-        let to_pdriver: u8 = (value & 0b01) << 6;
-        // write to bit 6 of PDRIVER
-        Self::write_bits(to_pdriver, &mut pdriver_data, 6, 1);        
-        self.reg_write( RegAddrs::PDRIVER, pdriver_data)
+        Ok(self.get_config())
     }
 
-    pub fn set_osc_active(&mut self, active: bool) -> Result<(), SensorError> {
-        let mut reg_data = self.reg_read(RegAddrs::PDRIVER)?;  
+    pub fn set_hrs_active(&mut self, active: bool) -> Result<(), SensorError<E>> {
+        let mut enable = self.regs.enable;
+        enable.hrs_active = active;
+        self.write_reg(enable)
+    }
 
-        let value: u8 = active as u8;
-        // // write to bit 5 of PDRIVER
-        Self::write_bits(value, &mut reg_data, 5, 1);
+    #[allow(unused)]
+    pub fn set_adc_wait_time(&mut self, wt: ADCWaitTime) -> Result<(), SensorError<E>> {
+        let mut enable = self.regs.enable;
+        enable.wait_time = wt;
+        self.write_reg(enable)
+    }
 
-        self.reg_write(RegAddrs::PDRIVER, reg_data)
+    pub fn get_adc_wait_time_us(&self) -> u32 {
+        self.regs.enable.wait_time.get_us()
     }
 
-    pub fn set_gain(&mut self, gain: Gain) -> Result<(), SensorError> {
-        let mut reg_data = self.reg_read(RegAddrs::HGAIN)?;  
+    #[allow(unused)]
+    pub fn set_led_current(&mut self, lc: LedCurrent) -> Result<(), SensorError<E>> {
+        // PDRIVE[1] is in ENABLE, PDRIVE[0] in PDRIVER
+        let (pdrive_hi, pdrive_lo) = lc.split();
 
-        let value = gain as u8;
-        // write to bit 4:2 of HGAIN
-        Self::write_bits(value, &mut reg_data, 2, 3);
+        let mut enable = self.regs.enable;
+        enable.pdrive_hi = pdrive_hi;
+        self.write_reg(enable)?;
 
-        self.reg_write(RegAddrs::HGAIN, reg_data)
+        let mut pdriver = self.regs.pdriver;
+        pdriver.pdrive_lo = pdrive_lo;
+        self.write_reg(pdriver)
     }
 
-    pub fn set_resolution(&mut self, res: BitsResolution) -> Result<(), SensorError> {
-        let mut reg_data = self.reg_read(RegAddrs::RES)?;  
-
-        let value = res as u8;
-        // write to bits 3:0 of RES
-        Self::write_bits(value, &mut reg_data, 0, 4);
+    pub fn set_osc_active(&mut self, active: bool) -> Result<(), SensorError<E>> {
+        let mut pdriver = self.regs.pdriver;
+        pdriver.osc_active = active;
+        self.write_reg(pdriver)
+    }
 
-        self.resolution_mask = res.get_mask();
+    #[allow(unused)]
+    pub fn set_gain(&mut self, gain: Gain) -> Result<(), SensorError<E>> {
+        let mut hgain = self.regs.hgain;
+        hgain.gain = gain;
+        self.write_reg(hgain)
+    }
 
-        self.reg_write(RegAddrs::RES, reg_data)
+    #[allow(unused)]
+    pub fn set_resolution(&mut self, res: BitsResolution) -> Result<(), SensorError<E>> {
+        let mut res_reg = self.regs.res;
+        res_reg.resolution = res;
+        self.write_reg(res_reg)
     }
 
+    fn resolution_mask(&self) -> u32 {
+        self.regs.res.resolution.get_mask()
+    }
 
-    #[allow(non_snake_case)]
-    pub fn read_raw_sample(&mut self) -> Result<RawSample, SensorError> {
+    pub fn read_raw_sample(&mut self) -> Result<RawSample, SensorError<E>> {
         let mut sample_buff = [0u8; SAMPLE_BLOCK_LEN];
         self.read_registers(RegAddrs::C1DATAM, &mut sample_buff)?;
 
+        Ok(self.decode_sample(&sample_buff))
+    }
+
+    /// Sample from a burst read of `SAMPLE_BLOCK_LEN` bytes starting at
+    /// `RegAddrs::C1DATAM`, for reads done without the driver (e.g. by DMA).
+    /// The block has to be taken with the current configuration.
+    #[allow(non_snake_case)]
+    pub fn decode_sample(&mut self, sample_buff: &[u8; SAMPLE_BLOCK_LEN]) -> RawSample {
         // The order of returned data is:
         // 0: C1DATAM 0x08
         // 1: C0DATAM 0x09
@@ -160,7 +258,7 @@ impl Sensor {
         Self::extract_channel_bits(ch1_0x08, 0, &mut als, 3, 8);        
         // 6:0 0x0D C1DATA[17:11] C1DATAH
         Self::extract_channel_bits(ch1_0x0D, 0, &mut als, 11, 7);
-        als &= self.resolution_mask;
+        als &= self.resolution_mask();
 
         let mut hrs: HrsValue = 0_u32;
         // 3:0 0x0F C0DATA[3:0] C0DATAL
@@ -171,48 +269,87 @@ impl Sensor {
         Self::extract_channel_bits(ch0_0x09, 0, &mut hrs, 8, 8);
         // 5:4 0x0F C0DATA[17:16] C0DATAL
         Self::extract_channel_bits(ch0_0x0F, 4, &mut hrs, 16, 2);
-        hrs &= self.resolution_mask;
+        hrs &= self.resolution_mask();
 
-        Ok(RawSample::new(hrs, als))
+        let mut sample = RawSample::with_setting(hrs, als, self.regs.hgain.gain, self.regs.res.resolution);
+        sample.quality.config_changed = self.config_changed;
+        self.config_changed = false;
+
+        sample
     }
 
+    /// Same as `read_raw_sample`, but a sample with any channel at the full
+    /// scale of the current resolution is returned as `SensorError::Saturated`
+    pub fn read_sample(&mut self) -> Result<RawSample, SensorError<E>> {
+        let sample = self.read_raw_sample()?;
 
-    fn reg_write(&mut self, sensor_reg_addr: RegAddrs, value: u8) -> Result<(), SensorError> {
-        let tr = [sensor_reg_addr as u8, value];
+        if sample.quality.is_saturated() {
+            return Err(SensorError::Saturated(sample));
+        }
 
-        self.i2c.write(SENSOR_ADDR, &tr).unwrap();
+        Ok(sample)
+    }
 
-        Ok(())
+    /// Switches to the ambient light measurement, see `SensorConfig::ambient_light`
+    pub fn start_ambient_light(&mut self) -> Result<(), SensorError<E>> {
+        self.configure(SensorConfig::ambient_light())
     }
 
-    fn reg_read(&mut self, sensor_reg_addr: RegAddrs) -> Result<u8, SensorError> {
+    /// Ambient light in millilux from the ALS channel, converted with
+    /// the current resolution.
+    ///
+    /// A reading at full scale is returned as `SensorError::Saturated`,
+    /// that much light is out of the range of the channel.
+    pub fn read_ambient_light(&mut self) -> Result<u32, SensorError<E>> {
+        let sample = self.read_raw_sample()?;
+
+        if sample.quality.als_saturated {
+            return Err(SensorError::Saturated(sample));
+        }
+
+        Ok(als_to_millilux(sample.als, sample.resolution))
+    }
+
+    fn reg_write(&mut self, sensor_reg_addr: RegAddrs, value: u8) -> Result<(), SensorError<E>> {
+        let tr = [sensor_reg_addr as u8, value];
+
+        self.i2c.write(SENSOR_ADDR, &tr).map_err(SensorError::Bus)
+    }
+
+    fn reg_read(&mut self, sensor_reg_addr: RegAddrs) -> Result<u8, SensorError<E>> {
         let mut buff = [0_u8; 1];
         let tr = [sensor_reg_addr as u8];
 
-        self.i2c.write_read(SENSOR_ADDR, &tr, &mut buff).unwrap();
+        self.i2c.write_read(SENSOR_ADDR, &tr, &mut buff).map_err(SensorError::Bus)?;
 
         Ok(buff[0])
     }
 
-    fn read_registers(&mut self, start_register: RegAddrs, buffer_to: &mut [u8]) -> Result<(), SensorError> {
-        let start_reg_bytes = [start_register as u8];
-        self.i2c.write_read(SENSOR_ADDR, &start_reg_bytes, buffer_to).unwrap();
+    /// Writes the register and updates the shadow copy once the write succeeded
+    fn write_reg<R: Register>(&mut self, reg: R) -> Result<(), SensorError<E>> {
+        self.reg_write(R::ADDR, reg.bits())?;
+        *R::slot(&mut self.regs) = reg;
+        self.config_changed = true;
 
         Ok(())
     }
 
+    /// Reads the register into the shadow copy
+    fn read_reg<R: Register>(&mut self) -> Result<R, SensorError<E>> {
+        let value = self.reg_read(R::ADDR)?;
+        let reg = R::from_bits(value)
+            .ok_or(SensorError::InvalidConfig { reg: R::ADDR, value })?;
+        *R::slot(&mut self.regs) = reg;
 
-    fn write_bits(mut from_right_aligned: u8, to: &mut u8, start_to: usize, count: usize) {
-        // assert!(start_to + count <= 8);
-    
-        let mask: u8 = (1 << count) - 1;   // create mask with ones in places 0..n, where n = 'start_from'
-        from_right_aligned &= mask;        // extract bits from 'from'
-        *to &= !(mask << start_to);        // clear bits in 'to'
-    
-        from_right_aligned <<= start_to;   // shift prepared 'from' to align it with 'to'
-        *to |= from_right_aligned;         // put bits in 'to'
+        Ok(reg)
     }
-    
+
+    fn read_registers(&mut self, start_register: RegAddrs, buffer_to: &mut [u8]) -> Result<(), SensorError<E>> {
+        let start_reg_bytes = [start_register as u8];
+        self.i2c.write_read(SENSOR_ADDR, &start_reg_bytes, buffer_to).map_err(SensorError::Bus)
+    }
+
+
     fn extract_channel_bits(from: u8, start_from: usize, to: &mut u32, start_to: usize, count: usize) {
         // assert!(start_from + count <= 8);
         // assert!(start_to + count <= 32);
@@ -230,25 +367,206 @@ impl Sensor {
     }
 }
 
+/// Register values read by `Sensor::probe`
+#[allow(unused)]
+#[derive(Clone, Copy, Debug)]
+pub struct ProbeReport {
+    pub id: u8,
+    pub enable: u8,
+    pub pdriver: u8,
+    pub res: u8,
+    pub hgain: u8,
+}
+impl ProbeReport {
+    /// Returns true if all configuration registers hold their power-on values
+    pub fn is_default(&self) -> bool {
+        self.mismatches().next().is_none()
+    }
+
+    /// Registers which differ from their power-on values,
+    /// as `(register, read value, default value)`
+    pub fn mismatches(&self) -> impl Iterator<Item = (RegAddrs, u8, u8)> {
+        let read = [
+            (RegAddrs::ENABLE,  self.enable),
+            (RegAddrs::PDRIVER, self.pdriver),
+            (RegAddrs::RES,     self.res),
+            (RegAddrs::HGAIN,   self.hgain),
+        ];
+
+        IntoIterator::into_iter(read).filter_map(|(reg, value)| {
+            let default = reg.default_value()?;
+            if value != default { Some((reg, value, default)) } else { None }
+        })
+    }
+}
+
+/// Complete sensor configuration, applied at once by `Sensor::configure`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SensorConfig {
+    pub hrs_active: bool,
+    pub osc_active: bool,
+    pub wait_time: ADCWaitTime,
+    pub led_current: LedCurrent,
+    pub resolution: BitsResolution,
+    pub gain: Gain,
+}
+impl SensorConfig {
+    fn from_registers(regs: &Registers) -> Self {
+        SensorConfig {
+            hrs_active: regs.enable.hrs_active,
+            osc_active: regs.pdriver.osc_active,
+            wait_time: regs.enable.wait_time,
+            led_current: LedCurrent::join(regs.enable.pdrive_hi, regs.pdriver.pdrive_lo),
+            resolution: regs.res.resolution,
+            gain: regs.hgain.gain,
+        }
+    }
+}
+impl SensorConfig {
+    /// Ambient light measurement: C1 is converted while the LED is off, the
+    /// LED still flashes for C0 so it's kept at the lowest current, and the
+    /// conversions are slowed down as light doesn't change that fast.
+    ///
+    /// The LED can't be switched off on its own: PDRIVE has no zero current,
+    /// HEN and PON stop the ALS conversions along with it.
+    pub fn ambient_light() -> Self {
+        SensorConfig {
+            hrs_active: true,
+            osc_active: true,
+            wait_time: ADCWaitTime::Ms100,
+            led_current: LedCurrent::Ma12_5,
+            resolution: BitsResolution::_16,
+            gain: Gain::X1,
+        }
+    }
+}
+impl Default for SensorConfig {
+    /// Recommended values, HRS is off
+    fn default() -> Self {
+        SensorConfig {
+            hrs_active: false,
+            osc_active: true,
+            wait_time: ADCWaitTime::Ms12_5,
+            led_current: LedCurrent::Ma40,
+            resolution: BitsResolution::_14,
+            gain: Gain::X64,
+        }
+    }
+}
+
+/// Converts counts taken with `gain` and `resolution` into the normalised
+/// unit: 18-bit counts at gain x1 with `NORMALISED_FRACTION_BITS` fraction
+/// bits, so values taken with any setting compare. Full scale at 18 bits and
+/// gain x1 is `((1 << 18) - 1) << 6`, the result always fits into 24 bits.
+pub fn normalise(counts: u32, gain: Gain, resolution: BitsResolution) -> u32 {
+    // readings are the top bits of an 18-bit conversion
+    let counts = counts & resolution.get_mask();
+    let counts_18 = counts << (18 - (resolution as u8 as u32 + 8));
+    // every gain divides 1 << 6 exactly
+    counts_18 * ((1 << NORMALISED_FRACTION_BITS) / gain.get_factor())
+}
+
+/// Converts an ALS reading taken with `resolution` into millilux
+pub fn als_to_millilux(als: AlsValue, resolution: BitsResolution) -> u32 {
+    let millilux = (normalise(als, Gain::X1, resolution) as u64 * MILLILUX_PER_COUNT) >> NORMALISED_FRACTION_BITS;
+    millilux.min(u32::MAX as u64) as u32
+}
+
 pub type HrsValue = u32;
 pub type AlsValue = u32;
-#[derive(Copy, Clone)]
+
+/// Conditions a sample was captured in
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct SampleQuality {
+    /// HRS channel is at the full scale of the resolution
+    pub hrs_saturated: bool,
+    /// ALS channel is at the full scale of the resolution
+    pub als_saturated: bool,
+    /// HRS channel reads zero, nothing reaches the photodiode or HRS is off
+    pub hrs_zero: bool,
+    /// Ambient light is stronger than the reflected LED light
+    pub ambient_dominant: bool,
+    /// Configuration was written since the previous sample,
+    /// the level may step
+    pub config_changed: bool,
+}
+impl SampleQuality {
+    pub fn is_saturated(&self) -> bool {
+        self.hrs_saturated || self.als_saturated
+    }
+
+    /// No flag is set
+    pub fn is_good(&self) -> bool {
+        *self == SampleQuality::default()
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
 pub struct RawSample {
     pub hrs: HrsValue,
-    pub als: AlsValue
+    pub als: AlsValue,
+    pub quality: SampleQuality,
+    /// Gain the sample was captured with
+    pub gain: Gain,
+    /// Resolution the sample was captured with
+    pub resolution: BitsResolution,
 }
 impl RawSample {
+    /// Sample with the recommended gain and resolution and no flags,
+    /// for values which don't come from the sensor
     pub fn new(hrs: HrsValue, als: AlsValue) -> Self {
-        RawSample { hrs, als }
+        let config = SensorConfig::default();
+        RawSample {
+            hrs,
+            als,
+            quality: SampleQuality::default(),
+            gain: config.gain,
+            resolution: config.resolution,
+        }
+    }
+
+    /// Sample captured with `gain` and `resolution`, the level flags are set
+    pub fn with_setting(hrs: HrsValue, als: AlsValue, gain: Gain, resolution: BitsResolution) -> Self {
+        let mask = resolution.get_mask();
+        let mut sample = RawSample {
+            hrs,
+            als,
+            quality: SampleQuality::default(),
+            gain,
+            resolution,
+        };
+        sample.quality.hrs_saturated = hrs >= mask;
+        sample.quality.als_saturated = als >= mask;
+        sample.quality.hrs_zero = hrs == 0;
+        sample.quality.ambient_dominant = als > sample.get_sum();
+        sample
     }
 
     pub fn get_sum(&self) -> u32 {
         self.hrs.saturating_sub(self.als)
     }
+
+    /// HRS channel in the unit of `normalise`
+    pub fn get_normalised_hrs(&self) -> u32 {
+        normalise(self.hrs, self.gain, self.resolution)
+    }
+
+    /// ALS channel in the unit of `normalise`, the gain doesn't apply to it
+    pub fn get_normalised_als(&self) -> u32 {
+        normalise(self.als, Gain::X1, self.resolution)
+    }
+
+    /// `get_sum` in the unit of `normalise`, the channels are normalised
+    /// before the ALS is taken off as only the HRS is amplified
+    pub fn get_normalised_sum(&self) -> u32 {
+        self.get_normalised_hrs().saturating_sub(self.get_normalised_als())
+    }
 }
 
 
-#[allow(unused)]
+// names follow the datasheet
+#[allow(unused, clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum RegAddrs {
     ID = 0x00,      // R/W Device ID 0x21
     ENABLE = 0x01,  // R/W Enable HRS 0x68
@@ -262,9 +580,23 @@ pub enum RegAddrs {
     RES = 0x16,     // R/W ALS and HRS resolution 0x66
     HGAIN = 0x17    // R/W HRS gain 0x10
 }
+impl RegAddrs {
+    /// Power-on value of the register, `None` for data registers
+    pub fn default_value(&self) -> Option<u8> {
+        match *self {
+            RegAddrs::ID      => Some(DEVICE_ID),
+            RegAddrs::ENABLE  => Some(0x68),
+            RegAddrs::PDRIVER => Some(0x68),
+            RegAddrs::RES     => Some(0x66),
+            RegAddrs::HGAIN   => Some(0x10),
+            _ => None
+        }
+    }
+}
 
 // bits 4:6, wait time between each conversion 
 #[allow(unused)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ADCWaitTime {
     Ms800 = 0,
     Ms400,
@@ -276,6 +608,21 @@ pub enum ADCWaitTime {
     Ms0
 }
 impl ADCWaitTime {
+    pub fn from_bits(bits: u8) -> Option<Self> {
+        use ADCWaitTime::*;
+        Some(match bits {
+            0 => Ms800,
+            1 => Ms400,
+            2 => Ms200,
+            3 => Ms100,
+            4 => Ms75,
+            5 => Ms50,
+            6 => Ms12_5,
+            7 => Ms0,
+            _ => return None
+        })
+    }
+
     pub fn get_us(&self) -> u32 {
         match *self {
             ADCWaitTime::Ms800  => 800_000,
@@ -284,7 +631,7 @@ impl ADCWaitTime {
             ADCWaitTime::Ms100  => 100_000,
             ADCWaitTime::Ms75   => 75_000,
             ADCWaitTime::Ms50   => 50_000,
-            ADCWaitTime::Ms12_5 => 1_250,
+            ADCWaitTime::Ms12_5 => 12_500,
             ADCWaitTime::Ms0    => 0,
         }
     }
@@ -292,15 +639,49 @@ impl ADCWaitTime {
 
 // led current 2-bit value, bit 1 of 0:1
 #[allow(unused)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum LedCurrent {
     Ma12_5 = 0,
     Ma20,
     Ma30,
     Ma40
 }
+impl LedCurrent {
+    /// Splits the value into (PDRIVE[1], PDRIVE[0])
+    pub fn split(&self) -> (bool, bool) {
+        let value = *self as u8;
+        (value & 0b10 != 0, value & 0b01 != 0)
+    }
+
+    pub fn join(pdrive_hi: bool, pdrive_lo: bool) -> Self {
+        match (pdrive_hi, pdrive_lo) {
+            (false, false) => LedCurrent::Ma12_5,
+            (false, true)  => LedCurrent::Ma20,
+            (true, false)  => LedCurrent::Ma30,
+            (true, true)   => LedCurrent::Ma40,
+        }
+    }
+
+    pub fn from_bits(bits: u8) -> Option<Self> {
+        if bits > 0b11 {
+            return None;
+        }
+        Some(Self::join(bits & 0b10 != 0, bits & 0b01 != 0))
+    }
+
+    /// Current in tenths of a milliampere
+    pub fn get_ma_x10(&self) -> u32 {
+        match *self {
+            LedCurrent::Ma12_5 => 125,
+            LedCurrent::Ma20   => 200,
+            LedCurrent::Ma30   => 300,
+            LedCurrent::Ma40   => 400,
+        }
+    }
+}
 
 // ADC resolution
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum BitsResolution {
     _8 = 0,
     _9,
@@ -315,6 +696,24 @@ pub enum BitsResolution {
     _18
 }
 impl BitsResolution {
+    pub fn from_bits(bits: u8) -> Option<Self> {
+        use BitsResolution::*;
+        Some(match bits {
+            0  => _8,
+            1  => _9,
+            2  => _10,
+            3  => _11,
+            4  => _12,
+            5  => _13,
+            6  => _14,
+            7  => _15,
+            8  => _16,
+            9  => _17,
+            10 => _18,
+            _  => return None
+        })
+    }
+
     pub fn get_mask(&self) -> u32 {
         (1 << (*self as u8 + 8)) - 1
     }
@@ -322,6 +721,7 @@ impl BitsResolution {
 
 // gain
 #[allow(unused)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Gain {
     X1 = 0, 
     X2,
@@ -329,3 +729,368 @@ pub enum Gain {
     X8,
     X64
 }
+impl Gain {
+    pub fn from_bits(bits: u8) -> Option<Self> {
+        use Gain::*;
+        Some(match bits {
+            0 => X1,
+            1 => X2,
+            2 => X4,
+            3 => X8,
+            4 => X64,
+            _ => return None
+        })
+    }
+
+    pub fn get_factor(&self) -> u32 {
+        match *self {
+            Gain::X1  => 1,
+            Gain::X2  => 2,
+            Gain::X4  => 4,
+            Gain::X8  => 8,
+            Gain::X64 => 64,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::emulator::{
+        Emulator,
+        EmulatorError,
+    };
+
+    #[test]
+    fn probe_fresh_chip() {
+        let chip = Emulator::new();
+        let mut sensor = Sensor::new(&chip);
+
+        let report = sensor.probe().unwrap();
+        assert_eq!(report.id, DEVICE_ID);
+        assert!(report.is_default());
+    }
+
+    #[test]
+    fn probe_reports_changed_registers() {
+        let chip = Emulator::new();
+        chip.set_reg(RegAddrs::HGAIN, 0x00);
+        let mut sensor = Sensor::new(&chip);
+
+        let report = sensor.probe().unwrap();
+        assert!(!report.is_default());
+
+        let mut mismatches = report.mismatches();
+        assert_eq!(mismatches.next(), Some((RegAddrs::HGAIN, 0x00, 0x10)));
+        assert_eq!(mismatches.next(), None);
+    }
+
+    #[test]
+    fn probe_wrong_device() {
+        let chip = Emulator::with_id(0x42);
+        let mut sensor = Sensor::new(&chip);
+
+        match sensor.probe() {
+            Err(SensorError::WrongDeviceId(0x42)) => {}
+            other => panic!("unexpected probe result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn bus_error_is_propagated() {
+        let chip = Emulator::new();
+        let mut sensor = Sensor::new(&chip);
+
+        chip.fail_next(1);
+        match sensor.set_hrs_active(true) {
+            Err(SensorError::Bus(EmulatorError::BusFault)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        // failed write doesn't reach the shadow copy
+        assert!(!sensor.get_config().hrs_active);
+
+        sensor.set_hrs_active(true).unwrap();
+        assert!(sensor.get_config().hrs_active);
+    }
+
+    #[test]
+    fn init_writes_recommended_values() {
+        let chip = Emulator::new();
+        let mut sensor = Sensor::new(&chip);
+
+        sensor.init().unwrap();
+
+        assert_eq!(chip.reg(RegAddrs::ENABLE), 0x68);
+        assert_eq!(chip.reg(RegAddrs::PDRIVER), 0x68);
+        assert_eq!(chip.reg(RegAddrs::RES), 0x66);
+        assert_eq!(chip.reg(RegAddrs::HGAIN), 0x10);
+        assert_eq!(sensor.read_config().unwrap(), SensorConfig::default());
+    }
+
+    #[test]
+    fn setters_do_single_write() {
+        let chip = Emulator::new();
+        let mut sensor = Sensor::new(&chip);
+        sensor.init().unwrap();
+
+        let writes = chip.writes();
+        sensor.set_gain(Gain::X8).unwrap();
+        assert_eq!(chip.writes(), writes + 1);
+        assert_eq!(chip.reg(RegAddrs::HGAIN), 0x0C);
+    }
+
+    #[test]
+    fn configure_round_trip() {
+        let chip = Emulator::new();
+        let mut sensor = Sensor::new(&chip);
+
+        let config = SensorConfig {
+            hrs_active: true,
+            osc_active: true,
+            wait_time: ADCWaitTime::Ms50,
+            led_current: LedCurrent::Ma20,
+            resolution: BitsResolution::_18,
+            gain: Gain::X2,
+        };
+        sensor.configure(config).unwrap();
+
+        assert_eq!(sensor.verify_config().unwrap(), config);
+        // reserved bits are kept as they were
+        assert_eq!(chip.reg(RegAddrs::PDRIVER) & 0b1001_1111, 0x08);
+        assert_eq!(chip.reg(RegAddrs::RES) & 0xF0, 0x60);
+    }
+
+    #[test]
+    fn verify_config_detects_changed_register() {
+        let chip = Emulator::new();
+        let mut sensor = Sensor::new(&chip);
+        sensor.init().unwrap();
+
+        chip.set_reg(RegAddrs::RES, 0x6F);
+        match sensor.verify_config() {
+            Err(SensorError::InvalidConfig { reg: RegAddrs::RES, value: 0x6F }) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn led_current_is_split_between_registers() {
+        let chip = Emulator::new();
+        let mut sensor = Sensor::new(&chip);
+        sensor.init().unwrap();
+
+        sensor.set_led_current(LedCurrent::Ma30).unwrap();
+        assert_eq!(chip.reg(RegAddrs::ENABLE) & (1 << 3), 1 << 3);
+        assert_eq!(chip.reg(RegAddrs::PDRIVER) & (1 << 6), 0);
+
+        sensor.set_led_current(LedCurrent::Ma20).unwrap();
+        assert_eq!(chip.reg(RegAddrs::ENABLE) & (1 << 3), 0);
+        assert_eq!(chip.reg(RegAddrs::PDRIVER) & (1 << 6), 1 << 6);
+
+        assert_eq!(sensor.read_config().unwrap().led_current, LedCurrent::Ma20);
+    }
+
+    #[test]
+    fn wait_time_in_microseconds() {
+        let expected = [800_000, 400_000, 200_000, 100_000, 75_000, 50_000, 12_500, 0];
+        for (bits, &us) in expected.iter().enumerate() {
+            assert_eq!(ADCWaitTime::from_bits(bits as u8).unwrap().get_us(), us);
+        }
+    }
+
+    #[test]
+    fn raw_sample_decoding() {
+        let chip = Emulator::new();
+        let mut sensor = Sensor::new(&chip);
+        sensor.set_resolution(BitsResolution::_18).unwrap();
+
+        // every bit of both channels ends up in a different register
+        for &(c0, c1) in [(0x2_A5C3, 0x1_5A3C), (0x3_FFFF, 0), (0, 0x3_FFFF), (0x1_0001, 0x2_0004)].iter() {
+            chip.set_channels(c0, c1);
+            let sample = sensor.read_raw_sample().unwrap();
+            assert_eq!((sample.hrs, sample.als), (c0, c1));
+        }
+    }
+
+    #[test]
+    fn raw_sample_is_masked_by_resolution() {
+        let chip = Emulator::new();
+        let mut sensor = Sensor::new(&chip);
+        sensor.set_resolution(BitsResolution::_14).unwrap();
+
+        chip.set_channels(0x3_FFFF, 0x2_1234);
+        let sample = sensor.read_raw_sample().unwrap();
+        assert_eq!(sample.hrs, (1 << 14) - 1);
+        assert_eq!(sample.als, 0x1234);
+    }
+
+    #[test]
+    fn conversion_follows_configuration() {
+        let chip = Emulator::new();
+        let mut sensor = Sensor::new(&chip);
+        chip.set_light(1_000, 100);
+
+        // nothing is converted while HRS is off
+        sensor.init().unwrap();
+        let sample = sensor.read_raw_sample().unwrap();
+        assert_eq!((sample.hrs, sample.als), (0, 0));
+
+        sensor.configure(SensorConfig {
+            hrs_active: true,
+            resolution: BitsResolution::_18,
+            gain: Gain::X2,
+            led_current: LedCurrent::Ma20,
+            ..SensorConfig::default()
+        }).unwrap();
+        let sample = sensor.read_raw_sample().unwrap();
+        assert_eq!(sample.hrs, (1_000 / 2 + 100) * 2);
+        assert_eq!(sample.als, 100);
+        assert_eq!(sample.get_sum(), 1_100);
+    }
+
+    #[test]
+    fn saturated_sample() {
+        let chip = Emulator::new();
+        let mut sensor = Sensor::new(&chip);
+        sensor.init().unwrap();
+        sensor.set_hrs_active(true).unwrap();
+
+        chip.set_light(1 << 17, 0);
+        match sensor.read_sample() {
+            Err(SensorError::Saturated(sample)) => assert_eq!(sample.hrs, (1 << 14) - 1),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        chip.set_light(1 << 10, 0);
+        assert!(sensor.read_sample().is_ok());
+    }
+
+    #[test]
+    fn normalised_values() {
+        let full_scale = ((1 << 18) - 1) << NORMALISED_FRACTION_BITS;
+        assert_eq!(normalise((1 << 18) - 1, Gain::X1, BitsResolution::_18), full_scale);
+        assert_eq!(normalise(1, Gain::X64, BitsResolution::_18), 1);
+        assert_eq!(normalise(1, Gain::X1, BitsResolution::_8), 1 << 16);
+        // bits above the resolution are not part of the value
+        assert_eq!(normalise(0x1_0001, Gain::X8, BitsResolution::_16), 1 << 5);
+
+        // same light through different settings
+        let chip = Emulator::new();
+        let mut sensor = Sensor::new(&chip);
+        sensor.init().unwrap();
+        sensor.set_hrs_active(true).unwrap();
+        chip.set_light(1_200, 300);
+
+        let reference = sensor.read_raw_sample().unwrap();
+        for &(gain, resolution) in [(Gain::X8, BitsResolution::_18), (Gain::X2, BitsResolution::_16), (Gain::X1, BitsResolution::_12)].iter() {
+            sensor.set_gain(gain).unwrap();
+            sensor.set_resolution(resolution).unwrap();
+            let sample = sensor.read_raw_sample().unwrap();
+
+            assert_ne!(sample.hrs, reference.hrs);
+            // only bits lost at the lower resolution make a difference
+            let lsb = normalise(1, gain, resolution);
+            assert!(reference.get_normalised_hrs().abs_diff(sample.get_normalised_hrs()) < lsb);
+            // the ALS isn't amplified, the coarser of both readings counts
+            let als_lsb = normalise(1, Gain::X1, resolution).max(normalise(1, Gain::X1, reference.resolution));
+            assert!(reference.get_normalised_sum().abs_diff(sample.get_normalised_sum()) < lsb + als_lsb);
+        }
+    }
+
+    #[test]
+    fn ambient_light_conversion() {
+        // 16-bit count is 4 counts at 18 bits
+        assert_eq!(als_to_millilux(1, BitsResolution::_16), 1_000);
+        assert_eq!(als_to_millilux(100, BitsResolution::_18), 25_000);
+        assert_eq!(als_to_millilux(100, BitsResolution::_14), 400_000);
+    }
+
+    #[test]
+    fn ambient_light_mode() {
+        let chip = Emulator::new();
+        let mut sensor = Sensor::new(&chip);
+        sensor.init().unwrap();
+        sensor.start_ambient_light().unwrap();
+        assert_eq!(sensor.get_config(), SensorConfig::ambient_light());
+
+        // 400 lux, LED light doesn't reach the ALS channel
+        chip.set_light(50_000, 1_600);
+        assert_eq!(sensor.read_ambient_light().unwrap(), 400_000);
+
+        // the LED still flashes for C0, it adds nothing to C1
+        let lit = sensor.read_raw_sample().unwrap();
+        chip.set_light(0, 1_600);
+        let dark = sensor.read_raw_sample().unwrap();
+        assert_eq!(lit.als, dark.als);
+        assert!(lit.hrs > dark.hrs);
+        chip.set_light(50_000, 1_600);
+
+        sensor.set_gain(Gain::X8).unwrap();
+        assert_eq!(sensor.read_ambient_light().unwrap(), 400_000);
+
+        chip.set_light(0, 1 << 18);
+        match sensor.read_ambient_light() {
+            Err(SensorError::Saturated(sample)) => assert_eq!(sample.als, (1 << 16) - 1),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn sample_quality_flags() {
+        let chip = Emulator::new();
+        let mut sensor = Sensor::new(&chip);
+        sensor.init().unwrap();
+        sensor.set_hrs_active(true).unwrap();
+
+        chip.set_light(1_000, 100);
+        let sample = sensor.read_raw_sample().unwrap();
+        assert!(sample.quality.config_changed);
+        assert_eq!((sample.gain, sample.resolution), (Gain::X64, BitsResolution::_14));
+
+        let sample = sensor.read_raw_sample().unwrap();
+        assert!(sample.quality.is_good(), "{:?}", sample.quality);
+
+        sensor.set_gain(Gain::X1).unwrap();
+        let sample = sensor.read_raw_sample().unwrap();
+        assert!(sample.quality.config_changed);
+        assert_eq!(sample.gain, Gain::X1);
+
+        chip.set_light(100, 1_000);
+        let quality = sensor.read_raw_sample().unwrap().quality;
+        assert_eq!(quality, SampleQuality { ambient_dominant: true, ..SampleQuality::default() });
+
+        chip.set_light(0, 1 << 18);
+        let quality = sensor.read_raw_sample().unwrap().quality;
+        assert!(quality.hrs_saturated && quality.als_saturated);
+
+        sensor.set_hrs_active(false).unwrap();
+        chip.set_channels(0, 0);
+        assert!(sensor.read_raw_sample().unwrap().quality.hrs_zero);
+    }
+
+    #[test]
+    fn decode_sample_block() {
+        let chip = Emulator::new();
+        let mut sensor = Sensor::new(&chip);
+        sensor.set_resolution(BitsResolution::_18).unwrap();
+
+        // C1DATAM, C0DATAM, C0DATAH, PDRIVER, C1DATAH, C1DATAL, C0DATAL
+        let block = [0x1E, 0xAB, 0x0C, 0x00, 0x3E, 0x03, 0x2D];
+        let sample = sensor.decode_sample(&block);
+        assert_eq!((sample.hrs, sample.als), (0x2_ABCD, 0x1_F0F3));
+        assert!(sample.quality.config_changed);
+        assert!(!sensor.decode_sample(&block).quality.config_changed);
+
+        // bits above the resolution are dropped
+        sensor.set_resolution(BitsResolution::_16).unwrap();
+        let sample = sensor.decode_sample(&block);
+        assert_eq!((sample.hrs, sample.als), (0xABCD, 0xF0F3));
+    }
+
+    #[test]
+    fn raw_sample_sum_does_not_underflow() {
+        assert_eq!(RawSample::new(10, 3).get_sum(), 7);
+        assert_eq!(RawSample::new(3, 10).get_sum(), 0);
+    }
+}
@@ -0,0 +1,241 @@
+//! Register-accurate software model of the HRS3300.
+//!
+//! The blocking I2C traits are implemented for `&Emulator`, so a test can
+//! hand the "bus" to `Sensor` and still drive the chip from outside:
+//!
+//! ```ignore
+//! let chip = Emulator::new();
+//! let mut sensor = Sensor::new(&chip);
+//! chip.set_light(20_000, 1_000);
+//! ```
+
+use core::cell::RefCell;
+use embedded_hal::blocking::i2c::{
+    Write,
+    WriteRead,
+};
+use super::{
+    SENSOR_ADDR,
+    RegAddrs,
+    Register,
+    Enable,
+    PDriver,
+    Res,
+    HGain,
+    LedCurrent,
+};
+
+const REG_COUNT: usize = 0x18;
+const FULL_SCALE_BITS: u32 = 18;
+
+#[derive(Debug, PartialEq)]
+pub enum EmulatorError {
+    /// Nobody answered at the address
+    AddressNack,
+    /// Failure injected with `Emulator::fail_next`
+    BusFault,
+}
+
+pub struct Emulator {
+    state: RefCell<State>,
+}
+
+struct State {
+    regs: [u8; REG_COUNT],
+    /// Light reaching the photodiode from the LED at 40 mA, 18-bit counts at gain x1
+    reflected: u32,
+    /// Ambient light, 18-bit counts at gain x1, seen by both channels,
+    /// HGAIN amplifies the HRS channel only
+    ambient: u32,
+    /// Number of transactions to fail with `EmulatorError::BusFault`
+    failures: u32,
+    /// Number of register writes done over the bus
+    writes: u32,
+}
+
+impl Emulator {
+    /// Chip right after power-on
+    pub fn new() -> Self {
+        let mut regs = [0_u8; REG_COUNT];
+        for &reg in [RegAddrs::ID, RegAddrs::ENABLE, RegAddrs::PDRIVER, RegAddrs::RES, RegAddrs::HGAIN].iter() {
+            regs[reg as usize] = reg.default_value().unwrap_or(0);
+        }
+
+        Emulator {
+            state: RefCell::new(State {
+                regs,
+                reflected: 0,
+                ambient: 0,
+                failures: 0,
+                writes: 0,
+            })
+        }
+    }
+
+    /// Chip which answers with another device ID
+    pub fn with_id(id: u8) -> Self {
+        let emulator = Self::new();
+        emulator.state.borrow_mut().regs[RegAddrs::ID as usize] = id;
+        emulator
+    }
+
+    /// Sets the light seen by the photodiode and runs a conversion
+    pub fn set_light(&self, reflected: u32, ambient: u32) {
+        let mut state = self.state.borrow_mut();
+        state.reflected = reflected;
+        state.ambient = ambient;
+        state.convert();
+    }
+
+    /// Puts raw channel values into the data registers, as if converted
+    pub fn set_channels(&self, c0: u32, c1: u32) {
+        self.state.borrow_mut().store_channels(c0, c1);
+    }
+
+    /// Fails the next `count` transactions
+    pub fn fail_next(&self, count: u32) {
+        self.state.borrow_mut().failures = count;
+    }
+
+    pub fn reg(&self, reg: RegAddrs) -> u8 {
+        self.state.borrow().regs[reg as usize]
+    }
+
+    pub fn set_reg(&self, reg: RegAddrs, value: u8) {
+        self.state.borrow_mut().regs[reg as usize] = value;
+    }
+
+    /// Number of register writes done over the bus
+    pub fn writes(&self) -> u32 {
+        self.state.borrow().writes
+    }
+
+    fn transaction(&self, address: u8) -> Result<(), EmulatorError> {
+        if address != SENSOR_ADDR {
+            return Err(EmulatorError::AddressNack);
+        }
+
+        let mut state = self.state.borrow_mut();
+        if state.failures > 0 {
+            state.failures -= 1;
+            return Err(EmulatorError::BusFault);
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Emulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl State {
+    fn read(&self, addr: u8) -> u8 {
+        self.regs.get(addr as usize).copied().unwrap_or(0)
+    }
+
+    fn write(&mut self, addr: u8, value: u8) {
+        self.writes += 1;
+
+        let writable = [RegAddrs::ENABLE, RegAddrs::PDRIVER, RegAddrs::RES, RegAddrs::HGAIN];
+        if writable.iter().any(|&reg| reg as u8 == addr) {
+            self.regs[addr as usize] = value;
+            self.convert();
+        }
+    }
+
+    /// Takes a sample if HRS and the oscillator are on, with the current
+    /// LED current, gain and resolution
+    fn convert(&mut self) {
+        let enable = Enable::from_bits(self.regs[RegAddrs::ENABLE as usize]);
+        let pdriver = PDriver::from_bits(self.regs[RegAddrs::PDRIVER as usize]);
+        let res = Res::from_bits(self.regs[RegAddrs::RES as usize]);
+        let hgain = HGain::from_bits(self.regs[RegAddrs::HGAIN as usize]);
+
+        let (enable, pdriver, res, hgain) = match (enable, pdriver, res, hgain) {
+            (Some(enable), Some(pdriver), Some(res), Some(hgain)) => (enable, pdriver, res, hgain),
+            // chip doesn't convert with a broken configuration
+            _ => return
+        };
+        if !enable.hrs_active || !pdriver.osc_active {
+            return;
+        }
+
+        let led_ma_x10 = LedCurrent::join(enable.pdrive_hi, pdriver.pdrive_lo).get_ma_x10() as u64;
+        let gain = hgain.gain.get_factor() as u64;
+
+        let led = self.reflected as u64 * led_ma_x10 / 400;
+        let c0 = (led + self.ambient as u64) * gain;
+        let c1 = self.ambient as u64;
+
+        // conversion is done at 18 bits and cut down to the resolution,
+        // anything above full scale reads as full scale
+        let full_scale = (1_u64 << FULL_SCALE_BITS) - 1;
+        let shift = FULL_SCALE_BITS - (res.resolution as u32 + 8);
+        let c0 = (c0.min(full_scale) >> shift) as u32;
+        let c1 = (c1.min(full_scale) >> shift) as u32;
+
+        self.store_channels(c0, c1);
+    }
+
+    fn store_channels(&mut self, c0: u32, c1: u32) {
+        let regs = &mut self.regs;
+
+        // C1DATA[10:3], C1DATA[17:11], C1DATA[2:0]
+        regs[RegAddrs::C1DATAM as usize] = (c1 >> 3) as u8;
+        regs[RegAddrs::C1DATAH as usize] = ((c1 >> 11) & 0x7F) as u8;
+        regs[RegAddrs::C1DATAL as usize] = (c1 & 0x07) as u8;
+
+        // C0DATA[15:8], C0DATA[7:4], C0DATA[17:16] and C0DATA[3:0]
+        regs[RegAddrs::C0DATAM as usize] = (c0 >> 8) as u8;
+        regs[RegAddrs::C0DATAH as usize] = ((c0 >> 4) & 0x0F) as u8;
+        regs[RegAddrs::C0DATAL as usize] = (((c0 >> 16) & 0x03) << 4) as u8 | (c0 & 0x0F) as u8;
+    }
+}
+
+/// Address auto increment, 0x0B is not a register and is skipped,
+/// so a 7-byte burst from C1DATAM ends at C0DATAL
+fn next_addr(addr: u8) -> u8 {
+    match addr.wrapping_add(1) {
+        0x0B => 0x0C,
+        next => next
+    }
+}
+
+impl<'a> Write for &'a Emulator {
+    type Error = EmulatorError;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.transaction(address)?;
+
+        if let Some((&first, values)) = bytes.split_first() {
+            let mut state = self.state.borrow_mut();
+            let mut addr = first;
+            for &value in values {
+                state.write(addr, value);
+                addr = next_addr(addr);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> WriteRead for &'a Emulator {
+    type Error = EmulatorError;
+
+    fn write_read(&mut self, address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), Self::Error> {
+        self.transaction(address)?;
+
+        let state = self.state.borrow();
+        let mut addr = bytes.first().copied().unwrap_or(0);
+        for value in buffer.iter_mut() {
+            *value = state.read(addr);
+            addr = next_addr(addr);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,180 @@
+//! Typed view of the HRS3300 configuration registers.
+//!
+//! Every register keeps the bits it doesn't know about in `reserved`,
+//! so a value read from the chip is written back unchanged.
+
+use super::{
+    RegAddrs,
+    ADCWaitTime,
+    BitsResolution,
+    Gain,
+};
+
+/// A configuration register which can be decoded from and encoded to its raw value
+pub trait Register: Copy {
+    const ADDR: RegAddrs;
+
+    /// Decodes the raw value, `None` if a field holds an unknown setting
+    fn from_bits(bits: u8) -> Option<Self>;
+
+    fn bits(&self) -> u8;
+
+    /// Place of this register in the shadow copy
+    fn slot(regs: &mut Registers) -> &mut Self;
+}
+
+/// ENABLE 0x01
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Enable {
+    /// bit 7, HRS on/off
+    pub hrs_active: bool,
+    /// bits 6:4, wait time between conversions
+    pub wait_time: ADCWaitTime,
+    /// bit 3, PDRIVE[1] of LED current
+    pub pdrive_hi: bool,
+    reserved: u8,
+}
+impl Enable {
+    const RESERVED_MASK: u8 = 0b0000_0111;
+}
+impl Register for Enable {
+    const ADDR: RegAddrs = RegAddrs::ENABLE;
+
+    fn from_bits(bits: u8) -> Option<Self> {
+        Some(Enable {
+            hrs_active: bits & (1 << 7) != 0,
+            wait_time: ADCWaitTime::from_bits((bits >> 4) & 0b111)?,
+            pdrive_hi: bits & (1 << 3) != 0,
+            reserved: bits & Self::RESERVED_MASK,
+        })
+    }
+
+    fn bits(&self) -> u8 {
+        (self.hrs_active as u8) << 7
+            | (self.wait_time as u8) << 4
+            | (self.pdrive_hi as u8) << 3
+            | self.reserved & Self::RESERVED_MASK
+    }
+
+    fn slot(regs: &mut Registers) -> &mut Self {
+        &mut regs.enable
+    }
+}
+
+/// PDRIVER 0x0C
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PDriver {
+    /// bit 6, PDRIVE[0] of LED current
+    pub pdrive_lo: bool,
+    /// bit 5, PON, oscillator on/off
+    pub osc_active: bool,
+    reserved: u8,
+}
+impl PDriver {
+    const RESERVED_MASK: u8 = 0b1001_1111;
+}
+impl Register for PDriver {
+    const ADDR: RegAddrs = RegAddrs::PDRIVER;
+
+    fn from_bits(bits: u8) -> Option<Self> {
+        Some(PDriver {
+            pdrive_lo: bits & (1 << 6) != 0,
+            osc_active: bits & (1 << 5) != 0,
+            reserved: bits & Self::RESERVED_MASK,
+        })
+    }
+
+    fn bits(&self) -> u8 {
+        (self.pdrive_lo as u8) << 6
+            | (self.osc_active as u8) << 5
+            | self.reserved & Self::RESERVED_MASK
+    }
+
+    fn slot(regs: &mut Registers) -> &mut Self {
+        &mut regs.pdriver
+    }
+}
+
+/// RES 0x16
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Res {
+    /// bits 3:0, ADC resolution
+    pub resolution: BitsResolution,
+    reserved: u8,
+}
+impl Res {
+    const RESERVED_MASK: u8 = 0b1111_0000;
+}
+impl Register for Res {
+    const ADDR: RegAddrs = RegAddrs::RES;
+
+    fn from_bits(bits: u8) -> Option<Self> {
+        Some(Res {
+            resolution: BitsResolution::from_bits(bits & 0b1111)?,
+            reserved: bits & Self::RESERVED_MASK,
+        })
+    }
+
+    fn bits(&self) -> u8 {
+        self.resolution as u8 | self.reserved & Self::RESERVED_MASK
+    }
+
+    fn slot(regs: &mut Registers) -> &mut Self {
+        &mut regs.res
+    }
+}
+
+/// HGAIN 0x17
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HGain {
+    /// bits 4:2, HRS gain
+    pub gain: Gain,
+    reserved: u8,
+}
+impl HGain {
+    const RESERVED_MASK: u8 = 0b1110_0011;
+}
+impl Register for HGain {
+    const ADDR: RegAddrs = RegAddrs::HGAIN;
+
+    fn from_bits(bits: u8) -> Option<Self> {
+        Some(HGain {
+            gain: Gain::from_bits((bits >> 2) & 0b111)?,
+            reserved: bits & Self::RESERVED_MASK,
+        })
+    }
+
+    fn bits(&self) -> u8 {
+        (self.gain as u8) << 2 | self.reserved & Self::RESERVED_MASK
+    }
+
+    fn slot(regs: &mut Registers) -> &mut Self {
+        &mut regs.hgain
+    }
+}
+
+/// Copy of all configuration registers
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Registers {
+    pub enable: Enable,
+    pub pdriver: PDriver,
+    pub res: Res,
+    pub hgain: HGain,
+}
+impl Default for Registers {
+    /// Power-on values
+    fn default() -> Self {
+        Registers {
+            enable: power_on(),
+            pdriver: power_on(),
+            res: power_on(),
+            hgain: power_on(),
+        }
+    }
+}
+
+fn power_on<R: Register>() -> R {
+    R::ADDR.default_value()
+        .and_then(R::from_bits)
+        .expect("power-on value of a configuration register")
+}
@@ -0,0 +1,431 @@
+//! Heart rate variability from inter-beat intervals.
+//!
+//! Beats come from `heart_rate::HeartRateEstimator`, intervals which don't fit the
+//! current rhythm (ectopic beats, missed or doubled peaks) are rejected, and
+//! time-domain metrics are computed over the last `N` normal (NN) intervals.
+//! Everything is integer, intervals are in microseconds.
+
+use core::fmt;
+
+use crate::heart_rate::{
+    Beat,
+    MIN_INTERVAL_US,
+    MAX_INTERVAL_US,
+};
+
+/// Interval further than this percentage from the reference is ectopic
+const ECTOPIC_PERCENT: u32 = 20;
+/// Number of last NN intervals averaged for the reference
+const REFERENCE_LEN: usize = 4;
+/// Consistent rejected intervals in a row taken as a new rhythm
+const NEW_RHYTHM_RUN: u8 = 3;
+/// Successive difference counted by pNN50
+const NN50_US: u32 = 50_000;
+
+/// What happened to an interval given to `HrvAnalyzer`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IntervalKind {
+    /// Accepted as a normal interval
+    Normal,
+    /// Too far from the current rhythm
+    Ectopic,
+    /// Outside of `MIN_INTERVAL_US..=MAX_INTERVAL_US`
+    OutOfRange,
+}
+
+/// Time-domain HRV metrics
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HrvMetrics {
+    /// Number of NN intervals used
+    pub count: u16,
+    pub mean_nn_us: u32,
+    /// Standard deviation of NN intervals
+    pub sdnn_us: u32,
+    /// Root mean square of successive differences
+    pub rmssd_us: u32,
+    /// Share of successive differences over 50 ms, 0..=1000
+    pub pnn50_permille: u16,
+}
+
+impl fmt::Display for HrvMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "NN {} ms, SDNN {} ms, RMSSD {} ms, pNN50 {}.{}% ({} intervals)",
+            (self.mean_nn_us + 500) / 1_000,
+            (self.sdnn_us + 500) / 1_000,
+            (self.rmssd_us + 500) / 1_000,
+            self.pnn50_permille / 10,
+            self.pnn50_permille % 10,
+            self.count,
+        )
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct NnInterval {
+    interval_us: u32,
+    /// Previous stored interval is right before this one,
+    /// no rejected interval or detection gap in between
+    adjacent: bool,
+}
+
+/// Ectopic beat rejection and HRV metrics over the last `N` NN intervals
+pub struct HrvAnalyzer<const N: usize> {
+    intervals: [NnInterval; N],
+    cursor: usize,
+    len: usize,
+    /// Next accepted interval follows the last stored one
+    adjacent: bool,
+    rejected_run: u8,
+    last_rejected_us: u32,
+    rejected_total: u32,
+}
+
+impl<const N: usize> HrvAnalyzer<N> {
+    pub fn new() -> Self {
+        HrvAnalyzer {
+            intervals: [NnInterval::default(); N],
+            cursor: 0,
+            len: 0,
+            adjacent: false,
+            rejected_run: 0,
+            last_rejected_us: 0,
+            rejected_total: 0,
+        }
+    }
+
+    /// Takes a detected beat, a beat without interval breaks the sequence
+    pub fn consume_beat(&mut self, beat: Beat) -> Option<IntervalKind> {
+        match beat.interval_us {
+            Some(interval_us) => Some(self.push_interval(interval_us)),
+            None => {
+                self.adjacent = false;
+                None
+            }
+        }
+    }
+
+    /// Classifies the interval and keeps it if it's normal
+    pub fn push_interval(&mut self, interval_us: u32) -> IntervalKind {
+        if !(MIN_INTERVAL_US..=MAX_INTERVAL_US).contains(&interval_us) {
+            self.reject(interval_us);
+            return IntervalKind::OutOfRange;
+        }
+
+        let accepted = match self.reference_us() {
+            Some(reference) => within(interval_us, reference, ECTOPIC_PERCENT),
+            None => true,
+        };
+        if accepted {
+            self.accept(interval_us);
+            return IntervalKind::Normal;
+        }
+
+        // a steady run of "ectopic" intervals is a changed rhythm, start over from it
+        if self.rejected_run > 0 && within(interval_us, self.last_rejected_us, ECTOPIC_PERCENT) {
+            self.rejected_run += 1;
+        } else {
+            self.rejected_run = 1;
+        }
+        self.last_rejected_us = interval_us;
+        if self.rejected_run >= NEW_RHYTHM_RUN {
+            self.clear();
+            self.accept(interval_us);
+            return IntervalKind::Normal;
+        }
+
+        self.rejected_total += 1;
+        self.adjacent = false;
+        IntervalKind::Ectopic
+    }
+
+    /// Metrics over the stored NN intervals, `None` with less than 2 of them
+    pub fn metrics(&self) -> Option<HrvMetrics> {
+        if self.len < 2 {
+            return None;
+        }
+
+        let n = self.len as u64;
+        let sum: u64 = self.stored().map(|nn| nn.interval_us as u64).sum();
+        let mean = sum / n;
+
+        let squares: u64 = self.stored()
+            .map(|nn| {
+                let d = nn.interval_us as i64 - mean as i64;
+                (d * d) as u64
+            })
+            .sum();
+
+        let mut diff_count = 0_u64;
+        let mut diff_squares = 0_u64;
+        let mut nn50 = 0_u64;
+        let mut previous: Option<u32> = None;
+        for nn in self.stored() {
+            if let (true, Some(previous)) = (nn.adjacent, previous) {
+                let d = (nn.interval_us as i64 - previous as i64).unsigned_abs();
+                diff_count += 1;
+                diff_squares += d * d;
+                if d > NN50_US as u64 {
+                    nn50 += 1;
+                }
+            }
+            previous = Some(nn.interval_us);
+        }
+
+        let rmssd = diff_squares.checked_div(diff_count).map_or(0, isqrt);
+        let pnn50 = (nn50 * 1_000).checked_div(diff_count).unwrap_or(0);
+
+        Some(HrvMetrics {
+            count: self.len as u16,
+            mean_nn_us: mean as u32,
+            sdnn_us: isqrt(squares / (n - 1)) as u32,
+            rmssd_us: rmssd as u32,
+            pnn50_permille: pnn50 as u16,
+        })
+    }
+
+    /// Number of stored NN intervals
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Number of intervals rejected since the start
+    pub fn rejected(&self) -> u32 {
+        self.rejected_total
+    }
+
+    pub fn clear(&mut self) {
+        self.cursor = 0;
+        self.len = 0;
+        self.adjacent = false;
+        self.rejected_run = 0;
+    }
+
+    fn accept(&mut self, interval_us: u32) {
+        self.rejected_run = 0;
+        if N == 0 {
+            return;
+        }
+
+        self.intervals[self.cursor] = NnInterval {
+            interval_us,
+            adjacent: self.adjacent,
+        };
+        self.cursor = (self.cursor + 1) % N;
+        self.len = (self.len + 1).min(N);
+        self.adjacent = true;
+    }
+
+    fn reject(&mut self, interval_us: u32) {
+        self.rejected_total += 1;
+        self.rejected_run = 0;
+        self.last_rejected_us = interval_us;
+        self.adjacent = false;
+    }
+
+    /// Mean of the last few NN intervals
+    fn reference_us(&self) -> Option<u32> {
+        let count = self.len.min(REFERENCE_LEN);
+        if count == 0 {
+            return None;
+        }
+        let sum: u64 = self.stored().skip(self.len - count).map(|nn| nn.interval_us as u64).sum();
+        Some((sum / count as u64) as u32)
+    }
+
+    /// Stored intervals, oldest first
+    fn stored(&self) -> impl Iterator<Item = NnInterval> + '_ {
+        let start = (self.cursor + N - self.len) % N.max(1);
+        (0..self.len).map(move |i| self.intervals[(start + i) % N])
+    }
+}
+
+impl<const N: usize> Default for HrvAnalyzer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn within(value: u32, reference: u32, percent: u32) -> bool {
+    let tolerance = reference * percent / 100;
+    value + tolerance >= reference && value <= reference + tolerance
+}
+
+/// Integer square root, rounded down
+fn isqrt(value: u64) -> u64 {
+    if value < 2 {
+        return value;
+    }
+
+    // Newton's method from an estimate above the root
+    // half the bits of the value, rounded up
+    let mut x = 1_u64 << ((65 - value.leading_zeros()) / 2);
+    loop {
+        let next = (x + value / x) / 2;
+        if next >= x {
+            return x;
+        }
+        x = next;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::heart_rate::HeartRateEstimator;
+    use crate::hrs3300::RawSample;
+
+    fn analyzer_with(intervals: &[u32]) -> HrvAnalyzer<16> {
+        let mut analyzer = HrvAnalyzer::new();
+        for &interval in intervals {
+            analyzer.push_interval(interval);
+        }
+        analyzer
+    }
+
+    #[test]
+    fn integer_sqrt() {
+        for &(value, root) in [(0, 0), (1, 1), (3, 1), (4, 2), (99, 9), (100, 10), (u64::MAX, 4_294_967_295)].iter() {
+            assert_eq!(isqrt(value), root, "{}", value);
+        }
+    }
+
+    #[test]
+    fn steady_rhythm() {
+        let metrics = analyzer_with(&[800_000; 10]).metrics().unwrap();
+        assert_eq!(metrics, HrvMetrics {
+            count: 10,
+            mean_nn_us: 800_000,
+            sdnn_us: 0,
+            rmssd_us: 0,
+            pnn50_permille: 0,
+        });
+    }
+
+    #[test]
+    fn alternating_intervals() {
+        let metrics = analyzer_with(&[800_000, 860_000, 800_000, 860_000, 800_000]).metrics().unwrap();
+        assert_eq!(metrics.mean_nn_us, 824_000);
+        assert_eq!(metrics.rmssd_us, 60_000);
+        assert_eq!(metrics.pnn50_permille, 1_000);
+        // deviations -24, 36, -24, 36, -24 ms => sqrt(4320 ms² / 4)
+        assert_eq!(metrics.sdnn_us, 32_863);
+
+        let metrics = analyzer_with(&[800_000, 840_000, 800_000]).metrics().unwrap();
+        assert_eq!(metrics.rmssd_us, 40_000);
+        assert_eq!(metrics.pnn50_permille, 0);
+    }
+
+    #[test]
+    fn ectopic_beat_is_rejected() {
+        let mut analyzer = analyzer_with(&[800_000, 810_000, 800_000]);
+
+        // premature beat and its compensatory pause
+        assert_eq!(analyzer.push_interval(450_000), IntervalKind::Ectopic);
+        assert_eq!(analyzer.push_interval(1_150_000), IntervalKind::Ectopic);
+        assert_eq!(analyzer.push_interval(790_000), IntervalKind::Normal);
+        assert_eq!(analyzer.push_interval(5_000_000), IntervalKind::OutOfRange);
+        assert_eq!(analyzer.rejected(), 3);
+
+        let metrics = analyzer.metrics().unwrap();
+        assert_eq!(metrics.count, 4);
+        // 800 -> 790 difference crosses the rejected beats and is not counted
+        assert_eq!(metrics.rmssd_us, 10_000);
+    }
+
+    #[test]
+    fn new_rhythm_is_taken() {
+        let mut analyzer = analyzer_with(&[1_000_000, 1_000_000, 1_000_000]);
+
+        assert_eq!(analyzer.push_interval(600_000), IntervalKind::Ectopic);
+        assert_eq!(analyzer.push_interval(610_000), IntervalKind::Ectopic);
+        assert_eq!(analyzer.push_interval(600_000), IntervalKind::Normal);
+        assert_eq!(analyzer.push_interval(605_000), IntervalKind::Normal);
+
+        assert_eq!(analyzer.metrics().unwrap().count, 2);
+    }
+
+    #[test]
+    fn window_keeps_last_intervals() {
+        let mut analyzer = HrvAnalyzer::<4>::new();
+        for &interval in [900_000, 900_000, 900_000, 900_000, 800_000, 800_000, 800_000, 800_000].iter() {
+            analyzer.push_interval(interval);
+        }
+        let metrics = analyzer.metrics().unwrap();
+        assert_eq!(metrics.count, 4);
+        assert_eq!(metrics.mean_nn_us, 800_000);
+    }
+
+    #[test]
+    fn gap_breaks_successive_differences() {
+        let mut analyzer = HrvAnalyzer::<8>::new();
+        analyzer.push_interval(800_000);
+        analyzer.push_interval(820_000);
+        analyzer.consume_beat(Beat { time_us: 0, interval_us: None });
+        analyzer.push_interval(790_000);
+
+        assert_eq!(analyzer.metrics().unwrap().rmssd_us, 20_000);
+    }
+
+    #[test]
+    fn metrics_display() {
+        let metrics = HrvMetrics {
+            count: 30,
+            mean_nn_us: 812_400,
+            sdnn_us: 45_600,
+            rmssd_us: 31_499,
+            pnn50_permille: 125,
+        };
+
+        let mut text = [0_u8; 96];
+        let mut cursor = Cursor { buffer: &mut text, len: 0 };
+        fmt::write(&mut cursor, format_args!("{}", metrics)).unwrap();
+        let len = cursor.len;
+        assert_eq!(
+            core::str::from_utf8(&text[..len]).unwrap(),
+            "NN 812 ms, SDNN 46 ms, RMSSD 31 ms, pNN50 12.5% (30 intervals)"
+        );
+    }
+
+    #[test]
+    fn beats_of_estimator_on_synthetic_pulse() {
+        const PERIOD_US: u32 = 12_500;
+        let mut estimator = HeartRateEstimator::new(PERIOD_US);
+        let mut analyzer = HrvAnalyzer::<32>::new();
+
+        for i in 0..(20_000_000 / PERIOD_US) {
+            let t = (i * PERIOD_US) as f32 / 1_000_000_f32;
+            let phase = 2_f32 * core::f32::consts::PI * 1.25 * t;
+            let pulse = 1_000_f32 * phase.sin();
+            estimator.consume_sample(RawSample::new((20_000_f32 + pulse) as u32, 0));
+            if let Some(beat) = estimator.beat() {
+                analyzer.consume_beat(beat);
+            }
+        }
+
+        let metrics = analyzer.metrics().unwrap();
+        assert!((metrics.mean_nn_us as i64 - 800_000).abs() < 2_000, "{:?}", metrics);
+        assert!(metrics.rmssd_us < 5_000, "{:?}", metrics);
+        assert_eq!(analyzer.rejected(), 0);
+    }
+
+    struct Cursor<'a> {
+        buffer: &'a mut [u8],
+        len: usize,
+    }
+    impl<'a> fmt::Write for Cursor<'a> {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            let end = self.len + s.len();
+            self.buffer.get_mut(self.len..end).ok_or(fmt::Error)?.copy_from_slice(s.as_bytes());
+            self.len = end;
+            Ok(())
+        }
+    }
+}
@@ -1,9 +1,13 @@
-use crate::display::{
-    self,
-    DisplayDriver
-};
+use crate::display;
 use crate::delay::TimerDelay;
-use crate::hrs3300::Sensor;
+use pt_hello::hrs3300::{
+    Sensor,
+    ProbeReport,
+};
+use crate::{
+    SensorType,
+    SensorErrorType,
+};
 use embedded_hal::{
     digital::v2::OutputPin
 };
@@ -19,139 +23,186 @@ use nrf52832_hal::{
 };
 use crate::backlight::Backlight;
 use crate::battery::BatteryStatus;
+use crate::button::Button;
+use crate::monotonic_nrf52::Rtc1;
 
-pub struct Components {
-    pub display_wrapper: DisplayDriver,    
-    pub sensor: Sensor,
-    pub backlight: Backlight,
+/// Resources used by more than one task, `app` puts them behind locks
+pub struct Shared {
     pub battery: BatteryStatus,
-    pub delay_provider: crate::SensorDelayProviderType,
 }
-impl Components {
-    pub fn new() -> Components {
-        let sensor: Sensor;
-        let display_wrapper: DisplayDriver;
-        let backlight: Backlight;
-        let battery: BatteryStatus;
-        let delay_provider: crate::SensorDelayProviderType;
 
-        let pac::Peripherals {
-            CLOCK: clock_peripheral,
-            // FICR,
-            P0: p0_peripheral,
-            // RADIO,
-            SAADC: saadc_peripheral,
-            // SPIM1,
-            TIMER0: timer0_peripheral,
-            TIMER1: timer1_peripheral,
-            TWIM0: twim0_peripheral,
-            SPIM1: spim1_peripheral,
-            ..
-        } = pac::Peripherals::take().unwrap();
-    
-        // Set up GPIO peripheral
-        let gpio = gpio::p0::Parts::new(p0_peripheral);
+/// Resources owned by a single task
+pub struct Local {
+    pub lcd: display::Lcd,
+    pub sensor: SensorType,
+    pub sensor_probe: Result<ProbeReport, SensorErrorType>,
+    pub sample_timer: crate::SampleTimerType,
+    pub block_timer: crate::dma_sampler::BlockTimerType,
+    pub ppi: pac::PPI,
+    pub backlight: Backlight,
+    pub button: Button,
+}
 
-        // Sensor
-        {
-            // P0.06 : I²C SDA
-            let sda = gpio.p0_06.into_floating_input().degrade();
-            // P0.07 : I²C SCL
-            let scl = gpio.p0_07.into_floating_input().degrade();
-            // pins for TWIM0
-            let pins = twim::Pins { scl, sda };    
-            let twim_driver = twim::Twim::new(
-                twim0_peripheral, 
-                pins, 
-                hal::target::twim0::frequency::FREQUENCY_A::K400
-            );
-            sensor = Sensor::new(twim_driver);
-        }
-        
-        // Display
-        {
-            // Set up SPI pins
-            let spi_clk = gpio.p0_02
-                .into_push_pull_output(Level::Low).degrade();
-            let spi_mosi = gpio.p0_03
-                .into_push_pull_output(Level::Low).degrade();
-            let spi_miso = gpio.p0_04
-                .into_floating_input().degrade();
-            let spi_pins = spim::Pins {
-                sck: spi_clk,
-                miso: Some(spi_miso),
-                mosi: Some(spi_mosi)
-            };
-        
-            // Set up LCD pins
-            // LCD_RS - data/clock pin      (P0.18) 	Clock/data pin (CD)
-            let lcd_data_clock = gpio.p0_18
-                .into_push_pull_output(Level::Low);
-            // LCD_CS - chip select         (P0.25) 	Chip select
-            let mut lcd_chip_select = gpio.p0_25
-                .into_push_pull_output(Level::Low);
-            // LCD_RESET - reset            (P0.26) 	Display reset
-            let lcd_reset = gpio.p0_26
-                .into_push_pull_output(Level::Low);
-        
-            // Initialize SPI
-            let spi_interface = spim::Spim::new(
-                spim1_peripheral,
-                spi_pins,
-                // Use SPI at 8MHz (the fastest clock available on the nRF52832)
-                // because otherwise refreshing will be super slow.
-                spim::Frequency::M8,
-                // SPI must be used in mode 3. Mode 0 (the default) won't work.
-                spim::MODE_3,
-                0);
-        
-            // Chip select must be held low while driving the display. It must be high
-            // when using other SPI devices on the same bus (such as external flash
-            // storage) so that the display controller won't respond to the wrong
-            // commands.
-            lcd_chip_select.set_low().unwrap();
-        
-            // Set up delay provider on TIMER0
-            let delay_provider_1 = TimerDelay::new(timer1_peripheral);
-            // Initialize LCD
-            let display_driver = st7789::ST7789::new(
-                    spi_interface,
-                    lcd_data_clock, lcd_reset,
-                    display::LCD_W, display::LCD_H,
-                    delay_provider_1);
-        
-            display_wrapper = DisplayDriver::new(display_driver);
+/// Brings the hardware up, splits it into the task resources
+pub fn init() -> (Shared, Local) {
+    let sensor: SensorType;
+    let sensor_probe: Result<ProbeReport, SensorErrorType>;
+    let lcd: display::Lcd;
+    let backlight: Backlight;
+    let battery: BatteryStatus;
+
+    let pac::Peripherals {
+        CLOCK: clock_peripheral,
+        // FICR,
+        P0: p0_peripheral,
+        // RADIO,
+        SAADC: saadc_peripheral,
+        // SPIM1,
+        // TIMER0,
+        TIMER1: timer1_peripheral,
+        TIMER2: timer2_peripheral,
+        TIMER3: timer3_peripheral,
+        RTC1: rtc1_peripheral,
+        PPI: ppi_peripheral,
+        TWIM0: twim0_peripheral,
+        SPIM1: spim1_peripheral,
+        ..
+    } = pac::Peripherals::take().unwrap();
+
+    // Set up GPIO peripheral
+    let gpio = gpio::p0::Parts::new(p0_peripheral);
+
+    // Sensor
+    {
+        // P0.06 : I²C SDA
+        let sda = gpio.p0_06.into_floating_input().degrade();
+        // P0.07 : I²C SCL
+        let scl = gpio.p0_07.into_floating_input().degrade();
+        // pins for TWIM0
+        let pins = twim::Pins { scl, sda };    
+        let twim_driver = twim::Twim::new(
+            twim0_peripheral, 
+            pins, 
+            hal::target::twim0::frequency::FREQUENCY_A::K400
+        );
+        let mut sensor_driver = Sensor::new(twim_driver);
+
+        // make sure a right sensor is soldered before going further
+        sensor_probe = sensor_driver.probe();
+        match &sensor_probe {
+            Ok(report) if report.is_default() => info!("HRS3300 found: {:?}", report),
+            Ok(report) => {
+                warn!("HRS3300 found, registers differ from power-on values: {:?}", report);
+                for (reg, value, default) in report.mismatches() {
+                    warn!("  {:?} = {:#04x}, expected {:#04x}", reg, value, default);
+                }
+            }
+            Err(e) => error!("HRS3300 probe failed: {:?}", e),
         }
 
-        // Set up clocks. On reset, the high frequency clock is already used,
-        // but we also need to switch to the external HF oscillator. This is
-        // needed for Bluetooth to work.
-        let _clocks = hal::clocks::Clocks::new(clock_peripheral).enable_ext_hfosc();
+        sensor = sensor_driver;
+    }
     
-        // Backlight
-        backlight = Backlight::init(
-            gpio.p0_14.into_push_pull_output(Level::High).degrade(),
-            gpio.p0_22.into_push_pull_output(Level::High).degrade(),
-            gpio.p0_23.into_push_pull_output(Level::High).degrade(),
-            1,
-        );
+    // Display
+    {
+        // Set up SPI pins
+        let spi_clk = gpio.p0_02
+            .into_push_pull_output(Level::Low).degrade();
+        let spi_mosi = gpio.p0_03
+            .into_push_pull_output(Level::Low).degrade();
+        let spi_miso = gpio.p0_04
+            .into_floating_input().degrade();
+        let spi_pins = spim::Pins {
+            sck: spi_clk,
+            miso: Some(spi_miso),
+            mosi: Some(spi_mosi)
+        };
     
-        // Battery Status
-        battery = BatteryStatus::init(
-            gpio.p0_12.into_floating_input(),
-            gpio.p0_31.into_floating_input(),
-            saadc_peripheral,
-        ); 
+        // Set up LCD pins
+        // LCD_RS - data/clock pin      (P0.18) 	Clock/data pin (CD)
+        let lcd_data_clock = gpio.p0_18
+            .into_push_pull_output(Level::Low);
+        // LCD_CS - chip select         (P0.25) 	Chip select
+        let mut lcd_chip_select = gpio.p0_25
+            .into_push_pull_output(Level::Low);
+        // LCD_RESET - reset            (P0.26) 	Display reset
+        let lcd_reset = gpio.p0_26
+            .into_push_pull_output(Level::Low);
     
-        // Delay provider
-        delay_provider = TimerDelay::new(timer0_peripheral);
-
-        Components {
-            display_wrapper,
-            sensor,
-            backlight,
-            battery,
-            delay_provider
-        }
+        // Initialize SPI
+        let spi_interface = spim::Spim::new(
+            spim1_peripheral,
+            spi_pins,
+            // Use SPI at 8MHz (the fastest clock available on the nRF52832)
+            // because otherwise refreshing will be super slow.
+            spim::Frequency::M8,
+            // SPI must be used in mode 3. Mode 0 (the default) won't work.
+            spim::MODE_3,
+            0);
+    
+        // Chip select must be held low while driving the display. It must be high
+        // when using other SPI devices on the same bus (such as external flash
+        // storage) so that the display controller won't respond to the wrong
+        // commands.
+        lcd_chip_select.set_low().unwrap();
+    
+        // Set up delay provider on TIMER0
+        let delay_provider_1 = TimerDelay::new(timer1_peripheral);
+        // Initialize LCD
+        let display_driver = st7789::ST7789::new(
+                spi_interface,
+                lcd_data_clock, lcd_reset,
+                display::LCD_W, display::LCD_H,
+                delay_provider_1);
+    
+        lcd = display::Lcd(display_driver);
     }
-}
\ No newline at end of file
+
+    // Set up clocks. On reset, the high frequency clock is already used,
+    // but we also need to switch to the external HF oscillator. This is
+    // needed for Bluetooth to work.
+    // The low frequency clock from the 32.768 kHz crystal drives the RTC.
+    let _clocks = hal::clocks::Clocks::new(clock_peripheral)
+        .enable_ext_hfosc()
+        .set_lfclk_src_external(hal::clocks::LfOscConfiguration::NoExternalNoBypass)
+        .start_lfclk();
+
+    // Monotonic clock for the sample timestamps
+    Rtc1::initialize(rtc1_peripheral);
+
+    // Backlight
+    backlight = Backlight::init(
+        gpio.p0_14.into_push_pull_output(Level::High).degrade(),
+        gpio.p0_22.into_push_pull_output(Level::High).degrade(),
+        gpio.p0_23.into_push_pull_output(Level::High).degrade(),
+        1,
+    );
+
+    // Side button
+    let button = Button::init(
+        gpio.p0_13.into_pulldown_input(),
+        gpio.p0_15.into_push_pull_output(Level::High),
+    );
+
+    // Battery Status
+    battery = BatteryStatus::init(
+        gpio.p0_12.into_floating_input(),
+        gpio.p0_31.into_floating_input(),
+        saadc_peripheral,
+    ); 
+
+    let shared = Shared {
+        battery,
+    };
+    let local = Local {
+        lcd,
+        sensor,
+        sensor_probe,
+        sample_timer: timer2_peripheral,
+        block_timer: timer3_peripheral,
+        ppi: ppi_peripheral,
+        backlight,
+        button,
+    };
+    (shared, local)
+}
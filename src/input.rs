@@ -0,0 +1,87 @@
+//! Input events of the watch, from the side button.
+//!
+//! The button is polled, a press shorter than `LONG_PRESS_MS` is a `Press`
+//! when it's released, holding it on is a single `LongPress` as soon as the
+//! time is up. Polling every few tens of milliseconds also gets the contact
+//! bounce out of the way.
+
+/// Holding the button this long is a long press
+pub const LONG_PRESS_MS: u32 = 800;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InputEvent {
+    Press,
+    LongPress,
+}
+
+/// Turns the polled button level into events
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ButtonDecoder {
+    /// Time the button went down, `None` while it's up
+    pressed_at: Option<u32>,
+    long_sent: bool,
+}
+
+impl ButtonDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes the button level at `now_ms`, a millisecond clock which may wrap
+    pub fn update(&mut self, pressed: bool, now_ms: u32) -> Option<InputEvent> {
+        match (self.pressed_at, pressed) {
+            (None, true) => {
+                self.pressed_at = Some(now_ms);
+                self.long_sent = false;
+                None
+            }
+            (Some(at), true) => {
+                if self.long_sent || now_ms.wrapping_sub(at) < LONG_PRESS_MS {
+                    return None;
+                }
+                self.long_sent = true;
+                Some(InputEvent::LongPress)
+            }
+            (Some(_), false) => {
+                self.pressed_at = None;
+                if self.long_sent {
+                    None
+                } else {
+                    Some(InputEvent::Press)
+                }
+            }
+            (None, false) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_press_on_release() {
+        let mut button = ButtonDecoder::new();
+        assert_eq!(button.update(false, 0), None);
+        assert_eq!(button.update(true, 50), None);
+        assert_eq!(button.update(true, 100), None);
+        assert_eq!(button.update(false, 150), Some(InputEvent::Press));
+        assert_eq!(button.update(false, 200), None);
+    }
+
+    #[test]
+    fn long_press_once_while_held() {
+        let mut button = ButtonDecoder::new();
+        // the clock wraps in the middle of the press
+        let start = u32::MAX - 100;
+        assert_eq!(button.update(true, start), None);
+        assert_eq!(button.update(true, start.wrapping_add(LONG_PRESS_MS - 1)), None);
+        assert_eq!(button.update(true, start.wrapping_add(LONG_PRESS_MS)), Some(InputEvent::LongPress));
+        assert_eq!(button.update(true, start.wrapping_add(2 * LONG_PRESS_MS)), None);
+        assert_eq!(button.update(false, start.wrapping_add(2 * LONG_PRESS_MS)), None);
+
+        // the next press starts over
+        assert_eq!(button.update(true, 5_000), None);
+        assert_eq!(button.update(false, 5_050), Some(InputEvent::Press));
+    }
+}
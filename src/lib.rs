@@ -0,0 +1,30 @@
+//! Hardware independent part of the firmware.
+//!
+//! Everything here builds for the watch and for the host, so drivers and
+//! signal processing are covered by unit tests without a watch:
+//!
+//! ```sh
+//! make test
+//! ```
+
+#![no_std]
+
+pub mod hrs3300;
+pub mod agc;
+pub mod ppg_processor;
+pub mod heart_rate;
+pub mod filters;
+pub mod hrv;
+pub mod wrist;
+pub mod sample_queue;
+pub mod sample_timing;
+pub mod strip_chart;
+pub mod battery_level;
+pub mod plot;
+pub mod framebuffer;
+pub mod render;
+pub mod scroll;
+pub mod scroll_chart;
+pub mod input;
+pub mod ui;
+pub mod screens;
@@ -1,38 +1,60 @@
-//! Using NRF52 as monotonic timer
+//! Using NRF52 RTC as monotonic timer
 //!
-//! Source:
+//! RTC1 counts the 32.768 kHz low frequency clock, it keeps running with the
+//! CPU asleep and costs next to nothing. The 24 bit counter wraps around every
+//! 512 s, the overflow interrupt counts the wraps, together they make 64 bits
+//! of ticks, which don't wrap in the lifetime of the watch.
+//!
+//! `Rtc1::initialize` takes the RTC1 peripheral, so nothing else can
+//! reconfigure the counter. Before it's called `Instant::now()` stays at zero.
+//! The compare channel wakes up the task scheduler, the RTC1 interrupt handler
+//! is in `app` and calls `Rtc1::on_interrupt`.
+//!
+//! Based on:
 //! https://github.com/rtfm-rs/rtfm-examples/blob/master/rtfm_v5/monotonic_nrf52/src/monotonic_nrf52.rs
 
-use core::u32;
 use core::{
-    cmp::Ordering,
-    convert::{Infallible, TryInto},
     fmt, ops,
+    sync::atomic::{self, AtomicU32},
 };
+use cortex_m::interrupt as cs;
 use nrf52832_hal::target;
 
-/// A measurement of the counter. Opaque and useful only with `Duration`
-///
-/// # Correctness
-///
-/// Adding or subtracting a `Duration` of more than `(1 << 31)` cycles to an `Instant` effectively
-/// makes it "wrap around" and creates an incorrect value. This is also true if the operation is
-/// done in steps, e.g. `(instant + dur) + dur` where `dur` is `(1 << 30)` ticks.
-#[derive(Clone, Copy, Eq, PartialEq)]
+/// Ticks per second of the low frequency clock
+pub const TICKS_PER_SECOND: u64 = 32_768;
+/// Bits of the RTC counter
+const COUNTER_BITS: u32 = 24;
+const COUNTER_MASK: u64 = (1 << COUNTER_BITS) - 1;
+/// Farthest compare which can't be mistaken for one after the wrap
+const MAX_COMPARE_TICKS: u64 = 1 << (COUNTER_BITS - 1);
+
+/// Wraps of the RTC counter, upper part of the ticks
+static OVERFLOWS: AtomicU32 = AtomicU32::new(0);
+
+/// A measurement of the monotonic clock. Opaque and useful only with `Duration`
+#[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
 pub struct Instant {
-    inner: i32,
+    ticks: u64,
 }
 
 impl Instant {
     /// Returns an instant corresponding to "now"
     pub fn now() -> Self {
-        let now = {
-            let timer = unsafe { &*target::TIMER1::ptr() };
-            timer.tasks_capture[0].write(|w| unsafe { w.bits(1) });
-            timer.cc[0].read().bits()
-        };
+        let rtc = unsafe { &*target::RTC1::ptr() };
+
+        let ticks = cs::free(|_| {
+            let mut overflows = OVERFLOWS.load(atomic::Ordering::Relaxed);
+            let mut counter = rtc.counter.read().bits();
+            if rtc.events_ovrflw.read().bits() != 0 {
+                // wrapped, but the interrupt hasn't counted it yet;
+                // the counter is read again, it might have been before the wrap
+                overflows += 1;
+                counter = rtc.counter.read().bits();
+            }
+            ((overflows as u64) << COUNTER_BITS) | counter as u64
+        });
 
-        Instant { inner: now as i32 }
+        Instant { ticks }
     }
 
     /// Returns the amount of time elapsed since this instant was created.
@@ -40,33 +62,34 @@ impl Instant {
         Instant::now() - *self
     }
 
-    /// Returns the underlying count
-    pub fn counts(&self) -> u32 {
-        self.inner as u32
+    /// Returns the underlying count of 32.768 kHz ticks
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    /// Time since the clock started
+    pub fn as_micros(&self) -> u64 {
+        Duration::from_ticks(self.ticks).as_micros()
     }
 
-    /// Returns the amount of time elapsed from another instant to this one.
+    /// Returns the amount of time elapsed from another instant to this one,
+    /// zero if the other one is later
     pub fn duration_since(&self, earlier: Instant) -> Duration {
-        let diff = self.inner - earlier.inner;
-        assert!(diff >= 0, "second instant is later than self");
-        Duration { inner: diff as u32 }
+        Duration::from_ticks(self.ticks.saturating_sub(earlier.ticks))
     }
 }
 
 impl fmt::Debug for Instant {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_tuple("Instant")
-            .field(&(self.inner as u32))
+            .field(&self.ticks)
             .finish()
     }
 }
 
 impl ops::AddAssign<Duration> for Instant {
     fn add_assign(&mut self, dur: Duration) {
-        // NOTE this is a debug assertion because there's no foolproof way to detect a wrap around;
-        // the user may write `(instant + dur) + dur` where `dur` is `(1<<31)-1` ticks.
-        debug_assert!(dur.inner < (1 << 31));
-        self.inner = self.inner.wrapping_add(dur.inner as i32);
+        self.ticks += dur.ticks;
     }
 }
 
@@ -81,9 +104,7 @@ impl ops::Add<Duration> for Instant {
 
 impl ops::SubAssign<Duration> for Instant {
     fn sub_assign(&mut self, dur: Duration) {
-        // NOTE see the NOTE in `<Instant as AddAssign<Duration>>::add_assign`
-        debug_assert!(dur.inner < (1 << 31));
-        self.inner = self.inner.wrapping_sub(dur.inner as i32);
+        self.ticks -= dur.ticks;
     }
 }
 
@@ -104,56 +125,59 @@ impl ops::Sub<Instant> for Instant {
     }
 }
 
-impl Ord for Instant {
-    fn cmp(&self, rhs: &Self) -> Ordering {
-        self.inner.wrapping_sub(rhs.inner).cmp(&0)
-    }
-}
-
-impl PartialOrd for Instant {
-    fn partial_cmp(&self, rhs: &Self) -> Option<Ordering> {
-        Some(self.cmp(rhs))
-    }
-}
-
-/// A `Duration` type to represent a span of time.
-///
-/// This data type is only available on ARMv7-M
-///
-/// # Correctness
+/// A `Duration` type to represent a span of time in 32.768 kHz ticks.
 ///
-/// This type is *not* appropriate for representing time spans in the order of, or larger than,
-/// seconds because it can hold a maximum of `(1 << 31)` "ticks" where each tick is the inverse of
-/// the CPU frequency, which usually is dozens of MHz.
+/// A tick is about 30.5 µs, conversions from smaller units round down.
 #[derive(Clone, Copy, Default, Eq, Ord, PartialEq, PartialOrd)]
 pub struct Duration {
-    inner: u32,
+    ticks: u64,
 }
 
 impl Duration {
-    /// Creates a new `Duration` from the specified number of clock cycles
-    pub fn from_cycles(cycles: u32) -> Self {
-        Duration { inner: cycles }
+    pub fn from_ticks(ticks: u64) -> Self {
+        Duration { ticks }
     }
 
-    /// Returns the total number of clock cycles contained by this `Duration`
-    pub fn as_cycles(&self) -> u32 {
-        self.inner
+    pub fn from_micros(micros: u64) -> Self {
+        // 1_000_000 / 32_768 is 15_625 / 512
+        Duration { ticks: micros * 512 / 15_625 }
     }
-}
 
-// Used internally by RTFM to convert the duration into a known type
-impl TryInto<u32> for Duration {
-    type Error = Infallible;
+    pub fn from_millis(millis: u64) -> Self {
+        Duration { ticks: millis * TICKS_PER_SECOND / 1_000 }
+    }
 
-    fn try_into(self) -> Result<u32, Infallible> {
-        Ok(self.as_cycles())
+    pub fn from_secs(secs: u64) -> Self {
+        Duration { ticks: secs * TICKS_PER_SECOND }
+    }
+
+    /// Returns the total number of ticks contained by this `Duration`
+    pub fn as_ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    pub fn as_micros(&self) -> u64 {
+        self.ticks * 15_625 / 512
+    }
+
+    pub fn as_millis(&self) -> u64 {
+        self.ticks * 1_000 / TICKS_PER_SECOND
+    }
+
+    pub fn as_secs(&self) -> u64 {
+        self.ticks / TICKS_PER_SECOND
+    }
+}
+
+impl fmt::Debug for Duration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}us", self.as_micros())
     }
 }
 
 impl ops::AddAssign for Duration {
     fn add_assign(&mut self, dur: Duration) {
-        self.inner += dur.inner;
+        self.ticks += dur.ticks;
     }
 }
 
@@ -162,7 +186,7 @@ impl ops::Add<Duration> for Duration {
 
     fn add(self, other: Self) -> Self {
         Duration {
-            inner: self.inner + other.inner,
+            ticks: self.ticks + other.ticks,
         }
     }
 }
@@ -172,7 +196,7 @@ impl ops::Mul<u32> for Duration {
 
     fn mul(self, other: u32) -> Self {
         Duration {
-            inner: self.inner * other,
+            ticks: self.ticks * other as u64,
         }
     }
 }
@@ -185,7 +209,7 @@ impl ops::MulAssign<u32> for Duration {
 
 impl ops::SubAssign for Duration {
     fn sub_assign(&mut self, rhs: Duration) {
-        self.inner -= rhs.inner;
+        self.ticks -= rhs.ticks;
     }
 }
 
@@ -194,14 +218,12 @@ impl ops::Sub<Duration> for Duration {
 
     fn sub(self, rhs: Self) -> Self {
         Duration {
-            inner: self.inner - rhs.inner,
+            ticks: self.ticks - rhs.ticks,
         }
     }
 }
 
-/// Adds the `millis` and `micros` methods to the `u32` type
-///
-/// This trait is only available on ARMv7-M
+/// Adds the `secs`, `millis`, `micros` and `hz` methods to the `u32` type
 pub trait U32Ext {
     /// Converts the `u32` value as seconds into ticks
     fn secs(self) -> Duration;
@@ -212,75 +234,95 @@ pub trait U32Ext {
     /// Converts the `u32` value as microseconds into ticks
     fn micros(self) -> Duration;
 
-    /// Converts the `u32` value as hertz into ticks
+    /// Converts the `u32` value as hertz into the ticks of one period
     fn hz(self) -> Duration;
 }
 
 impl U32Ext for u32 {
     fn secs(self) -> Duration {
-        self.millis() * 1_000
+        Duration::from_secs(self as u64)
     }
 
     fn millis(self) -> Duration {
-        self.micros() * 1_000
+        Duration::from_millis(self as u64)
     }
 
     fn micros(self) -> Duration {
-        let frac = Tim1::ratio();
-        Duration {
-            inner: (64 * frac.denominator * self) / frac.numerator,
-        }
+        Duration::from_micros(self as u64)
     }
 
     fn hz(self) -> Duration {
-        (1_000_000 / self).micros()
+        Duration::from_ticks(TICKS_PER_SECOND / self.max(1) as u64)
     }
 }
 
-/// Implementor of the `rtfm::Monotonic` traits and used to consume the timer
-/// to not allow for erroneous configuration.
+/// Owner of RTC1, consumes the peripheral to not allow for
+/// erroneous configuration.
 ///
-/// The timer must be initialized through `initialize()`.
-pub struct Tim1;
-
-impl Tim1 {
-    pub fn initialize(timer: target::TIMER1) {
-        // Auto restart, make sure the entire timer won't stop for any event
-        timer.shorts.write(|w| {
-            w.compare0_clear()
-                .enabled()
-                .compare0_stop()
-                .disabled()
-                .compare1_clear()
-                .enabled()
-                .compare1_stop()
-                .disabled()
-                .compare2_clear()
-                .enabled()
-                .compare2_stop()
-                .disabled()
-                .compare3_clear()
-                .enabled()
-                .compare3_stop()
-                .disabled()
-        });
+/// The RTC must be initialized through `initialize()`,
+/// with the low frequency clock started.
+pub struct Rtc1;
+
+impl Rtc1 {
+    pub fn initialize(rtc: target::RTC1) {
+        rtc.tasks_stop.write(|w| unsafe { w.bits(1) });
+
+        // 32.768 kHz, no prescaler
+        rtc.prescaler.write(|w| unsafe { w.prescaler().bits(0) });
+
+        // only the overflow is needed, it wakes the CPU every 512 s
+        rtc.intenset.write(|w| w.ovrflw().set());
+        rtc.events_ovrflw.reset();
 
-        // 1 MHz mode
-        timer.prescaler.write(|w| unsafe { w.prescaler().bits(4) });
+        OVERFLOWS.store(0, atomic::Ordering::Relaxed);
+        rtc.tasks_clear.write(|w| unsafe { w.bits(1) });
+        rtc.tasks_start.write(|w| unsafe { w.bits(1) });
 
-        // 32 bit mode
-        timer.bitmode.write(|w| w.bitmode()._32bit());
+        unsafe { target::NVIC::unmask(target::Interrupt::RTC1) };
 
-        // Set compare value to max, not sure if this is needed
-        timer.cc[0].write(|w| unsafe { w.cc().bits(u32::MAX) });
+        // The RTC is not given back, it is now set up and consumed
+    }
 
-        // Clear the counter value
-        timer.tasks_clear.write(|w| unsafe { w.bits(1) });
+    /// Fires the RTC1 interrupt at `instant`, or right away if it's already
+    /// past. Instants too far ahead fire early, at the longest distance the
+    /// 24 bit compare reaches, the handler is expected to set it again.
+    pub fn set_compare(instant: Instant) {
+        let rtc = unsafe { &*target::RTC1::ptr() };
+
+        cs::free(|_| {
+            let now = Instant::now().ticks;
+            // compare needs to be at least 2 ticks ahead to fire
+            let ticks = instant.ticks.max(now + 2).min(now + MAX_COMPARE_TICKS);
+            rtc.cc[0].write(|w| unsafe { w.compare().bits((ticks & COUNTER_MASK) as u32) });
+            rtc.events_compare[0].reset();
+            rtc.intenset.write(|w| w.compare0().set());
+
+            if Instant::now().ticks + 2 > ticks {
+                // counter might have passed it while it was written
+                target::NVIC::pend(target::Interrupt::RTC1);
+            }
+        });
+    }
 
-        // Start the timer
-        timer.tasks_start.write(|w| unsafe { w.bits(1) });
+    /// Stops the compare interrupt, nothing is waiting
+    pub fn clear_compare() {
+        let rtc = unsafe { &*target::RTC1::ptr() };
+        rtc.intenclr.write(|w| w.compare0().clear());
+        rtc.events_compare[0].reset();
+    }
 
-        // Throw away the timer, it is now setup and consumed
-        drop(timer);
+    /// Handles the RTC1 interrupt, counts the overflows and
+    /// clears the compare event. To be called from the RTC1 handler.
+    pub fn on_interrupt() {
+        let rtc = unsafe { &*target::RTC1::ptr() };
+        cs::free(|_| {
+            if rtc.events_ovrflw.read().bits() != 0 {
+                rtc.events_ovrflw.reset();
+                OVERFLOWS.fetch_add(1, atomic::Ordering::Relaxed);
+            }
+            rtc.events_compare[0].reset();
+            // read back, so the cleared events don't fire the interrupt again
+            rtc.events_ovrflw.read();
+        });
     }
 }
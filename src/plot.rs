@@ -0,0 +1,79 @@
+//! Plot math for the 240x240 display, independent of the display driver.
+
+use embedded_graphics::prelude::Point;
+
+pub const LCD_W: u16 = 240;
+pub const LCD_H: u16 = 240;
+
+pub fn transform(p: Point) -> Point {
+    Point::new(
+        clamp(p.y + LCD_H as i32 / 2, 0, LCD_W),
+        clamp(p.x + LCD_W as i32 / 2, 0, LCD_H)
+    )
+}
+
+pub fn clamp(x: i32, min: u16, max: u16) -> i32 {
+    let x_u16 = x as u16;
+    if x_u16 > max { max as i32 }
+    else if x_u16 < min { min as i32 }
+    else { x }
+}
+
+// y = mul * sin(x / div)
+pub fn sin(x: i32, div: f32, mul: f32) -> i32 {
+    let mut y: f32 = 0_f32;
+    let x = x as f32;
+
+    let mut sign: f32 = 1_f32;
+    let mut dx: f32 = x / div;
+
+    let mut den_factor: f32 = 1_f32;
+
+    loop {
+        y += sign * dx; 
+
+        // calculate next addition            
+        dx *= x / div * x / div;
+
+        den_factor += 1_f32;
+        dx /= den_factor;
+        den_factor += 1_f32;
+        dx /= den_factor;
+        //println!("dx: '{}'", dx);
+
+        if dx < 0.1_f32 && dx > -0.1_f32 { break; }
+
+        sign *= -1_f32;
+    }
+
+    (y * mul) as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transform_centers_origin() {
+        assert_eq!(transform(Point::new(0, 0)), Point::new(120, 120));
+        // x goes down the screen, y goes right
+        assert_eq!(transform(Point::new(-120, 10)), Point::new(130, 0));
+    }
+
+    #[test]
+    fn clamp_to_range() {
+        assert_eq!(clamp(5, 0, 240), 5);
+        assert_eq!(clamp(300, 0, 240), 240);
+        assert_eq!(clamp(5, 10, 240), 10);
+    }
+
+    #[test]
+    fn sin_values() {
+        // series stops at the first term below 0.1, so up to 0.1 * mul off
+        assert_eq!(sin(0, 10_f32, 100_f32), 0);
+        assert!((sin(5, 10_f32, 100_f32) - 48).abs() <= 10);
+        assert!((sin(16, 10_f32, 100_f32) - 100).abs() <= 10);
+        assert!((sin(-16, 10_f32, 100_f32) + 100).abs() <= 10);
+        assert!(sin(31, 10_f32, 100_f32).abs() <= 10);
+    }
+}
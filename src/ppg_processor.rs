@@ -1,43 +1,255 @@
 use crate::hrs3300::RawSample as Rs;
 
-#[allow(unused)]
 pub const VALUES_BUFFER_LENGTH: usize = 100;
 
-#[allow(unused)]
-pub struct PpgFilter {
-    pub values_buffer: [Rs; VALUES_BUFFER_LENGTH],  
-    cursor: usize,  
+/// Last `N` values in a ring buffer with a running sum,
+/// so pushing a value and getting the mean don't depend on `N`
+#[derive(Clone, Debug)]
+pub struct SlidingWindow<const N: usize> {
+    values: [i64; N],
+    cursor: usize,
+    len: usize,
+    sum: i64,
 }
-impl PpgFilter{
-    #[allow(unused)]
-    pub fn new () -> Self {
-        PpgFilter {
-            values_buffer: [
-                Rs::new(0_u32, 0_u32); 
-                VALUES_BUFFER_LENGTH
-            ],
-            cursor: 0
-        }
-    }
-
-    #[allow(unused)]
-    pub fn consume_value(&mut self, value: Rs) -> i64 {
-        self.cursor += 1;
-        return if self.cursor >= self.values_buffer.len() {
-            self.cursor = self.values_buffer.len();
-            self.values_buffer.rotate_left(1);
-            *(self.values_buffer.last_mut().unwrap()) = value;
-            value.get_sum() as i64 - Self::get_avg(&self) as i64
+impl<const N: usize> SlidingWindow<N> {
+    pub fn new() -> Self {
+        SlidingWindow {
+            values: [0; N],
+            cursor: 0,
+            len: 0,
+            sum: 0,
+        }
+    }
+
+    /// Adds the value, returns the one which left the window
+    pub fn push(&mut self, value: i64) -> Option<i64> {
+        if N == 0 {
+            return Some(value);
+        }
+
+        let evicted = if self.is_full() {
+            Some(self.values[self.cursor])
         } else {
-            0_i64
-        }        
+            self.len += 1;
+            None
+        };
+
+        self.sum += value - evicted.unwrap_or(0);
+        self.values[self.cursor] = value;
+        self.cursor = (self.cursor + 1) % N;
+
+        evicted
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    pub fn sum(&self) -> i64 {
+        self.sum
+    }
+
+    /// Mean of the window, `None` until `N` values are pushed
+    pub fn mean(&self) -> Option<i64> {
+        if !self.is_full() || N == 0 {
+            return None;
+        }
+        Some(self.sum / N as i64)
+    }
+
+    pub fn clear(&mut self) {
+        self.cursor = 0;
+        self.len = 0;
+        self.sum = 0;
+    }
+}
+impl<const N: usize> Default for SlidingWindow<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `SlidingWindow` with a running sum of squares for the variance
+#[derive(Clone, Debug)]
+pub struct SlidingStats<const N: usize> {
+    window: SlidingWindow<N>,
+    sum_squares: i128,
+}
+impl<const N: usize> SlidingStats<N> {
+    pub fn new() -> Self {
+        SlidingStats {
+            window: SlidingWindow::new(),
+            sum_squares: 0,
+        }
+    }
+
+    /// Adds the value, returns the one which left the window
+    pub fn push(&mut self, value: i64) -> Option<i64> {
+        let evicted = self.window.push(value);
+        let evicted_square = evicted.map_or(0, |evicted| evicted as i128 * evicted as i128);
+        self.sum_squares += value as i128 * value as i128 - evicted_square;
+        evicted
+    }
+
+    pub fn window(&self) -> &SlidingWindow<N> {
+        &self.window
+    }
+
+    /// Mean of the window, `None` until `N` values are pushed
+    pub fn mean(&self) -> Option<i64> {
+        self.window.mean()
+    }
+
+    /// Population variance of the window, `None` until `N` values are pushed
+    pub fn variance(&self) -> Option<i64> {
+        self.window.mean()?;
+
+        let n = N as i128;
+        let sum = self.window.sum() as i128;
+        Some(((n * self.sum_squares - sum * sum) / (n * n)) as i64)
+    }
+
+    pub fn clear(&mut self) {
+        self.window.clear();
+        self.sum_squares = 0;
+    }
+}
+impl<const N: usize> Default for SlidingStats<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Removes the slow trend from the PPG signal by subtracting
+/// the moving average over the last `N` samples.
+///
+/// Works on normalised values (see `hrs3300::normalise`), so a gain or
+/// resolution change doesn't show up as a step.
+pub struct PpgFilter<const N: usize = VALUES_BUFFER_LENGTH> {
+    window: SlidingWindow<N>,
+}
+impl<const N: usize> PpgFilter<N> {
+    pub fn new() -> Self {
+        PpgFilter {
+            window: SlidingWindow::new(),
+        }
+    }
+
+    /// Returns the sample minus the moving average,
+    /// `None` while the window is filling up
+    pub fn consume_value(&mut self, value: Rs) -> Option<i64> {
+        let value = value.get_normalised_sum() as i64;
+        self.window.push(value);
+        Some(value - self.window.mean()?)
+    }
+
+    pub fn reset(&mut self) {
+        self.window.clear();
+    }
+}
+impl<const N: usize> Default for PpgFilter<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hrs3300::{Gain, BitsResolution};
+
+    /// Sample which normalises to its own value, the ALS isn't amplified
+    /// and normalises to 64 times its value
+    fn sample(hrs: u32, als: u32) -> Rs {
+        Rs::with_setting(hrs, als, Gain::X64, BitsResolution::_18)
+    }
+
+    #[test]
+    fn window_matches_plain_sum() {
+        let mut window = SlidingWindow::<5>::new();
+        let values = [3_i64, -7, 12, 0, 5, 9, -2, 40, 1, 1, 8];
+
+        for (i, &value) in values.iter().enumerate() {
+            let evicted = window.push(value);
+            assert_eq!(evicted, if i >= 5 { Some(values[i - 5]) } else { None });
+
+            let start = (i + 1).saturating_sub(5);
+            assert_eq!(window.sum(), values[start..=i].iter().sum::<i64>());
+            assert_eq!(window.len(), i.min(4) + 1);
+        }
+    }
+
+    #[test]
+    fn window_mean_after_warm_up() {
+        let mut window = SlidingWindow::<4>::new();
+        for &value in [10, 20, 30].iter() {
+            window.push(value);
+            assert_eq!(window.mean(), None);
+        }
+        window.push(40);
+        assert_eq!(window.mean(), Some(25));
+
+        window.clear();
+        assert!(window.is_empty());
+        assert_eq!(window.mean(), None);
+    }
+
+    #[test]
+    fn stats_variance() {
+        let mut stats = SlidingStats::<4>::new();
+        for &value in [2, 4, 6, 8].iter() {
+            assert_eq!(stats.variance(), None);
+            stats.push(value);
+        }
+        // 2, 4, 6, 8 => mean 5, variance (9 + 1 + 1 + 9) / 4
+        assert_eq!(stats.variance(), Some(5));
+
+        for &value in [0, 0, 8, 8].iter() {
+            stats.push(value);
+        }
+        // 0, 0, 8, 8 => mean 4, variance 16, not the sample variance
+        assert_eq!(stats.mean(), Some(4));
+        assert_eq!(stats.variance(), Some(16));
+
+        for &value in [1_000, -1_000, 1_000, -1_000].iter() {
+            stats.push(value);
+        }
+        assert_eq!(stats.variance(), Some(1_000_000));
+    }
+
+    #[test]
+    fn filter_warm_up_and_detrend() {
+        let mut filter = PpgFilter::<10>::new();
+        for _ in 0..9 {
+            assert_eq!(filter.consume_value(sample(1_500, 8)), None);
+        }
+        assert_eq!(filter.consume_value(sample(1_500, 8)), Some(0));
+
+        // step goes through, then fades as the average follows it
+        assert_eq!(filter.consume_value(sample(2_500, 8)), Some(900));
+        for _ in 0..9 {
+            filter.consume_value(sample(2_500, 8));
+        }
+        assert_eq!(filter.consume_value(sample(2_500, 8)), Some(0));
     }
 
-    fn get_avg(&self) -> i64 {
-        let mut avg = 0_i64;
-        for value in self.values_buffer.iter() {
-             avg += value.get_sum() as i64;
+    #[test]
+    fn filter_ignores_setting_change() {
+        let mut filter = PpgFilter::<10>::new();
+        for _ in 0..10 {
+            filter.consume_value(Rs::with_setting(8_000, 100, Gain::X8, BitsResolution::_16));
         }
-        avg / self.values_buffer.len() as i64
+
+        // same light at a quarter of the gain and two more bits,
+        // the ALS only gets the bits
+        assert_eq!(filter.consume_value(Rs::with_setting(8_000, 400, Gain::X2, BitsResolution::_18)), Some(0));
     }
-}
\ No newline at end of file
+}
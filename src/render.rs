@@ -0,0 +1,130 @@
+//! Drawing in windows of pixels instead of pixel by pixel.
+//!
+//! Every `set_pixel` of the ST7789 sets the address window and starts a
+//! memory write, 11 bytes of commands for 2 bytes of color. `WindowWrite`
+//! sends a whole rectangle after a single window, so the drawing code sends
+//! only the dirty parts of the screen in as few windows as it can.
+//! `FrameStats` keeps the frame times, to compare the ways of drawing.
+
+use embedded_graphics::{
+    prelude::*,
+    pixelcolor::{raw::RawU16, Rgb565},
+};
+use embedded_hal::{
+    blocking::{delay::DelayUs, spi},
+    digital::v2::OutputPin,
+};
+
+use crate::framebuffer::FrameBuffer;
+
+/// Display which takes a rectangle of pixels in one go
+pub trait WindowWrite: DrawTarget<Rgb565> {
+    /// Fills the window from `top_left` to `bottom_right`, both included,
+    /// row by row from the top left pixel
+    fn write_window<I>(&mut self, top_left: Point, bottom_right: Point, colors: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Rgb565>;
+}
+
+impl<SPI, DC, RST, DELAY> WindowWrite for st7789::ST7789<SPI, DC, RST, DELAY>
+where
+    SPI: spi::Write<u8>,
+    DC: OutputPin,
+    RST: OutputPin,
+    DELAY: DelayUs<u32>,
+{
+    fn write_window<I>(&mut self, top_left: Point, bottom_right: Point, colors: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Rgb565>,
+    {
+        self.set_pixels(
+            top_left.x as u16, top_left.y as u16,
+            bottom_right.x as u16, bottom_right.y as u16,
+            colors.into_iter().map(|color| RawU16::from(color).into_inner()),
+        )
+    }
+}
+
+impl<const W: usize, const H: usize> WindowWrite for FrameBuffer<W, H> {
+    fn write_window<I>(&mut self, top_left: Point, bottom_right: Point, colors: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Rgb565>,
+    {
+        self.count_window();
+        let width = (bottom_right.x - top_left.x + 1).max(0);
+        let height = (bottom_right.y - top_left.y + 1).max(0);
+        let points = (0..height).flat_map(|y| (0..width).map(move |x| top_left + Point::new(x, y)));
+        for (point, color) in points.zip(colors) {
+            self.set_pixel(point, color);
+        }
+        Ok(())
+    }
+}
+
+/// Times of the drawn frames since the last reset
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FrameStats {
+    pub frames: u32,
+    pub last_us: u32,
+    pub min_us: u32,
+    pub max_us: u32,
+    total_us: u64,
+}
+
+impl FrameStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes the time a frame took to draw
+    pub fn record(&mut self, frame_us: u32) {
+        self.min_us = if self.frames == 0 { frame_us } else { self.min_us.min(frame_us) };
+        self.max_us = self.max_us.max(frame_us);
+        self.last_us = frame_us;
+        self.total_us += frame_us as u64;
+        self.frames += 1;
+    }
+
+    /// `None` before the first frame
+    pub fn average_us(&self) -> Option<u32> {
+        if self.frames == 0 {
+            return None;
+        }
+        Some((self.total_us / self.frames as u64) as u32)
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_fills_rows() {
+        let mut buffer = FrameBuffer::<4, 4>::new();
+        let colors = [Rgb565::RED, Rgb565::GREEN, Rgb565::BLUE, Rgb565::WHITE];
+        buffer.write_window(Point::new(1, 1), Point::new(2, 2), colors.iter().copied()).unwrap();
+
+        assert_eq!(buffer.row(1), &[Rgb565::BLACK, Rgb565::RED, Rgb565::GREEN, Rgb565::BLACK]);
+        assert_eq!(buffer.row(2), &[Rgb565::BLACK, Rgb565::BLUE, Rgb565::WHITE, Rgb565::BLACK]);
+        assert_eq!((buffer.windows(), buffer.pixels()), (1, 4));
+    }
+
+    #[test]
+    fn frame_stats() {
+        let mut stats = FrameStats::new();
+        assert_eq!(stats.average_us(), None);
+
+        for frame_us in &[3_000, 1_000, 5_000] {
+            stats.record(*frame_us);
+        }
+        assert_eq!((stats.frames, stats.min_us, stats.max_us, stats.last_us), (3, 1_000, 5_000, 5_000));
+        assert_eq!(stats.average_us(), Some(3_000));
+
+        stats.reset();
+        assert_eq!(stats, FrameStats::new());
+    }
+}
@@ -0,0 +1,80 @@
+//! Resources shared between tasks of different priorities.
+//!
+//! Same idea as the RTIC resources: every resource has a ceiling, the highest
+//! priority of the tasks using it. `lock` raises BASEPRI to the ceiling, so
+//! no other user can preempt the closure, while tasks above the ceiling keep
+//! running. A task at the ceiling itself doesn't mask anything at all.
+
+use core::cell::UnsafeCell;
+use cortex_m::register::{
+    basepri,
+    basepri_max,
+};
+use nrf52832_hal::pac::NVIC_PRIO_BITS;
+
+/// Highest logical priority, interrupts at it can't be masked by BASEPRI 0
+pub const MAX_PRIORITY: u8 = 1 << NVIC_PRIO_BITS;
+
+/// Value for `NVIC::set_priority` and BASEPRI of a logical priority,
+/// 1 is the lowest task priority, thread mode (idle) is 0
+pub const fn to_hw_priority(priority: u8) -> u8 {
+    (MAX_PRIORITY - priority) << (8 - NVIC_PRIO_BITS)
+}
+
+/// Value of type `T` behind a priority ceiling.
+///
+/// Only tasks with a priority up to `ceiling` may lock it,
+/// the task list in `app` keeps the ceilings right.
+pub struct Resource<T> {
+    value: UnsafeCell<Option<T>>,
+    ceiling: u8,
+}
+
+// access is serialised by the ceiling
+unsafe impl<T: Send> Sync for Resource<T> {}
+
+impl<T> Resource<T> {
+    pub const fn new(ceiling: u8) -> Self {
+        Resource {
+            value: UnsafeCell::new(None),
+            ceiling,
+        }
+    }
+
+    /// Puts the value in place, before the tasks using it are started
+    pub fn init(&self, value: T) {
+        self.lock_raw(|slot| *slot = Some(value));
+    }
+
+    /// Runs `f` on the value, tasks up to the ceiling wait until it's done.
+    ///
+    /// Panics if the resource wasn't initialised.
+    pub fn lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        self.lock_raw(|slot| f(slot.as_mut().expect("resource not initialised")))
+    }
+
+    /// Like `lock`, `None` if there is no value
+    pub fn try_lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+        self.lock_raw(|slot| slot.as_mut().map(f))
+    }
+
+    /// Puts `value` in place of the current one and gives that back,
+    /// for values which come and go while the tasks run
+    pub fn replace(&self, value: Option<T>) -> Option<T> {
+        self.lock_raw(|slot| core::mem::replace(slot, value))
+    }
+
+    fn lock_raw<R>(&self, f: impl FnOnce(&mut Option<T>) -> R) -> R {
+        if self.ceiling >= MAX_PRIORITY {
+            // BASEPRI can't mask the highest priority
+            return cortex_m::interrupt::free(|_| f(unsafe { &mut *self.value.get() }));
+        }
+
+        let previous = basepri::read();
+        // raises only, a nested lock with a lower ceiling keeps the higher one
+        basepri_max::write(to_hw_priority(self.ceiling));
+        let result = f(unsafe { &mut *self.value.get() });
+        unsafe { basepri::write(previous) };
+        result
+    }
+}
@@ -0,0 +1,176 @@
+//! Lock-free single producer, single consumer queue.
+//!
+//! Made for passing samples from an interrupt handler to the main loop:
+//! `split` gives a `Producer` for the handler and a `Consumer` for the loop,
+//! neither side blocks or disables interrupts. Only atomic loads and stores
+//! are used, so it works on cores without compare-and-swap as well.
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{
+    AtomicUsize,
+    Ordering,
+};
+
+/// Queue of up to `N - 1` values, one slot tells a full queue from an empty one
+pub struct SampleQueue<T, const N: usize> {
+    buffer: UnsafeCell<MaybeUninit<[T; N]>>,
+    /// Next slot to read, written by the consumer only
+    head: AtomicUsize,
+    /// Next slot to write, written by the producer only
+    tail: AtomicUsize,
+}
+
+// values are moved between the two sides, each slot is owned by one side at a time
+unsafe impl<T: Send, const N: usize> Sync for SampleQueue<T, N> {}
+
+impl<T: Copy, const N: usize> SampleQueue<T, N> {
+    pub const fn new() -> Self {
+        SampleQueue {
+            buffer: UnsafeCell::new(MaybeUninit::uninit()),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Splits the queue into its two ends, the borrow makes sure
+    /// there is only one of each
+    pub fn split(&mut self) -> (Producer<'_, T, N>, Consumer<'_, T, N>) {
+        let queue: &Self = self;
+        (Producer { queue }, Consumer { queue })
+    }
+
+    pub fn capacity(&self) -> usize {
+        N.saturating_sub(1)
+    }
+
+    pub fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        let tail = self.tail.load(Ordering::Acquire);
+        (tail + N - head) % N.max(1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn slot(&self, index: usize) -> *mut T {
+        // pointer to the element, no reference to the whole array is made
+        unsafe { (*self.buffer.get()).as_mut_ptr().cast::<T>().add(index) }
+    }
+}
+
+impl<T: Copy, const N: usize> Default for SampleQueue<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Writing end of `SampleQueue`
+pub struct Producer<'a, T, const N: usize> {
+    queue: &'a SampleQueue<T, N>,
+}
+
+impl<'a, T: Copy, const N: usize> Producer<'a, T, N> {
+    /// Adds the value, gives it back if the queue is full
+    pub fn enqueue(&mut self, value: T) -> Result<(), T> {
+        let tail = self.queue.tail.load(Ordering::Relaxed);
+        let next = (tail + 1) % N.max(1);
+        if N == 0 || next == self.queue.head.load(Ordering::Acquire) {
+            return Err(value);
+        }
+
+        unsafe { self.queue.slot(tail).write(value) };
+        // the value is in place before the consumer can see the slot
+        self.queue.tail.store(next, Ordering::Release);
+        Ok(())
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.queue.len() == self.queue.capacity()
+    }
+}
+
+/// Reading end of `SampleQueue`
+pub struct Consumer<'a, T, const N: usize> {
+    queue: &'a SampleQueue<T, N>,
+}
+
+impl<'a, T: Copy, const N: usize> Consumer<'a, T, N> {
+    /// Takes the oldest value
+    pub fn dequeue(&mut self) -> Option<T> {
+        let head = self.queue.head.load(Ordering::Relaxed);
+        if head == self.queue.tail.load(Ordering::Acquire) {
+            return None;
+        }
+
+        let value = unsafe { self.queue.slot(head).read() };
+        // the slot is read before the producer can reuse it
+        self.queue.head.store((head + 1) % N, Ordering::Release);
+        Some(value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+
+    #[test]
+    fn fifo_order_and_wrap_around() {
+        let mut queue = SampleQueue::<u32, 4>::new();
+        let (mut producer, mut consumer) = queue.split();
+
+        for round in 0..5 {
+            for i in 0..3 {
+                assert_eq!(producer.enqueue(round * 10 + i), Ok(()));
+            }
+            assert!(producer.is_full());
+            assert_eq!(producer.enqueue(99), Err(99));
+            assert_eq!(consumer.len(), 3);
+
+            for i in 0..3 {
+                assert_eq!(consumer.dequeue(), Some(round * 10 + i));
+            }
+            assert_eq!(consumer.dequeue(), None);
+        }
+    }
+
+    #[test]
+    fn threads_pass_every_value_once() {
+        const COUNT: u64 = 100_000;
+
+        let mut queue = SampleQueue::<u64, 16>::new();
+        let (mut producer, mut consumer) = queue.split();
+
+        std::thread::scope(|scope| {
+            scope.spawn(move || {
+                for value in 0..COUNT {
+                    while producer.enqueue(value).is_err() {
+                        std::thread::yield_now();
+                    }
+                }
+            });
+
+            let mut expected = 0;
+            while expected < COUNT {
+                match consumer.dequeue() {
+                    Some(value) => {
+                        assert_eq!(value, expected);
+                        expected += 1;
+                    }
+                    None => std::thread::yield_now(),
+                }
+            }
+        });
+    }
+}
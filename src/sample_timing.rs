@@ -0,0 +1,239 @@
+//! Capture time and sequence number of the acquired samples.
+//!
+//! The sampling side stamps every sample with the time it was captured at and
+//! a sequence number which goes up even when the sample is lost on the way.
+//! `TimingChecker` on the processing side tells from the stamps how many
+//! samples are missing and how late a sample came compared to the period.
+//! `BlockStamper` stamps samples which are read in blocks, when only the end
+//! of the block is timed.
+
+/// Stamp given to a sample when it's captured
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SampleStamp {
+    /// Number of the sample since the sampling started, wraps around
+    pub seq: u32,
+    /// Capture time in microseconds of the monotonic clock
+    pub time_us: u64,
+}
+
+/// What `TimingChecker` found out about a sample
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SampleTiming {
+    /// Samples missing right before this one
+    pub dropped: u32,
+    /// Time since the previous sample, 0 for the first one
+    pub interval_us: u32,
+    /// Time the sample came after the expected capture time,
+    /// 0 within the tolerance
+    pub late_us: u32,
+}
+impl SampleTiming {
+    /// Nothing is missing and the sample came in time
+    pub fn is_regular(&self) -> bool {
+        self.dropped == 0 && self.late_us == 0
+    }
+}
+
+/// Checks the stamps of samples taken every `sample_period_us`
+pub struct TimingChecker {
+    period_us: u32,
+    tolerance_us: u32,
+    last: Option<SampleStamp>,
+    dropped: u32,
+    late: u32,
+}
+
+impl TimingChecker {
+    /// Samples up to a quarter of the period away from the expected time are in time
+    pub fn new(sample_period_us: u32) -> Self {
+        Self::with_tolerance(sample_period_us, sample_period_us / 4)
+    }
+
+    pub fn with_tolerance(sample_period_us: u32, tolerance_us: u32) -> Self {
+        TimingChecker {
+            period_us: sample_period_us,
+            tolerance_us,
+            last: None,
+            dropped: 0,
+            late: 0,
+        }
+    }
+
+    /// Takes the stamp of the next received sample
+    pub fn check(&mut self, stamp: SampleStamp) -> SampleTiming {
+        let last = match self.last.replace(stamp) {
+            Some(last) => last,
+            None => return SampleTiming::default(),
+        };
+
+        // older or repeated numbers count as nothing missing
+        let dropped = match stamp.seq.wrapping_sub(last.seq) {
+            0 => 0,
+            step if step > u32::MAX / 2 => 0,
+            step => step - 1,
+        };
+        let interval_us = stamp.time_us.saturating_sub(last.time_us).min(u32::MAX as u64) as u32;
+        let expected_us = self.period_us.saturating_mul(dropped + 1);
+        let late_us = match interval_us.saturating_sub(expected_us) {
+            late if late > self.tolerance_us => late,
+            _ => 0,
+        };
+
+        self.dropped = self.dropped.saturating_add(dropped);
+        if late_us > 0 {
+            self.late += 1;
+        }
+
+        SampleTiming { dropped, interval_us, late_us }
+    }
+
+    /// Samples missing since the start
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    /// Samples which came late since the start
+    pub fn late(&self) -> u32 {
+        self.late
+    }
+
+    /// Stamp of the last sample
+    pub fn last(&self) -> Option<SampleStamp> {
+        self.last
+    }
+
+    /// Starts over, e.g. after the sampling was restarted
+    pub fn reset(&mut self) {
+        self.last = None;
+    }
+}
+
+/// Stamps for blocks of samples captured every `period_us`, of which
+/// only the time of the last one is known
+pub struct BlockStamper {
+    period_us: u32,
+    /// Number of the first sample of the next block
+    seq: u32,
+}
+
+impl BlockStamper {
+    pub fn new(period_us: u32) -> Self {
+        BlockStamper { period_us, seq: 0 }
+    }
+
+    /// Stamp of sample `index` of a block of `len`,
+    /// the last one was captured at `end_us`, the others a period apart
+    pub fn stamp(&self, index: usize, len: usize, end_us: u64) -> SampleStamp {
+        let before_end = (len - 1 - index) as u64;
+        SampleStamp {
+            seq: self.seq.wrapping_add(index as u32),
+            time_us: end_us.saturating_sub(before_end * self.period_us as u64),
+        }
+    }
+
+    /// The block of `len` samples is taken, the next one follows it
+    pub fn finish_block(&mut self, len: usize) {
+        self.seq = self.seq.wrapping_add(len as u32);
+    }
+
+    /// `count` samples were captured but are lost, e.g. the unfinished
+    /// block when the sampling pauses, their numbers are skipped
+    pub fn skip(&mut self, count: u32) {
+        self.seq = self.seq.wrapping_add(count);
+    }
+
+    /// Number of the first sample of the next block
+    pub fn next_seq(&self) -> u32 {
+        self.seq
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PERIOD_US: u32 = 12_500;
+
+    fn stamp(seq: u32, time_us: u64) -> SampleStamp {
+        SampleStamp { seq, time_us }
+    }
+
+    #[test]
+    fn regular_samples() {
+        let mut checker = TimingChecker::new(PERIOD_US);
+        assert_eq!(checker.check(stamp(0, 1_000)), SampleTiming::default());
+
+        for seq in 1..100 {
+            // a bit of jitter is fine
+            let jitter = if seq % 2 == 0 { 500 } else { 0 };
+            let timing = checker.check(stamp(seq, 1_000 + (seq * PERIOD_US) as u64 + jitter));
+            assert!(timing.is_regular(), "{:?}", timing);
+        }
+        assert_eq!((checker.dropped(), checker.late()), (0, 0));
+    }
+
+    #[test]
+    fn dropped_and_late_samples() {
+        let mut checker = TimingChecker::new(PERIOD_US);
+        checker.check(stamp(10, 0));
+
+        // two samples lost in the queue, the rest came in time
+        let timing = checker.check(stamp(13, 3 * PERIOD_US as u64));
+        assert_eq!(timing, SampleTiming { dropped: 2, interval_us: 3 * PERIOD_US, late_us: 0 });
+
+        // read 5 ms late, e.g. behind a long interrupt
+        let timing = checker.check(stamp(14, 4 * PERIOD_US as u64 + 5_000));
+        assert_eq!(timing.late_us, 5_000);
+        assert_eq!(timing.dropped, 0);
+
+        assert_eq!((checker.dropped(), checker.late()), (2, 1));
+    }
+
+    #[test]
+    fn sequence_wraps_around() {
+        let mut checker = TimingChecker::new(PERIOD_US);
+        checker.check(stamp(u32::MAX, 0));
+        assert_eq!(checker.check(stamp(0, PERIOD_US as u64)).dropped, 0);
+        assert_eq!(checker.check(stamp(2, 3 * PERIOD_US as u64)).dropped, 1);
+    }
+
+    #[test]
+    fn block_is_timed_back_from_its_end() {
+        let mut stamper = BlockStamper::new(PERIOD_US);
+        assert_eq!(stamper.stamp(3, 4, 1_000_000), stamp(3, 1_000_000));
+        assert_eq!(stamper.stamp(0, 4, 1_000_000), stamp(0, 1_000_000 - 3 * PERIOD_US as u64));
+
+        stamper.finish_block(4);
+        assert_eq!(stamper.stamp(0, 4, 1_050_000), stamp(4, 1_050_000 - 3 * PERIOD_US as u64));
+
+        // right after the clock started, no time before 0
+        assert_eq!(stamper.stamp(0, 16, 100).time_us, 0);
+        // a long block at a slow rate doesn't overflow
+        assert_eq!(BlockStamper::new(u32::MAX).stamp(0, 3, u64::MAX).time_us, u64::MAX - 2 * u32::MAX as u64);
+    }
+
+    #[test]
+    fn paused_samples_show_up_as_dropped() {
+        let mut stamper = BlockStamper::new(PERIOD_US);
+        let mut checker = TimingChecker::new(PERIOD_US);
+        let mut end_us = 4 * PERIOD_US as u64;
+        for i in 0..4 {
+            assert!(checker.check(stamper.stamp(i, 4, end_us)).is_regular());
+        }
+        stamper.finish_block(4);
+
+        // paused after 3 transfers of the next block, the sensor is set up
+        // again for the time of 5 periods, then a full block follows
+        stamper.skip(3);
+        end_us += (3 + 5 + 4) * PERIOD_US as u64;
+        assert_eq!(stamper.next_seq(), 7);
+        let timing = checker.check(stamper.stamp(0, 4, end_us));
+        assert_eq!(timing.dropped, 3);
+        // the periods of the pause itself make it late, not lost
+        assert_eq!(timing.late_us, 5 * PERIOD_US);
+        for i in 1..4 {
+            assert!(checker.check(stamper.stamp(i, 4, end_us)).is_regular());
+        }
+        assert_eq!(checker.dropped(), 3);
+    }
+}
@@ -0,0 +1,154 @@
+//! Sampling of the HRS3300 from a timer interrupt.
+//!
+//! TIMER2 fires every `ADCWaitTime`, the handler reads the sample through the
+//! AGC and puts it with its capture time and sequence number into a lock-free
+//! queue, then spawns the process task of `app`, which takes the samples from
+//! the queue at a lower priority. A slow display update doesn't shift the
+//! sampling anymore. The state is a `Resource` with the sampling priority as
+//! its ceiling, the I2C reads don't hold up the timer queue above it.
+
+use core::sync::atomic::{
+    AtomicU32,
+    Ordering,
+};
+use embedded_hal::timer::CountDown;
+use nrf52832_hal::{
+    pac::{
+        self,
+        interrupt,
+    },
+    timer::{
+        Timer,
+        Periodic,
+    },
+};
+use pt_hello::{
+    agc::{Agc, AgcSample},
+    hrs3300::BitsResolution,
+    sample_queue::{Producer, Consumer},
+    sample_timing::SampleStamp,
+};
+use crate::monotonic_nrf52::Instant;
+use crate::resource::Resource;
+use crate::{
+    app,
+    SensorType,
+    SensorErrorType,
+    SampleTimerType,
+};
+
+/// Samples the queue holds, 400 ms at 12.5 ms
+pub const QUEUE_LEN: usize = 32;
+pub type SampleProducer = Producer<'static, TimedSample, QUEUE_LEN>;
+pub type SampleConsumer = Consumer<'static, TimedSample, QUEUE_LEN>;
+
+/// Sample with the time it was read at, a gap in the sequence numbers
+/// is a lost sample
+#[derive(Clone, Copy, Debug)]
+pub struct TimedSample {
+    pub stamp: SampleStamp,
+    pub sample: AgcSample,
+}
+
+struct Sampler {
+    sensor: SensorType,
+    agc: Agc,
+    timer: Timer<SampleTimerType, Periodic>,
+    producer: SampleProducer,
+    /// Sequence number of the next sample, counts the failed reads too
+    seq: u32,
+    /// Last failed read, kept until the process task takes it
+    error: Option<SensorErrorType>,
+}
+
+static SAMPLER: Resource<Sampler> = Resource::new(app::SAMPLING_PRIORITY);
+/// Samples lost because the queue was full
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+/// Reads failed on the bus
+static BUS_ERRORS: AtomicU32 = AtomicU32::new(0);
+
+/// Starts sampling at the configured `ADCWaitTime`, the sensor is expected
+/// to be running already
+pub fn start(sensor: SensorType, timer: SampleTimerType, producer: SampleProducer) {
+    let period_us = sensor.get_adc_wait_time_us();
+
+    let mut timer = Timer::periodic(timer);
+    timer.enable_interrupt();
+    timer.start(period_us);
+
+    SAMPLER.replace(Some(Sampler {
+        sensor,
+        agc: Agc::new(BitsResolution::_14, BitsResolution::_16),
+        timer,
+        producer,
+        seq: 0,
+        error: None,
+    }));
+
+    unsafe { pac::NVIC::unmask(pac::Interrupt::TIMER2) };
+}
+
+/// Stops sampling, gives back the sensor and the timer
+pub fn stop() -> Option<(SensorType, SampleTimerType)> {
+    pac::NVIC::mask(pac::Interrupt::TIMER2);
+
+    let sampler = SAMPLER.replace(None)?;
+    let mut timer = sampler.timer;
+    timer.disable_interrupt();
+    // timer.cancel() is infallible
+    let _ = embedded_hal::timer::Cancel::cancel(&mut timer);
+
+    Some((sampler.sensor, timer.free()))
+}
+
+/// Runs `f` on the sensor between two samples, e.g. to set it up again
+/// after bus errors. The AGC starts over afterwards.
+pub fn with_sensor<R>(f: impl FnOnce(&mut SensorType) -> R) -> Option<R> {
+    SAMPLER.try_lock(|sampler| {
+        let result = f(&mut sampler.sensor);
+        sampler.agc.reset();
+        result
+    })
+}
+
+/// Error of the last failed read since the previous call
+pub fn take_error() -> Option<SensorErrorType> {
+    SAMPLER.try_lock(|sampler| sampler.error.take()).flatten()
+}
+
+pub fn dropped() -> u32 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+#[allow(unused)]
+pub fn bus_errors() -> u32 {
+    BUS_ERRORS.load(Ordering::Relaxed)
+}
+
+impl Sampler {
+    fn on_timer(&mut self) {
+        // clears the compare event, the timer is already counting the next period
+        let _ = self.timer.wait();
+        let stamp = SampleStamp { seq: self.seq, time_us: Instant::now().as_micros() };
+        self.seq = self.seq.wrapping_add(1);
+
+        match self.agc.read_sample(&mut self.sensor) {
+            Ok(sample) => {
+                let timed = TimedSample { stamp, sample };
+                if self.producer.enqueue(timed).is_err() {
+                    DROPPED.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            Err(e) => {
+                BUS_ERRORS.fetch_add(1, Ordering::Relaxed);
+                self.error = Some(e);
+            }
+        }
+        app::spawn(app::Task::Process);
+    }
+}
+
+#[interrupt]
+fn TIMER2() {
+    SAMPLER.try_lock(Sampler::on_timer);
+}
@@ -0,0 +1,571 @@
+//! Built-in screens, see `ui` for how they are shown.
+//!
+//! All screens have a title bar on top and a white background. Text lines
+//! are drawn with their background and padded to the width of the screen,
+//! so a line covers the longer text it replaces, and only the lines whose
+//! values changed are drawn again.
+
+use core::fmt::{self, Write};
+use embedded_graphics::{
+    fonts::{Font, Font12x16, Font24x32, Text},
+    prelude::*,
+    pixelcolor::Rgb565,
+    primitives::{Line, Rectangle},
+    style::{PrimitiveStyle, TextStyleBuilder},
+};
+
+use crate::input::InputEvent;
+use crate::plot::LCD_W;
+use crate::render::FrameStats;
+use crate::scroll::{VerticalScroll, RAM_LINES};
+use crate::scroll_chart::ScrollChart;
+use crate::strip_chart::StripChart;
+use crate::ui::{Screen, ScreenId, Status, Transition};
+use crate::wrist::WristState;
+
+const BACKGROUND_COLOR: Rgb565 = Rgb565::WHITE;
+const TEXT_COLOR:       Rgb565 = Rgb565::BLACK;
+const TITLE_COLOR:      Rgb565 = Rgb565::WHITE;
+const TITLE_BACKGROUND: Rgb565 = Rgb565::BLACK;
+const HIGHLIGHT_COLOR:  Rgb565 = Rgb565::RED;
+
+const MARGIN: i32 = 10;
+const TITLE_HEIGHT: i32 = 24;
+/// First text line under the title
+const TEXT_TOP: i32 = 40;
+const LINE_HEIGHT: i32 = 24;
+
+/// PPG chart of the waveform screen, full width
+const CHART_TOP: i32 = 40;
+const CHART_HEIGHT: u32 = 160;
+/// Samples per chart point, 6 s of the waveform at 80 Hz
+const CHART_DECIMATION: u32 = 2;
+
+/// Ways of drawing the chart of the waveform screen
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChartRendering {
+    /// Old trace over in the background color, then the new one
+    Lines,
+    /// A window per changed column
+    Columns,
+    /// Hardware scrolling, a line per point
+    Scrolling,
+}
+
+/// Text formatted on the stack, cut off at `N` bytes
+struct TextBuffer<const N: usize> {
+    bytes: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> TextBuffer<N> {
+    fn new() -> Self {
+        TextBuffer { bytes: [0; N], len: 0 }
+    }
+
+    fn as_str(&self) -> &str {
+        // only whole characters are copied in
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or("")
+    }
+}
+
+impl<const N: usize> Write for TextBuffer<N> {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        for c in text.chars() {
+            let len = c.len_utf8();
+            if self.len + len > N {
+                break;
+            }
+            c.encode_utf8(&mut self.bytes[self.len..self.len + len]);
+            self.len += len;
+        }
+        Ok(())
+    }
+}
+
+/// Background and title bar
+fn draw_frame<D: DrawTarget<Rgb565>>(display: &mut D, title: &str) -> Result<(), D::Error> {
+    Rectangle::new(Point::new(0, TITLE_HEIGHT), Point::new(LCD_W as i32 - 1, display.size().height as i32 - 1))
+        .into_styled(PrimitiveStyle::with_fill(BACKGROUND_COLOR))
+        .draw(display)?;
+    Rectangle::new(Point::new(0, 0), Point::new(LCD_W as i32 - 1, TITLE_HEIGHT - 1))
+        .into_styled(PrimitiveStyle::with_fill(TITLE_BACKGROUND))
+        .draw(display)?;
+
+    let style = TextStyleBuilder::new(Font12x16)
+        .text_color(TITLE_COLOR)
+        .background_color(TITLE_BACKGROUND)
+        .build();
+    Text::new(title, Point::new(MARGIN, 4)).into_styled(style).draw(display)?;
+    Ok(())
+}
+
+/// Line of text from the left margin to the right edge of the screen
+fn draw_text_line<D, F>(display: &mut D, font: F, y: i32, color: Rgb565, args: fmt::Arguments) -> Result<(), D::Error>
+where
+    D: DrawTarget<Rgb565>,
+    F: Font + Copy,
+{
+    let columns = (LCD_W as i32 - MARGIN) as usize / (F::CHARACTER_SIZE.width + F::CHARACTER_SPACING) as usize;
+    let mut text = TextBuffer::<40>::new();
+    let _ = text.write_fmt(args);
+    while text.len < columns.min(40) {
+        let _ = text.write_char(' ');
+    }
+
+    let style = TextStyleBuilder::new(font)
+        .text_color(color)
+        .background_color(BACKGROUND_COLOR)
+        .build();
+    Text::new(text.as_str(), Point::new(MARGIN, y)).into_styled(style).draw(display)?;
+    Ok(())
+}
+
+/// `true` if the part of the view `field` picks differs from what's shown
+fn differs<T, F: PartialEq>(shown: &Option<T>, latest: &T, field: impl Fn(&T) -> F) -> bool {
+    !matches!(shown, Some(shown) if field(shown) == field(latest))
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct HeartRateView {
+    bpm: u32,
+    rmssd_us: u32,
+    wrist: Option<WristState>,
+}
+
+/// Heart rate in large digits, the HRV and whether the watch is worn
+pub struct HeartRateScreen {
+    latest: HeartRateView,
+    shown: Option<HeartRateView>,
+}
+
+impl HeartRateScreen {
+    pub fn new() -> Self {
+        HeartRateScreen { latest: HeartRateView::default(), shown: None }
+    }
+}
+
+impl Default for HeartRateScreen {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<D: DrawTarget<Rgb565>> Screen<D> for HeartRateScreen {
+    fn enter(&mut self, display: &mut D) -> Result<(), D::Error> {
+        draw_frame(display, "Heart rate")?;
+        self.shown = None;
+        self.draw(display)
+    }
+
+    fn update(&mut self, status: &Status) -> bool {
+        self.latest = HeartRateView { bpm: status.bpm, rmssd_us: status.rmssd_us, wrist: status.wrist };
+        self.shown != Some(self.latest)
+    }
+
+    fn draw(&mut self, display: &mut D) -> Result<(), D::Error> {
+        let latest = self.latest;
+        if differs(&self.shown, &latest, |view| view.bpm) {
+            let y = TEXT_TOP + LINE_HEIGHT;
+            match latest.bpm {
+                0 => draw_text_line(display, Font24x32, y, HIGHLIGHT_COLOR, format_args!(" -- BPM")),
+                bpm => draw_text_line(display, Font24x32, y, HIGHLIGHT_COLOR, format_args!("{:>3} BPM", bpm)),
+            }?;
+        }
+        if differs(&self.shown, &latest, |view| view.rmssd_us) {
+            let y = TEXT_TOP + 4 * LINE_HEIGHT;
+            match latest.rmssd_us {
+                0 => draw_text_line(display, Font12x16, y, TEXT_COLOR, format_args!("RMSSD --")),
+                rmssd_us => draw_text_line(display, Font12x16, y, TEXT_COLOR, format_args!("RMSSD {} ms", rmssd_us / 1000)),
+            }?;
+        }
+        if differs(&self.shown, &latest, |view| view.wrist) {
+            let text = match latest.wrist {
+                Some(WristState::OnWrist) => "On wrist",
+                Some(WristState::OffWrist) => "Off wrist",
+                None => "Checking wrist",
+            };
+            draw_text_line(display, Font12x16, TEXT_TOP + 5 * LINE_HEIGHT, TEXT_COLOR, format_args!("{}", text))?;
+        }
+        self.shown = Some(latest);
+        Ok(())
+    }
+
+    fn handle(&mut self, event: InputEvent) -> Transition {
+        match event {
+            InputEvent::Press => Transition::Replace(ScreenId::Waveform),
+            InputEvent::LongPress => Transition::Push(ScreenId::Diagnostics),
+        }
+    }
+}
+
+/// Live PPG chart with the heart rate under it
+pub struct WaveformScreen {
+    rendering: ChartRendering,
+    chart: StripChart<{ LCD_W as usize }>,
+    /// Takes the samples instead of `chart` with `ChartRendering::Scrolling`
+    scroll_chart: Option<ScrollChart<{ CHART_HEIGHT as usize }>>,
+    bpm: u32,
+    shown_bpm: Option<u32>,
+}
+
+impl WaveformScreen {
+    pub fn new(rendering: ChartRendering) -> Self {
+        let scroll_chart = match rendering {
+            ChartRendering::Scrolling => ScrollChart::new(CHART_TOP as u16, 0, LCD_W as u32, CHART_DECIMATION)
+                .map(|chart| chart.with_colors(HIGHLIGHT_COLOR, BACKGROUND_COLOR)),
+            _ => None,
+        };
+        WaveformScreen {
+            rendering,
+            chart: StripChart::new(Point::new(0, CHART_TOP), CHART_HEIGHT, CHART_DECIMATION)
+                .with_colors(HIGHLIGHT_COLOR, BACKGROUND_COLOR),
+            scroll_chart,
+            bpm: 0,
+            shown_bpm: None,
+        }
+    }
+
+    fn chart_changed(&self) -> bool {
+        match self.scroll_chart.as_ref() {
+            Some(chart) => chart.has_pending(),
+            None => self.chart.is_changed(),
+        }
+    }
+}
+
+impl<D: VerticalScroll> Screen<D> for WaveformScreen {
+    fn enter(&mut self, display: &mut D) -> Result<(), D::Error> {
+        draw_frame(display, "Pulse wave")?;
+
+        let line_style = PrimitiveStyle::with_stroke(TEXT_COLOR, 1);
+        for y in &[CHART_TOP - 1, CHART_TOP + CHART_HEIGHT as i32] {
+            Line::new(Point::new(0, *y), Point::new(LCD_W as i32 - 1, *y))
+                .into_styled(line_style)
+                .draw(display)?;
+        }
+
+        // the points kept while hidden come back,
+        // the scrolling one only as far as they are still pending
+        match self.scroll_chart.as_mut() {
+            Some(chart) => chart.reset_area(),
+            None => self.chart.invalidate(),
+        }
+        self.shown_bpm = None;
+        self.draw(display)
+    }
+
+    fn exit(&mut self, display: &mut D) -> Result<(), D::Error> {
+        if self.scroll_chart.is_some() {
+            // the other screens draw on an unscrolled display
+            display.define_scroll_area(0, RAM_LINES, 0)?;
+            display.set_scroll_start(0)?;
+        }
+        Ok(())
+    }
+
+    fn update(&mut self, status: &Status) -> bool {
+        self.bpm = status.bpm;
+        self.chart_changed() || self.shown_bpm != Some(self.bpm)
+    }
+
+    fn push_sample(&mut self, value: i32) {
+        match self.scroll_chart.as_mut() {
+            Some(chart) => chart.push(value),
+            None => self.chart.push(value),
+        }
+    }
+
+    fn draw(&mut self, display: &mut D) -> Result<(), D::Error> {
+        match (self.rendering, self.scroll_chart.as_mut()) {
+            (_, Some(chart)) => chart.draw(display)?,
+            (ChartRendering::Lines, None) => self.chart.draw(display)?,
+            (_, None) => self.chart.draw_columns(display)?,
+        }
+
+        if self.shown_bpm != Some(self.bpm) {
+            let y = CHART_TOP + CHART_HEIGHT as i32 + 12;
+            match self.bpm {
+                0 => draw_text_line(display, Font12x16, y, TEXT_COLOR, format_args!("-- BPM")),
+                bpm => draw_text_line(display, Font12x16, y, TEXT_COLOR, format_args!("{} BPM", bpm)),
+            }?;
+            self.shown_bpm = Some(self.bpm);
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, event: InputEvent) -> Transition {
+        match event {
+            InputEvent::Press => Transition::Replace(ScreenId::Status),
+            InputEvent::LongPress => Transition::Push(ScreenId::Diagnostics),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct StatusView {
+    battery_voltage: u8,
+    charging: bool,
+    uptime_min: u32,
+}
+
+/// Battery and uptime
+pub struct StatusScreen {
+    latest: StatusView,
+    shown: Option<StatusView>,
+}
+
+impl StatusScreen {
+    pub fn new() -> Self {
+        StatusScreen { latest: StatusView::default(), shown: None }
+    }
+}
+
+impl Default for StatusScreen {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<D: DrawTarget<Rgb565>> Screen<D> for StatusScreen {
+    fn enter(&mut self, display: &mut D) -> Result<(), D::Error> {
+        draw_frame(display, "Status")?;
+        self.shown = None;
+        self.draw(display)
+    }
+
+    fn update(&mut self, status: &Status) -> bool {
+        self.latest = StatusView {
+            battery_voltage: status.battery_voltage,
+            charging: status.charging,
+            uptime_min: status.uptime_s / 60,
+        };
+        self.shown != Some(self.latest)
+    }
+
+    fn draw(&mut self, display: &mut D) -> Result<(), D::Error> {
+        let latest = self.latest;
+        if differs(&self.shown, &latest, |view| view.battery_voltage) {
+            let voltage = latest.battery_voltage;
+            draw_text_line(display, Font12x16, TEXT_TOP, TEXT_COLOR,
+                format_args!("Battery {}.{} V", voltage / 10, voltage % 10))?;
+        }
+        if differs(&self.shown, &latest, |view| view.charging) {
+            let text = if latest.charging { "Charging" } else { "On battery" };
+            draw_text_line(display, Font12x16, TEXT_TOP + LINE_HEIGHT, TEXT_COLOR, format_args!("{}", text))?;
+        }
+        if differs(&self.shown, &latest, |view| view.uptime_min) {
+            let minutes = latest.uptime_min;
+            draw_text_line(display, Font12x16, TEXT_TOP + 2 * LINE_HEIGHT, TEXT_COLOR,
+                format_args!("Uptime {}:{:02} h", minutes / 60, minutes % 60))?;
+        }
+        self.shown = Some(latest);
+        Ok(())
+    }
+
+    fn handle(&mut self, event: InputEvent) -> Transition {
+        match event {
+            InputEvent::Press => Transition::Replace(ScreenId::HeartRate),
+            InputEvent::LongPress => Transition::Push(ScreenId::Diagnostics),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct DiagnosticsView {
+    frames: FrameStats,
+    dropped: u32,
+    bus_errors: u32,
+}
+
+/// Frame times and the errors of the sampling, opened over the other screens
+pub struct DiagnosticsScreen {
+    latest: DiagnosticsView,
+    shown: Option<DiagnosticsView>,
+}
+
+impl DiagnosticsScreen {
+    pub fn new() -> Self {
+        DiagnosticsScreen { latest: DiagnosticsView::default(), shown: None }
+    }
+}
+
+impl Default for DiagnosticsScreen {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<D: DrawTarget<Rgb565>> Screen<D> for DiagnosticsScreen {
+    fn enter(&mut self, display: &mut D) -> Result<(), D::Error> {
+        draw_frame(display, "Diagnostics")?;
+        self.shown = None;
+        self.draw(display)
+    }
+
+    fn update(&mut self, status: &Status) -> bool {
+        self.latest = DiagnosticsView { frames: status.frames, dropped: status.dropped, bus_errors: status.bus_errors };
+        self.shown != Some(self.latest)
+    }
+
+    fn draw(&mut self, display: &mut D) -> Result<(), D::Error> {
+        let latest = self.latest;
+        if differs(&self.shown, &latest, |view| view.frames) {
+            let frames = latest.frames;
+            draw_text_line(display, Font12x16, TEXT_TOP, TEXT_COLOR, format_args!("Frames {}", frames.frames))?;
+            let y = TEXT_TOP + LINE_HEIGHT;
+            match frames.average_us() {
+                Some(average_us) => draw_text_line(display, Font12x16, y, TEXT_COLOR, format_args!("Avg {} us", average_us)),
+                None => draw_text_line(display, Font12x16, y, TEXT_COLOR, format_args!("Avg --")),
+            }?;
+            draw_text_line(display, Font12x16, TEXT_TOP + 2 * LINE_HEIGHT, TEXT_COLOR,
+                format_args!("Max {} us", frames.max_us))?;
+        }
+        if differs(&self.shown, &latest, |view| view.dropped) {
+            draw_text_line(display, Font12x16, TEXT_TOP + 3 * LINE_HEIGHT, TEXT_COLOR,
+                format_args!("Dropped {}", latest.dropped))?;
+        }
+        if differs(&self.shown, &latest, |view| view.bus_errors) {
+            draw_text_line(display, Font12x16, TEXT_TOP + 4 * LINE_HEIGHT, TEXT_COLOR,
+                format_args!("Bus errors {}", latest.bus_errors))?;
+        }
+        self.shown = Some(latest);
+        Ok(())
+    }
+
+    fn handle(&mut self, _event: InputEvent) -> Transition {
+        Transition::Pop
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framebuffer::{
+        LcdFrameBuffer,
+        snapshot::assert_snapshot,
+    };
+    use crate::plot::sin;
+    use crate::ui::Ui;
+
+    fn status() -> Status {
+        let mut frames = FrameStats::new();
+        frames.record(3_000);
+        frames.record(5_000);
+        Status {
+            bpm: 72,
+            rmssd_us: 42_000,
+            wrist: Some(WristState::OnWrist),
+            battery_voltage: 39,
+            charging: true,
+            uptime_s: 3_723,
+            frames,
+            dropped: 3,
+            bus_errors: 0,
+        }
+    }
+
+    fn screen(id: ScreenId) -> Ui<LcdFrameBuffer> {
+        rendered(id, ChartRendering::Columns)
+    }
+
+    fn rendered(id: ScreenId, rendering: ChartRendering) -> Ui<LcdFrameBuffer> {
+        let mut ui = Ui::new(LcdFrameBuffer::new(), rendering);
+        ui.update(&status()).unwrap();
+        ui.navigate(Transition::Replace(id)).unwrap();
+        ui
+    }
+
+    /// About 10 samples per radian, the series of `sin` only holds up to pi
+    fn wave(x: i32, amplitude: f32) -> i32 {
+        sin(x % 63 - 31, 10_f32, amplitude)
+    }
+
+    /// Pulses, then larger ones, the scale follows and no old trace is left
+    fn rescaled(rendering: ChartRendering) -> Ui<LcdFrameBuffer> {
+        let mut ui = rendered(ScreenId::Waveform, rendering);
+        for x in 0..300 {
+            ui.push_sample(wave(x, 100_f32));
+        }
+        ui.update(&status()).unwrap();
+        for x in 300..320 {
+            ui.push_sample(wave(x, 200_f32));
+        }
+        ui.update(&status()).unwrap();
+        ui
+    }
+
+    #[test]
+    fn text_is_cut_off() {
+        let mut text = TextBuffer::<4>::new();
+        write!(text, "{}°C", 21).unwrap();
+        assert_eq!(text.as_str(), "21°");
+        write!(text, "x").unwrap();
+        assert_eq!(text.as_str(), "21°");
+    }
+
+    #[test]
+    fn heart_rate_screen() {
+        let ui = screen(ScreenId::HeartRate);
+        assert_snapshot("screen_heart_rate", &ui.display);
+    }
+
+    #[test]
+    fn changed_value_replaces_longer_text() {
+        let mut ui = screen(ScreenId::HeartRate);
+        ui.update(&Status { bpm: 0, rmssd_us: 1_000, ..status() }).unwrap();
+        ui.update(&Status { bpm: 72, ..status() }).unwrap();
+
+        let fresh = screen(ScreenId::HeartRate);
+        for y in 0..240 {
+            assert!(ui.display.row(y).iter().eq(fresh.display.row(y).iter()), "row {}", y);
+        }
+    }
+
+    #[test]
+    fn waveform_screen() {
+        let mut ui = screen(ScreenId::Waveform);
+        for x in 0..300 {
+            ui.push_sample(wave(x, 100_f32));
+        }
+        ui.update(&status()).unwrap();
+        assert_snapshot("screen_waveform", &ui.display);
+    }
+
+    #[test]
+    fn waveform_lines_rescaled() {
+        let ui = rescaled(ChartRendering::Lines);
+        assert_snapshot("screen_waveform_lines", &ui.display);
+    }
+
+    #[test]
+    fn waveform_columns_rescaled() {
+        let ui = rescaled(ChartRendering::Columns);
+        assert_snapshot("screen_waveform_columns", &ui.display);
+    }
+
+    #[test]
+    fn waveform_scrolling() {
+        let mut ui = rendered(ScreenId::Waveform, ChartRendering::Scrolling);
+        // more than the area holds, so it has gone round
+        for frame in 0..12 {
+            for x in frame * 35..(frame + 1) * 35 {
+                ui.push_sample(wave(x, 100_f32));
+            }
+            ui.update(&status()).unwrap();
+        }
+        assert_snapshot("screen_waveform_scrolling", &ui.display);
+    }
+
+    #[test]
+    fn status_screen() {
+        let ui = screen(ScreenId::Status);
+        assert_snapshot("screen_status", &ui.display);
+    }
+
+    #[test]
+    fn diagnostics_screen() {
+        let ui = screen(ScreenId::Diagnostics);
+        assert_snapshot("screen_diagnostics", &ui.display);
+    }
+}
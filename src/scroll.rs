@@ -0,0 +1,153 @@
+//! Vertical scrolling of the ST7789.
+//!
+//! The controller has RAM for 320 lines, the PineTime panel shows the first
+//! 240 of them. A scrolling area is a band of lines which the panel shows
+//! rotated by a start line, moving everything in it by changing a single
+//! register. The definition (VSCRDEF) splits all 320 RAM lines into a fixed
+//! top part, the scrolling part and a fixed bottom part, so the 80 lines
+//! under the screen always go with the bottom part, even when the area ends
+//! above the bottom of the screen.
+//!
+//! The lines scroll along the panel, from the bottom to the top of the
+//! screen, whatever the orientation of the drawing is.
+
+use crate::plot::LCD_H;
+use crate::render::WindowWrite;
+use crate::framebuffer::FrameBuffer;
+
+/// Lines in the RAM of the controller
+pub const RAM_LINES: u16 = 320;
+
+/// Display which moves a band of lines by itself
+pub trait VerticalScroll: WindowWrite {
+    /// Vertical scrolling definition (VSCRDEF),
+    /// the three parts add up to the `RAM_LINES`
+    fn define_scroll_area(&mut self, top_fixed: u16, scroll_lines: u16, bottom_fixed: u16) -> Result<(), Self::Error>;
+
+    /// Vertical scroll start address (VSCSAD),
+    /// the RAM line shown at the top of the scrolling area
+    fn set_scroll_start(&mut self, line: u16) -> Result<(), Self::Error>;
+}
+
+/// Band of screen lines `top..top + height` which scrolls
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScrollArea {
+    top: u16,
+    height: u16,
+}
+
+impl ScrollArea {
+    /// `None` if the band isn't on the screen
+    pub fn new(top: u16, height: u16) -> Option<Self> {
+        if height == 0 || top.checked_add(height)? > LCD_H {
+            return None;
+        }
+        Some(ScrollArea { top, height })
+    }
+
+    pub fn top(&self) -> u16 {
+        self.top
+    }
+
+    pub fn height(&self) -> u16 {
+        self.height
+    }
+
+    /// Fixed lines under the area, including the RAM lines under the screen
+    pub fn bottom_fixed(&self) -> u16 {
+        RAM_LINES - self.top - self.height
+    }
+
+    /// RAM line shown at the top of the area when it's scrolled up by `offset` lines
+    pub fn start_line(&self, offset: u16) -> u16 {
+        self.top + offset % self.height
+    }
+
+    /// Row to draw at to show up `line` lines below the top of the area
+    /// scrolled up by `offset` lines
+    pub fn row_at(&self, offset: u16, line: u16) -> u16 {
+        let line = ((offset % self.height) as u32 + line as u32) % self.height as u32;
+        self.top + line as u16
+    }
+
+    /// Makes the band scroll, starting unscrolled
+    pub fn define<D: VerticalScroll>(&self, display: &mut D) -> Result<(), D::Error> {
+        display.define_scroll_area(self.top, self.height, self.bottom_fixed())?;
+        display.set_scroll_start(self.start_line(0))
+    }
+
+    pub fn scroll<D: VerticalScroll>(&self, display: &mut D, offset: u16) -> Result<(), D::Error> {
+        display.set_scroll_start(self.start_line(offset))
+    }
+}
+
+/// Scrolls the rows it shows, like the panel does
+impl<const W: usize, const H: usize> VerticalScroll for FrameBuffer<W, H> {
+    fn define_scroll_area(&mut self, top_fixed: u16, scroll_lines: u16, bottom_fixed: u16) -> Result<(), Self::Error> {
+        debug_assert_eq!(top_fixed + scroll_lines + bottom_fixed, RAM_LINES);
+        self.set_scroll(top_fixed as usize, scroll_lines as usize, top_fixed as usize);
+        Ok(())
+    }
+
+    fn set_scroll_start(&mut self, line: u16) -> Result<(), Self::Error> {
+        self.set_scroll_start_line(line as usize);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_graphics::{
+        prelude::*,
+        pixelcolor::Rgb565,
+    };
+
+    #[test]
+    fn area_covers_whole_ram() {
+        let area = ScrollArea::new(40, 160).unwrap();
+        // 80 lines under the screen go with the bottom
+        assert_eq!(area.bottom_fixed(), 40 + 80);
+        assert_eq!(ScrollArea::new(0, 240).unwrap().bottom_fixed(), 80);
+
+        assert_eq!(ScrollArea::new(100, 141), None);
+        assert_eq!(ScrollArea::new(0, 0), None);
+    }
+
+    #[test]
+    fn start_and_rows_wrap() {
+        let area = ScrollArea::new(40, 160).unwrap();
+        assert_eq!(area.start_line(0), 40);
+        assert_eq!(area.start_line(159), 199);
+        assert_eq!(area.start_line(160), 40);
+
+        // the line at the top scrolls to the bottom after one more line
+        assert_eq!(area.row_at(10, 0), 50);
+        assert_eq!(area.row_at(11, 159), 50);
+        assert_eq!(area.row_at(u16::MAX, 159), area.row_at(u16::MAX % 160 + 159, 0));
+    }
+
+    #[test]
+    fn frame_buffer_scrolls() {
+        let mut buffer = FrameBuffer::<2, 8>::new();
+        for y in 0..8 {
+            buffer.draw_pixel(Pixel(Point::new(0, y), Rgb565::new(y as u8, 0, 0))).unwrap();
+        }
+        let shown = |buffer: &FrameBuffer<2, 8>| {
+            let mut rows = [0; 8];
+            for (y, row) in rows.iter_mut().enumerate() {
+                *row = buffer.get_pixel(Point::new(0, y as i32)).unwrap().r();
+            }
+            rows
+        };
+
+        let area = ScrollArea::new(2, 4).unwrap();
+        area.define(&mut buffer).unwrap();
+        assert_eq!(shown(&buffer), [0, 1, 2, 3, 4, 5, 6, 7]);
+
+        // lines 2..6 move up by one, the first comes back at the bottom
+        area.scroll(&mut buffer, 1).unwrap();
+        assert_eq!(shown(&buffer), [0, 1, 3, 4, 5, 2, 6, 7]);
+        assert_eq!(area.row_at(1, 3), 2);
+    }
+}
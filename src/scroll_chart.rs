@@ -0,0 +1,266 @@
+//! PPG chart moved by the hardware scrolling of the display.
+//!
+//! Time runs along the scrolling area, from the bottom to the top of the
+//! screen, the value goes across. Every point paints a single line, the one
+//! leaving the top of the area, and the area is scrolled up so the line comes
+//! back in at the bottom. Only the part of the line with the old and the new
+//! trace is written, nothing else of the chart is ever redrawn.
+//!
+//! Unlike `StripChart` the scale applies to the new points only, the lines on
+//! the screen keep the scale they were drawn with.
+
+use embedded_graphics::{
+    prelude::*,
+    pixelcolor::Rgb565,
+};
+
+use crate::scroll::{ScrollArea, VerticalScroll};
+use crate::strip_chart::MIN_SPAN;
+
+/// Chart of `N` lines, the height of the scrolling area
+pub struct ScrollChart<const N: usize> {
+    area: ScrollArea,
+    left: i32,
+    width: u32,
+    trace_color: Rgb565,
+    background_color: Rgb565,
+    /// Latest points, ring buffer, `head` is the oldest, for the scale
+    values: [i32; N],
+    head: usize,
+    len: usize,
+    /// Points not drawn yet, the newest of `values`
+    pending: usize,
+    /// Samples averaged into one point
+    decimation: u32,
+    sum: i64,
+    summed: u32,
+    /// Columns the trace covers in each line of the area, by RAM row
+    spans: [Option<(i32, i32)>; N],
+    last_column: Option<i32>,
+    /// Lines the area is scrolled up by, `None` before it's set up
+    offset: Option<u16>,
+}
+
+impl<const N: usize> ScrollChart<N> {
+    /// Chart over the screen lines `top..top + N`, `width` pixels wide
+    /// from `left`, every point is the average of `decimation` samples.
+    ///
+    /// `None` if the lines aren't on the screen.
+    pub fn new(top: u16, left: i32, width: u32, decimation: u32) -> Option<Self> {
+        Some(ScrollChart {
+            area: ScrollArea::new(top, N as u16)?,
+            left,
+            width: width.max(1),
+            trace_color: Rgb565::RED,
+            background_color: Rgb565::WHITE,
+            values: [0; N],
+            head: 0,
+            len: 0,
+            pending: 0,
+            decimation: decimation.max(1),
+            sum: 0,
+            summed: 0,
+            spans: [None; N],
+            last_column: None,
+            offset: None,
+        })
+    }
+
+    pub fn with_colors(mut self, trace: Rgb565, background: Rgb565) -> Self {
+        self.trace_color = trace;
+        self.background_color = background;
+        self
+    }
+
+    pub fn area(&self) -> ScrollArea {
+        self.area
+    }
+
+    /// Takes the next sample, adds a point every `decimation` samples
+    pub fn push(&mut self, value: i32) {
+        self.sum += value as i64;
+        self.summed += 1;
+        if self.summed < self.decimation {
+            return;
+        }
+
+        let point = (self.sum / self.summed as i64) as i32;
+        self.sum = 0;
+        self.summed = 0;
+
+        if N == 0 {
+            return;
+        }
+        if self.len < N {
+            self.values[(self.head + self.len) % N] = point;
+            self.len += 1;
+        } else {
+            self.values[self.head] = point;
+            self.head = (self.head + 1) % N;
+        }
+        self.pending = (self.pending + 1).min(N);
+    }
+
+    /// Forgets the points, the trace on the screen scrolls off
+    /// as the new points come in
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.head = 0;
+        self.pending = 0;
+        self.sum = 0;
+        self.summed = 0;
+        self.last_column = None;
+    }
+
+    /// Points waiting for the next `draw`
+    pub fn has_pending(&self) -> bool {
+        self.pending > 0
+    }
+
+    /// Lowest and highest of the latest points, as in `StripChart::range`
+    pub fn range(&self) -> Option<(i32, i32)> {
+        let (min, max) = (0..self.len)
+            .map(|i| self.values[(self.head + i) % N])
+            .fold(None, |range, value| match range {
+                None => Some((value, value)),
+                Some((min, max)) => Some((min.min(value), max.max(value))),
+            })?;
+
+        let missing = (MIN_SPAN - (max - min)).max(0);
+        Some((min - missing / 2, max + (missing - missing / 2)))
+    }
+
+    /// Column of the screen `value` is drawn at, the highest value on the right
+    pub fn to_column(&self, value: i32, (min, max): (i32, i32)) -> i32 {
+        let span = (max - min).max(1) as i64;
+        let from_left = (value as i64 - min as i64).max(0).min(span);
+        self.left + (from_left * (self.width - 1) as i64 / span) as i32
+    }
+
+    /// Paints a line per new point and scrolls them in,
+    /// sets the scrolling area up the first time
+    pub fn draw<D: VerticalScroll>(&mut self, target: &mut D) -> Result<(), D::Error> {
+        let mut offset = match self.offset {
+            Some(offset) => offset,
+            None => {
+                self.area.define(target)?;
+                0
+            }
+        };
+        self.offset = Some(offset);
+
+        if self.pending == 0 {
+            return Ok(());
+        }
+        let range = match self.range() {
+            Some(range) => range,
+            None => return Ok(()),
+        };
+
+        for i in self.len - self.pending..self.len {
+            let column = self.to_column(self.values[(self.head + i) % N], range);
+            let previous = self.last_column.unwrap_or(column);
+            let new_span = (previous.min(column), previous.max(column));
+            self.last_column = Some(column);
+
+            // the line at the top goes to the bottom with the next scroll
+            let row = self.area.row_at(offset, 0);
+            let line = (row - self.area.top()) as usize;
+            let (from, to) = match self.spans[line] {
+                Some(old) => (old.0.min(new_span.0), old.1.max(new_span.1)),
+                None => new_span,
+            };
+            self.spans[line] = Some(new_span);
+
+            let (trace, background) = (self.trace_color, self.background_color);
+            let colors = (from..=to).map(|x| if (new_span.0..=new_span.1).contains(&x) { trace } else { background });
+            target.write_window(Point::new(from, row as i32), Point::new(to, row as i32), colors)?;
+
+            offset = (offset + 1) % N as u16;
+        }
+        self.pending = 0;
+
+        self.area.scroll(target, offset)?;
+        self.offset = Some(offset);
+        Ok(())
+    }
+
+    /// Lets `draw` set the area up again, e.g. after the display was reset
+    pub fn reset_area(&mut self) {
+        self.offset = None;
+        self.spans = [None; N];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framebuffer::FrameBuffer;
+
+    const LINES: usize = 12;
+    type Screen = FrameBuffer<24, 20>;
+
+    fn chart() -> ScrollChart<LINES> {
+        ScrollChart::new(4, 2, 20, 1).unwrap()
+    }
+
+    /// Trace column in each screen line of the area
+    fn trace(screen: &Screen) -> [Option<i32>; LINES] {
+        let mut columns = [None; LINES];
+        for (line, column) in columns.iter_mut().enumerate() {
+            *column = (0..24).find(|x| screen.get_pixel(Point::new(*x, 4 + line as i32)) == Some(Rgb565::RED));
+        }
+        columns
+    }
+
+    #[test]
+    fn new_points_come_in_at_the_bottom() {
+        let mut chart = chart();
+        let mut screen = Screen::new();
+        chart.push(0);
+        chart.push(1_000);
+        chart.draw(&mut screen).unwrap();
+
+        // two lines painted, scrolled up to the bottom of the area
+        let columns = trace(&screen);
+        assert!(columns[..LINES - 2].iter().all(Option::is_none), "{:?}", columns);
+        assert_eq!(columns[LINES - 2], Some(2));
+        // joined to the previous point
+        assert_eq!(columns[LINES - 1], Some(2));
+        assert_eq!(screen.get_pixel(Point::new(21, 4 + LINES as i32 - 1)), Some(Rgb565::RED));
+        assert_eq!(screen.windows(), 2);
+
+        // nothing new, nothing written
+        screen.reset_counts();
+        chart.draw(&mut screen).unwrap();
+        assert_eq!(screen.windows(), 0);
+    }
+
+    #[test]
+    fn old_trace_is_erased_when_lines_come_back() {
+        let mut chart = chart();
+        let mut screen = Screen::new();
+        for i in 0..LINES as i32 {
+            chart.push(if i % 2 == 0 { 0 } else { 1_000 });
+        }
+        chart.draw(&mut screen).unwrap();
+
+        // a flat line over the whole area, the zigzag and the step to it are gone
+        chart.push(500);
+        chart.draw(&mut screen).unwrap();
+        for _ in 0..LINES {
+            chart.push(500);
+        }
+        chart.draw(&mut screen).unwrap();
+        for line in 0..LINES as i32 {
+            let red = (0..24).filter(|x| screen.get_pixel(Point::new(*x, 4 + line)) == Some(Rgb565::RED)).count();
+            assert_eq!(red, 1, "line {}", line);
+        }
+
+        // one line at a time, a single window each
+        screen.reset_counts();
+        chart.push(500);
+        chart.draw(&mut screen).unwrap();
+        assert_eq!((screen.windows(), screen.pixels()), (1, 1));
+    }
+}
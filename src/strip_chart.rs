@@ -0,0 +1,431 @@
+//! Scrolling strip chart of the PPG waveform.
+//!
+//! Values come in at the right edge, the trace moves left by a column per
+//! point and the oldest point drops off at the left. The Y axis is scaled
+//! to the lowest and highest value on the chart, so the pulse fills the
+//! height whatever its amplitude. A refresh draws the previous trace over in
+//! the background color and then the new one, nothing else is touched.
+//!
+//! `draw_columns` draws the same trace as vertical runs, one window per
+//! column which changed, erasing and drawing in the same write. A chart is
+//! drawn either with `draw` or with `draw_columns`, one can't erase the
+//! trace of the other.
+
+use embedded_graphics::{
+    prelude::*,
+    pixelcolor::Rgb565,
+    primitives::Line,
+    style::PrimitiveStyle,
+};
+
+use crate::render::WindowWrite;
+
+/// Smallest range of values shown over the full height,
+/// a flat signal doesn't blow the noise up
+pub const MIN_SPAN: i32 = 64;
+
+/// Chart of `N` points, one pixel column each
+pub struct StripChart<const N: usize> {
+    top_left: Point,
+    height: u32,
+    trace_color: Rgb565,
+    background_color: Rgb565,
+    /// Points on the chart, ring buffer, `head` is the oldest
+    values: [i32; N],
+    head: usize,
+    len: usize,
+    /// Samples averaged into one point
+    decimation: u32,
+    sum: i64,
+    summed: u32,
+    /// Rows of the trace on the screen, `drawn_len` columns from the right
+    drawn: [i32; N],
+    drawn_len: usize,
+    changed: bool,
+}
+
+impl<const N: usize> StripChart<N> {
+    /// Chart `N` pixels wide and `height` pixels high at `top_left`,
+    /// every point is the average of `decimation` samples
+    pub fn new(top_left: Point, height: u32, decimation: u32) -> Self {
+        StripChart {
+            top_left,
+            height: height.max(1),
+            trace_color: Rgb565::RED,
+            background_color: Rgb565::WHITE,
+            values: [0; N],
+            head: 0,
+            len: 0,
+            decimation: decimation.max(1),
+            sum: 0,
+            summed: 0,
+            drawn: [0; N],
+            drawn_len: 0,
+            changed: false,
+        }
+    }
+
+    pub fn with_colors(mut self, trace: Rgb565, background: Rgb565) -> Self {
+        self.trace_color = trace;
+        self.background_color = background;
+        self
+    }
+
+    /// Takes the next sample, adds a point every `decimation` samples
+    pub fn push(&mut self, value: i32) {
+        self.sum += value as i64;
+        self.summed += 1;
+        if self.summed < self.decimation {
+            return;
+        }
+
+        let point = (self.sum / self.summed as i64) as i32;
+        self.sum = 0;
+        self.summed = 0;
+
+        if N == 0 {
+            return;
+        }
+        if self.len < N {
+            self.values[(self.head + self.len) % N] = point;
+            self.len += 1;
+        } else {
+            // full, the oldest point scrolls off
+            self.values[self.head] = point;
+            self.head = (self.head + 1) % N;
+        }
+        self.changed = true;
+    }
+
+    /// Empties the chart, e.g. after a gap in the samples,
+    /// the next `draw` erases the trace
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.head = 0;
+        self.sum = 0;
+        self.summed = 0;
+        self.changed = true;
+    }
+
+    /// The screen under the chart was drawn over, the next draw
+    /// puts the whole trace back without erasing anything
+    pub fn invalidate(&mut self) {
+        self.drawn_len = 0;
+        self.changed = true;
+    }
+
+    /// Points came in or went since the last draw
+    pub fn is_changed(&self) -> bool {
+        self.changed
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Points on the chart, oldest first
+    pub fn values(&self) -> impl Iterator<Item = i32> + '_ {
+        (0..self.len).map(move |i| self.values[(self.head + i) % N])
+    }
+
+    /// Lowest and highest value shown, `None` for an empty chart
+    pub fn range(&self) -> Option<(i32, i32)> {
+        let (min, max) = self.values().fold(None, |range, value| match range {
+            None => Some((value, value)),
+            Some((min, max)) => Some((min.min(value), max.max(value))),
+        })?;
+
+        // widened around the middle up to the smallest span
+        let missing = (MIN_SPAN - (max - min)).max(0);
+        Some((min - missing / 2, max + (missing - missing / 2)))
+    }
+
+    /// Row of the screen `value` is drawn at, the highest value is on top
+    pub fn to_row(&self, value: i32, (min, max): (i32, i32)) -> i32 {
+        let span = (max - min).max(1) as i64;
+        let from_top = (max as i64 - value as i64).max(0).min(span);
+        self.top_left.y + (from_top * (self.height - 1) as i64 / span) as i32
+    }
+
+    /// Replaces the trace on the screen, does nothing if there is no new point
+    pub fn draw<D: DrawTarget<Rgb565>>(&mut self, target: &mut D) -> Result<(), D::Error> {
+        if !self.changed {
+            return Ok(());
+        }
+
+        let erase = PrimitiveStyle::with_stroke(self.background_color, 1);
+        self.draw_trace(target, erase)?;
+
+        self.update_rows();
+
+        let trace = PrimitiveStyle::with_stroke(self.trace_color, 1);
+        self.draw_trace(target, trace)?;
+
+        self.changed = false;
+        Ok(())
+    }
+
+    /// Replaces the trace column by column, only the columns which changed
+    /// are written, each in a single window with the erased rows in it
+    pub fn draw_columns<D: WindowWrite>(&mut self, target: &mut D) -> Result<(), D::Error> {
+        if !self.changed {
+            return Ok(());
+        }
+
+        let (old, old_len) = (self.drawn, self.drawn_len);
+        self.update_rows();
+
+        for column in 0..N {
+            let old_span = Self::span(&old, old_len, column);
+            let new_span = Self::span(&self.drawn, self.drawn_len, column);
+            let (top, bottom) = match (old_span, new_span) {
+                (old, new) if old == new => continue,
+                (Some(old), Some(new)) => (old.0.min(new.0), old.1.max(new.1)),
+                (Some(span), None) | (None, Some(span)) => span,
+                (None, None) => continue,
+            };
+
+            let (trace, background) = (self.trace_color, self.background_color);
+            let colors = (top..=bottom).map(|row| match new_span {
+                Some((from, to)) if (from..=to).contains(&row) => trace,
+                _ => background,
+            });
+            let x = self.top_left.x + column as i32;
+            target.write_window(Point::new(x, top), Point::new(x, bottom), colors)?;
+        }
+
+        self.changed = false;
+        Ok(())
+    }
+
+    /// Rows of the points on the screen, for the values on the chart now
+    fn update_rows(&mut self) {
+        self.drawn_len = 0;
+        if let Some(range) = self.range() {
+            for i in 0..self.len {
+                self.drawn[i] = self.to_row(self.values[(self.head + i) % N], range);
+            }
+            self.drawn_len = self.len;
+        }
+    }
+
+    /// Rows the trace covers in `column`, from the previous point to this one
+    fn span(rows: &[i32; N], len: usize, column: usize) -> Option<(i32, i32)> {
+        // points are right aligned
+        let i = column.checked_sub(N - len)?;
+        let row = rows[i];
+        let previous = if i > 0 { rows[i - 1] } else { row };
+        Some((row.min(previous), row.max(previous)))
+    }
+
+    /// Lines between the points in `drawn`, the newest one at the right edge
+    fn draw_trace<D: DrawTarget<Rgb565>>(&self, target: &mut D, style: PrimitiveStyle<Rgb565>) -> Result<(), D::Error> {
+        let left = self.top_left.x + (N - self.drawn_len) as i32;
+        let points = self.drawn[..self.drawn_len]
+            .iter()
+            .enumerate()
+            .map(|(i, row)| Point::new(left + i as i32, *row));
+
+        let mut previous = None;
+        for point in points {
+            Line::new(previous.unwrap_or(point), point)
+                .into_styled(style)
+                .draw(target)?;
+            previous = Some(point);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_graphics::mock_display::MockDisplay;
+    use crate::framebuffer::FrameBuffer;
+
+    const HEIGHT: u32 = 33;
+
+    fn chart() -> StripChart<16> {
+        StripChart::new(Point::new(2, 4), HEIGHT, 1)
+    }
+
+    fn trace_columns(display: &MockDisplay<Rgb565>) -> usize {
+        (0..64)
+            .filter(|x| (0..64).any(|y| display.get_pixel(Point::new(*x, y)) == Some(Rgb565::RED)))
+            .count()
+    }
+
+    #[test]
+    fn scrolls_and_keeps_newest() {
+        let mut chart = chart();
+        for value in 0..20 {
+            chart.push(value * 100);
+        }
+        assert_eq!(chart.len(), 16);
+        assert_eq!(chart.values().next(), Some(400));
+        assert_eq!(chart.values().last(), Some(1_900));
+    }
+
+    #[test]
+    fn decimates_by_averaging() {
+        let mut chart = StripChart::<16>::new(Point::zero(), HEIGHT, 4);
+        for value in &[0, 4, 8, 12, 100] {
+            chart.push(*value);
+        }
+        assert!(chart.values().eq([6].iter().copied()));
+    }
+
+    #[test]
+    fn scales_to_window() {
+        let mut chart = chart();
+        // small pulse around a large level
+        for i in 0..16 {
+            chart.push(50_000 + if i % 2 == 0 { -500 } else { 500 });
+        }
+        let range = chart.range().unwrap();
+        assert_eq!(range, (49_500, 50_500));
+        assert_eq!(chart.to_row(50_500, range), 4);
+        assert_eq!(chart.to_row(49_500, range), 4 + HEIGHT as i32 - 1);
+
+        // a flat line stays in the middle instead of filling the height
+        chart.clear();
+        chart.push(7);
+        chart.push(8);
+        let range = chart.range().unwrap();
+        assert_eq!(range.1 - range.0, MIN_SPAN);
+        let row = chart.to_row(8, range);
+        assert!((row - (4 + HEIGHT as i32 / 2)).abs() <= 1, "{}", row);
+    }
+
+    #[test]
+    fn draws_inside_area_right_aligned() {
+        let mut chart = chart();
+        for i in 0..5 {
+            chart.push(i * 100);
+        }
+        let mut display = MockDisplay::new();
+        chart.draw(&mut display).unwrap();
+
+        // 5 points at the right edge of the 16 columns starting at x 2
+        assert_eq!(trace_columns(&display), 5);
+        assert_eq!(display.get_pixel(Point::new(17, 4)), Some(Rgb565::RED));
+        for x in 0..64 {
+            for y in 0..64 {
+                if display.get_pixel(Point::new(x, y)) == Some(Rgb565::RED) {
+                    assert!((13..18).contains(&x) && (4..4 + HEIGHT as i32).contains(&y));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn redraw_erases_old_trace() {
+        let mut chart = chart();
+        for i in 0..16 {
+            chart.push(if i % 4 == 0 { 1_000 } else { 0 });
+        }
+        let mut display = MockDisplay::new();
+        chart.draw(&mut display).unwrap();
+
+        chart.push(500);
+        chart.draw(&mut display).unwrap();
+
+        // every old pixel is either part of the new trace or background
+        for x in 0..64 {
+            for y in 0..64 {
+                if let Some(color) = display.get_pixel(Point::new(x, y)) {
+                    assert!(color == Rgb565::RED || color == Rgb565::WHITE);
+                }
+            }
+        }
+        assert_eq!(trace_columns(&display), 16);
+
+        // nothing new, nothing drawn
+        let mut untouched = MockDisplay::new();
+        chart.draw(&mut untouched).unwrap();
+        assert!(untouched == MockDisplay::new());
+
+        // cleared chart leaves only background
+        chart.clear();
+        chart.draw(&mut display).unwrap();
+        assert_eq!(trace_columns(&display), 0);
+    }
+
+    fn pulses(chart: &mut StripChart<16>, from: i32, count: i32) {
+        for i in from..from + count {
+            chart.push(if i % 8 == 0 { 1_000 } else { 0 });
+        }
+    }
+
+    #[test]
+    fn columns_replace_old_trace() {
+        let mut chart = chart();
+        let mut scrolled = FrameBuffer::<32, 48>::new();
+        pulses(&mut chart, 0, 16);
+        chart.draw_columns(&mut scrolled).unwrap();
+        pulses(&mut chart, 16, 3);
+        chart.draw_columns(&mut scrolled).unwrap();
+
+        // same as drawing the last state on an empty screen
+        let mut fresh = StripChart::new(Point::new(2, 4), HEIGHT, 1);
+        let mut expected = FrameBuffer::<32, 48>::new();
+        pulses(&mut fresh, 0, 19);
+        fresh.draw_columns(&mut expected).unwrap();
+        for y in 0..48 {
+            let background = |c: &Rgb565| if *c == Rgb565::WHITE { Rgb565::BLACK } else { *c };
+            assert!(
+                scrolled.row(y).iter().map(background).eq(expected.row(y).iter().copied()),
+                "row {}", y
+            );
+        }
+
+        // a column each, unchanged columns are skipped
+        scrolled.reset_counts();
+        chart.draw_columns(&mut scrolled).unwrap();
+        assert_eq!(scrolled.windows(), 0);
+        chart.push(0);
+        chart.draw_columns(&mut scrolled).unwrap();
+        assert!(scrolled.windows() > 0 && scrolled.windows() <= 16);
+    }
+
+    #[test]
+    fn columns_write_less_than_lines() {
+        let mut lines = chart();
+        let mut columns = chart();
+        let mut lines_screen = FrameBuffer::<32, 48>::new();
+        let mut columns_screen = FrameBuffer::<32, 48>::new();
+
+        for step in 0..8 {
+            pulses(&mut lines, step * 3, 3);
+            pulses(&mut columns, step * 3, 3);
+            lines.draw(&mut lines_screen).unwrap();
+            columns.draw_columns(&mut columns_screen).unwrap();
+        }
+
+        assert!(columns_screen.pixels() < lines_screen.pixels(),
+            "{} < {}", columns_screen.pixels(), lines_screen.pixels());
+        assert!(columns_screen.windows() * 4 < lines_screen.windows(),
+            "{} < {}", columns_screen.windows(), lines_screen.windows());
+    }
+
+    #[test]
+    fn invalidated_chart_is_drawn_again() {
+        let mut chart = chart();
+        let mut screen = FrameBuffer::<32, 48>::new();
+        pulses(&mut chart, 0, 16);
+        chart.draw_columns(&mut screen).unwrap();
+        assert!(!chart.is_changed());
+
+        // another screen was shown in between
+        let mut blank = FrameBuffer::<32, 48>::new();
+        chart.invalidate();
+        chart.draw_columns(&mut blank).unwrap();
+        for y in 0..48 {
+            assert!(blank.row(y).iter().eq(screen.row(y).iter()), "row {}", y);
+        }
+    }
+}
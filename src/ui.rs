@@ -0,0 +1,290 @@
+//! Screens of the watch and the navigation between them.
+//!
+//! Each screen draws all of itself when it's entered and only what changed
+//! afterwards. `Ui` keeps a stack of the screens, the top one is shown. The
+//! input events go to the top screen, which answers with a `Transition`.
+//! The status and the PPG samples go to every screen, including the hidden
+//! ones, so a screen has its data ready when it comes back.
+//!
+//! The side button goes round the main screens with a press, a long press
+//! opens the diagnostics on top and any press goes back from there.
+
+use embedded_graphics::{
+    prelude::*,
+    pixelcolor::Rgb565,
+};
+
+use crate::input::InputEvent;
+use crate::render::FrameStats;
+use crate::scroll::VerticalScroll;
+use crate::screens::{
+    ChartRendering,
+    DiagnosticsScreen,
+    HeartRateScreen,
+    StatusScreen,
+    WaveformScreen,
+};
+use crate::wrist::WristState;
+
+/// Screens open on top of each other at most
+pub const STACK_DEPTH: usize = 4;
+
+/// Latest state of the watch, as the screens show it
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Status {
+    pub bpm: u32,
+    pub rmssd_us: u32,
+    pub wrist: Option<WristState>,
+    /// Battery voltage in 0.1 volts
+    pub battery_voltage: u8,
+    pub charging: bool,
+    pub uptime_s: u32,
+    /// Frames drawn since the last reset of the stats
+    pub frames: FrameStats,
+    /// Samples the sampling dropped since the start
+    pub dropped: u32,
+    /// Bus errors in a row of the sensor
+    pub bus_errors: u32,
+}
+
+/// Built-in screens
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScreenId {
+    HeartRate,
+    Waveform,
+    Status,
+    Diagnostics,
+}
+
+impl ScreenId {
+    pub const ALL: [ScreenId; 4] = [ScreenId::HeartRate, ScreenId::Waveform, ScreenId::Status, ScreenId::Diagnostics];
+}
+
+/// Where to go after an input event
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Transition {
+    None,
+    /// Opens a screen on top of this one
+    Push(ScreenId),
+    /// Back to the screen below
+    Pop,
+    /// Swaps this screen for another one
+    Replace(ScreenId),
+}
+
+/// One screen of the watch
+pub trait Screen<D: DrawTarget<Rgb565>> {
+    /// Draws the whole screen, it's shown from now on
+    fn enter(&mut self, display: &mut D) -> Result<(), D::Error>;
+
+    /// Puts back the display settings the next screen doesn't expect
+    fn exit(&mut self, _display: &mut D) -> Result<(), D::Error> {
+        Ok(())
+    }
+
+    /// Takes the latest status, also while hidden,
+    /// `true` if `draw` has something new to show
+    fn update(&mut self, status: &Status) -> bool;
+
+    /// Takes a filtered PPG sample, dropped unless the screen plots them
+    fn push_sample(&mut self, _value: i32) {}
+
+    /// Draws what changed since `enter` or the last `draw`
+    fn draw(&mut self, display: &mut D) -> Result<(), D::Error>;
+
+    /// What an input event does while the screen is shown
+    fn handle(&mut self, event: InputEvent) -> Transition;
+}
+
+/// The built-in screens, apart from the display so both can be borrowed
+struct Screens {
+    heart_rate: HeartRateScreen,
+    waveform: WaveformScreen,
+    status: StatusScreen,
+    diagnostics: DiagnosticsScreen,
+}
+
+impl Screens {
+    fn get<D: VerticalScroll>(&mut self, id: ScreenId) -> &mut dyn Screen<D> {
+        match id {
+            ScreenId::HeartRate => &mut self.heart_rate,
+            ScreenId::Waveform => &mut self.waveform,
+            ScreenId::Status => &mut self.status,
+            ScreenId::Diagnostics => &mut self.diagnostics,
+        }
+    }
+}
+
+/// Screen stack on a display, the heart rate screen at the bottom
+pub struct Ui<D> {
+    pub display: D,
+    screens: Screens,
+    stack: [ScreenId; STACK_DEPTH],
+    depth: usize,
+}
+
+impl<D: VerticalScroll> Ui<D> {
+    /// Nothing is drawn before `start`
+    pub fn new(display: D, rendering: ChartRendering) -> Self {
+        Ui {
+            display,
+            screens: Screens {
+                heart_rate: HeartRateScreen::new(),
+                waveform: WaveformScreen::new(rendering),
+                status: StatusScreen::new(),
+                diagnostics: DiagnosticsScreen::new(),
+            },
+            stack: [ScreenId::HeartRate; STACK_DEPTH],
+            depth: 1,
+        }
+    }
+
+    /// Draws the screen on top of the stack
+    pub fn start(&mut self) -> Result<(), D::Error> {
+        let current = self.current();
+        self.screens.get(current).enter(&mut self.display)
+    }
+
+    /// Screen shown now
+    pub fn current(&self) -> ScreenId {
+        self.stack[self.depth - 1]
+    }
+
+    /// Screens on the stack, the shown one included
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Adds a filtered PPG sample, it shows up with the next `update`
+    pub fn push_sample(&mut self, value: i32) {
+        for id in ScreenId::ALL {
+            Screens::get::<D>(&mut self.screens, id).push_sample(value);
+        }
+    }
+
+    /// Passes the status to the screens and draws the shown one if it
+    /// changed, `true` if it did
+    pub fn update(&mut self, status: &Status) -> Result<bool, D::Error> {
+        let current = self.current();
+        let mut changed = false;
+        for id in ScreenId::ALL {
+            let screen_changed = Screens::get::<D>(&mut self.screens, id).update(status);
+            changed |= id == current && screen_changed;
+        }
+        if changed {
+            self.screens.get(current).draw(&mut self.display)?;
+        }
+        Ok(changed)
+    }
+
+    /// Lets the shown screen handle the event, goes where it says
+    pub fn handle(&mut self, event: InputEvent) -> Result<Transition, D::Error> {
+        let current = self.current();
+        let transition = Screens::get::<D>(&mut self.screens, current).handle(event);
+        self.navigate(transition)?;
+        Ok(transition)
+    }
+
+    /// Exits the shown screen and enters the next one, a push on a full
+    /// stack and a pop of the last screen do nothing
+    pub fn navigate(&mut self, transition: Transition) -> Result<(), D::Error> {
+        let next = match transition {
+            Transition::Push(id) if self.depth < STACK_DEPTH => id,
+            Transition::Pop if self.depth > 1 => self.stack[self.depth - 2],
+            Transition::Replace(id) => id,
+            _ => return Ok(()),
+        };
+
+        let current = self.current();
+        self.screens.get(current).exit(&mut self.display)?;
+        match transition {
+            Transition::Push(_) => self.depth += 1,
+            Transition::Pop => self.depth -= 1,
+            _ => {}
+        }
+        self.stack[self.depth - 1] = next;
+        self.screens.get(next).enter(&mut self.display)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framebuffer::LcdFrameBuffer;
+
+    fn ui(rendering: ChartRendering) -> Ui<LcdFrameBuffer> {
+        let mut ui = Ui::new(LcdFrameBuffer::new(), rendering);
+        ui.start().unwrap();
+        ui
+    }
+
+    #[test]
+    fn press_goes_round_long_press_opens_diagnostics() {
+        let mut ui = ui(ChartRendering::Columns);
+        assert_eq!(ui.current(), ScreenId::HeartRate);
+
+        assert_eq!(ui.handle(InputEvent::Press).unwrap(), Transition::Replace(ScreenId::Waveform));
+        assert_eq!(ui.handle(InputEvent::Press).unwrap(), Transition::Replace(ScreenId::Status));
+        assert_eq!((ui.current(), ui.depth()), (ScreenId::Status, 1));
+
+        ui.handle(InputEvent::LongPress).unwrap();
+        assert_eq!((ui.current(), ui.depth()), (ScreenId::Diagnostics, 2));
+        ui.handle(InputEvent::Press).unwrap();
+        assert_eq!((ui.current(), ui.depth()), (ScreenId::Status, 1));
+
+        ui.handle(InputEvent::Press).unwrap();
+        assert_eq!(ui.current(), ScreenId::HeartRate);
+    }
+
+    #[test]
+    fn stack_has_limits() {
+        let mut ui = ui(ChartRendering::Columns);
+        ui.navigate(Transition::Pop).unwrap();
+        assert_eq!((ui.current(), ui.depth()), (ScreenId::HeartRate, 1));
+
+        for _ in 0..STACK_DEPTH + 2 {
+            ui.navigate(Transition::Push(ScreenId::Status)).unwrap();
+        }
+        assert_eq!(ui.depth(), STACK_DEPTH);
+        for _ in 0..STACK_DEPTH {
+            ui.navigate(Transition::Pop).unwrap();
+        }
+        assert_eq!((ui.current(), ui.depth()), (ScreenId::HeartRate, 1));
+    }
+
+    #[test]
+    fn only_changes_are_drawn() {
+        let mut ui = ui(ChartRendering::Columns);
+        let status = Status { bpm: 72, ..Status::default() };
+        assert!(ui.update(&status).unwrap());
+
+        ui.display.reset_counts();
+        assert!(!ui.update(&status).unwrap());
+        assert_eq!(ui.display.windows(), 0);
+
+        // samples only change the waveform, hidden or not
+        ui.push_sample(10);
+        assert!(!ui.update(&status).unwrap());
+        ui.navigate(Transition::Replace(ScreenId::Waveform)).unwrap();
+        ui.push_sample(20);
+        assert!(ui.update(&status).unwrap());
+    }
+
+    #[test]
+    fn scrolling_stops_on_exit() {
+        let mut ui = ui(ChartRendering::Scrolling);
+        ui.navigate(Transition::Replace(ScreenId::Waveform)).unwrap();
+        for i in 0..50 {
+            ui.push_sample(if i % 10 < 5 { 0 } else { 1_000 });
+        }
+        ui.update(&Status::default()).unwrap();
+
+        // the next screen is drawn where it's shown
+        ui.navigate(Transition::Replace(ScreenId::Status)).unwrap();
+        let mut fresh = Ui::new(LcdFrameBuffer::new(), ChartRendering::Scrolling);
+        fresh.navigate(Transition::Replace(ScreenId::Status)).unwrap();
+        for y in 0..240 {
+            assert!(ui.display.row(y).iter().eq(fresh.display.row(y).iter()), "row {}", y);
+        }
+    }
+}
@@ -0,0 +1,261 @@
+//! On-wrist detection from the HRS3300 samples.
+//!
+//! Skin close to the sensor reflects a good part of the LED light (DC level),
+//! blocks the ambient light (ALS leakage) and shows a pulse (pulsatile part
+//! relative to the DC level, the perfusion index). All three are checked over
+//! windows of a couple of seconds, the state changes after two windows agree.
+
+use crate::hrs3300::{
+    RawSample,
+    normalise,
+};
+use crate::ppg_processor::PpgFilter;
+
+/// Length of a decision window
+const WINDOW_US: u32 = 2_000_000;
+/// Windows in a row needed to change the state
+const CONFIRM_WINDOWS: u8 = 2;
+/// Lowest reflected light on the wrist, permille of full scale
+const MIN_DC_PERMILLE: u32 = 20;
+/// Highest ambient light on the wrist, percent of the HRS channel
+const MAX_AMBIENT_PERCENT: u32 = 50;
+/// Perfusion index range on the wrist, basis points (1/100 %):
+/// below is a still object, above is motion or light flicker
+const MIN_PERFUSION_BP: u32 = 5;
+const MAX_PERFUSION_BP: u32 = 2_000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WristState {
+    OnWrist,
+    OffWrist,
+}
+
+/// Values of the last finished window, for logging and tuning
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct WristFeatures {
+    /// Mean HRS minus ALS, permille of full scale
+    pub dc_permille: u32,
+    /// Mean ALS, percent of the mean HRS
+    pub ambient_percent: u32,
+    /// Peak to peak of the detrended signal, basis points of the DC level
+    pub perfusion_bp: u32,
+}
+impl WristFeatures {
+    pub fn looks_on_wrist(&self) -> bool {
+        self.dc_permille >= MIN_DC_PERMILLE
+            && self.ambient_percent <= MAX_AMBIENT_PERCENT
+            && (MIN_PERFUSION_BP..=MAX_PERFUSION_BP).contains(&self.perfusion_bp)
+    }
+}
+
+/// Wear state from `RawSample`s taken every `sample_period_us`
+pub struct WristDetector {
+    filter: PpgFilter,
+    window_len: u32,
+
+    count: u32,
+    hrs_sum: u64,
+    als_sum: u64,
+    dc_sum: u64,
+    full_scale: u32,
+    ac_min: i64,
+    ac_max: i64,
+    ac_count: u32,
+
+    candidate: Option<WristState>,
+    votes: u8,
+    state: Option<WristState>,
+    features: Option<WristFeatures>,
+}
+
+impl WristDetector {
+    pub fn new(sample_period_us: u32) -> Self {
+        WristDetector {
+            filter: PpgFilter::new(),
+            window_len: (WINDOW_US / sample_period_us.max(1)).max(1),
+            count: 0,
+            hrs_sum: 0,
+            als_sum: 0,
+            dc_sum: 0,
+            full_scale: 0,
+            ac_min: i64::MAX,
+            ac_max: i64::MIN,
+            ac_count: 0,
+            candidate: None,
+            votes: 0,
+            state: None,
+            features: None,
+        }
+    }
+
+    /// Takes the next sample, returns the new state when it changes
+    pub fn consume_sample(&mut self, sample: RawSample) -> Option<WristState> {
+        // levels are normalised, full scale depends on the gain and resolution
+        let full_scale = normalise(sample.resolution.get_mask(), sample.gain, sample.resolution);
+        if self.count > 0 && full_scale != self.full_scale {
+            // levels of the window don't compare anymore
+            self.clear_window();
+        }
+        self.full_scale = full_scale;
+
+        self.count += 1;
+        self.hrs_sum += sample.get_normalised_hrs() as u64;
+        self.als_sum += sample.get_normalised_als() as u64;
+        self.dc_sum += sample.get_normalised_sum() as u64;
+        if let Some(ac) = self.filter.consume_value(sample) {
+            self.ac_min = self.ac_min.min(ac);
+            self.ac_max = self.ac_max.max(ac);
+            self.ac_count += 1;
+        }
+
+        if self.count < self.window_len {
+            return None;
+        }
+
+        let features = self.window_features();
+        self.clear_window();
+        // no pulse part yet, the detrend is warming up
+        let features = features?;
+        self.features = Some(features);
+        self.vote(if features.looks_on_wrist() { WristState::OnWrist } else { WristState::OffWrist })
+    }
+
+    /// Accounts for a sample which can't be used, like one around a sensor
+    /// setting change, the current window and the detrend start over
+    pub fn skip_sample(&mut self) {
+        self.filter.reset();
+        self.clear_window();
+    }
+
+    /// Current state, `None` until the first decision
+    pub fn get(&self) -> Option<WristState> {
+        self.state
+    }
+
+    pub fn is_on_wrist(&self) -> bool {
+        self.state == Some(WristState::OnWrist)
+    }
+
+    /// Features of the last finished window
+    pub fn features(&self) -> Option<WristFeatures> {
+        self.features
+    }
+
+    pub fn reset(&mut self) {
+        self.skip_sample();
+        self.candidate = None;
+        self.votes = 0;
+        self.state = None;
+        self.features = None;
+    }
+
+    fn window_features(&self) -> Option<WristFeatures> {
+        if self.ac_count < self.count / 2 {
+            return None;
+        }
+
+        let count = self.count as u64;
+        let hrs = self.hrs_sum / count;
+        let als = self.als_sum / count;
+        let dc = self.dc_sum / count;
+        let ac = (self.ac_max - self.ac_min).max(0) as u64;
+
+        Some(WristFeatures {
+            dc_permille: (dc * 1_000 / self.full_scale.max(1) as u64) as u32,
+            ambient_percent: (als * 100).checked_div(hrs).unwrap_or(100) as u32,
+            perfusion_bp: (ac * 10_000).checked_div(dc).unwrap_or(0).min(u32::MAX as u64) as u32,
+        })
+    }
+
+    fn vote(&mut self, seen: WristState) -> Option<WristState> {
+        if self.candidate == Some(seen) {
+            self.votes = self.votes.saturating_add(1);
+        } else {
+            self.candidate = Some(seen);
+            self.votes = 1;
+        }
+
+        if self.votes >= CONFIRM_WINDOWS && self.state != Some(seen) {
+            self.state = Some(seen);
+            return self.state;
+        }
+        None
+    }
+
+    fn clear_window(&mut self) {
+        self.count = 0;
+        self.hrs_sum = 0;
+        self.als_sum = 0;
+        self.dc_sum = 0;
+        self.ac_min = i64::MAX;
+        self.ac_max = i64::MIN;
+        self.ac_count = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::vec::Vec;
+
+    const PERIOD_US: u32 = 12_500;
+
+    /// Feeds `seconds` of samples from `signal(time in s)`, returns the events
+    fn run(detector: &mut WristDetector, seconds: u32, signal: impl Fn(f32) -> RawSample) -> Vec<WristState> {
+        let mut events = Vec::new();
+        for i in 0..(seconds * 1_000_000 / PERIOD_US) {
+            let t = (i * PERIOD_US) as f32 / 1_000_000_f32;
+            if let Some(state) = detector.consume_sample(signal(t)) {
+                events.push(state);
+            }
+        }
+        events
+    }
+
+    /// Wrist at 72 BPM, 1% perfusion, little ambient light
+    fn wrist(t: f32) -> RawSample {
+        let pulse = 40_f32 * (2_f32 * core::f32::consts::PI * 1.2 * t).sin();
+        RawSample::new((8_000_f32 + pulse) as u32, 3)
+    }
+
+    #[test]
+    fn detects_wrist() {
+        let mut detector = WristDetector::new(PERIOD_US);
+        assert_eq!(run(&mut detector, 10, wrist), [WristState::OnWrist]);
+        assert!(detector.is_on_wrist());
+
+        let features = detector.features().unwrap();
+        assert!((90..=110).contains(&features.perfusion_bp), "{:?}", features);
+    }
+
+    #[test]
+    fn table_facing_up() {
+        // LED shines into the room, ambient light reaches both channels
+        let mut detector = WristDetector::new(PERIOD_US);
+        assert_eq!(run(&mut detector, 10, |_| RawSample::new(2_300, 31)), [WristState::OffWrist]);
+        assert_eq!(detector.features().unwrap().ambient_percent, 86);
+    }
+
+    #[test]
+    fn table_facing_down() {
+        // strong reflection from the table, but nothing pulses
+        let mut detector = WristDetector::new(PERIOD_US);
+        assert_eq!(run(&mut detector, 10, |t| RawSample::new(9_000 + (t * 80_f32) as u32 % 2, 0)), [WristState::OffWrist]);
+    }
+
+    #[test]
+    fn taken_off_and_put_back() {
+        let mut detector = WristDetector::new(PERIOD_US);
+        run(&mut detector, 10, wrist);
+
+        // single window without a pulse doesn't change the state
+        let mut events = run(&mut detector, 2, |_| RawSample::new(8_000, 3));
+        events.extend(run(&mut detector, 2, wrist));
+        assert!(events.is_empty(), "{:?}", events);
+
+        assert_eq!(run(&mut detector, 10, |_| RawSample::new(300, 4)), [WristState::OffWrist]);
+        assert_eq!(run(&mut detector, 10, wrist), [WristState::OnWrist]);
+    }
+}